@@ -527,6 +527,7 @@ impl Shuttle {
                     }
                     shuttle_common::deployment::State::Running
                     | shuttle_common::deployment::State::Completed
+                    | shuttle_common::deployment::State::Stopping
                     | shuttle_common::deployment::State::Stopped
                     | shuttle_common::deployment::State::Unknown => break,
                 }
@@ -26,15 +26,25 @@ pub const EXP_MINUTES: i64 = 5;
 const ISS: &str = "shuttle";
 const PUBLIC_KEY_CACHE_KEY: &str = "shuttle.public-key";
 
+/// Checks whether a secret given on a request is the admin secret
+type AdminSecretVerifier = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
 /// Layer to check the admin secret set by deployer is correct
 #[derive(Clone)]
 pub struct AdminSecretLayer {
-    secret: String,
+    verifier: AdminSecretVerifier,
 }
 
 impl AdminSecretLayer {
     pub fn new(secret: String) -> Self {
-        Self { secret }
+        Self::with_verifier(move |candidate| candidate == secret)
+    }
+
+    /// Use a custom check of the admin secret, for example to keep only a hash of the secret in memory
+    pub fn with_verifier(verifier: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        Self {
+            verifier: Arc::new(verifier),
+        }
     }
 }
 
@@ -44,7 +54,7 @@ impl<S> Layer<S> for AdminSecretLayer {
     fn layer(&self, inner: S) -> Self::Service {
         AdminSecret {
             inner,
-            secret: self.secret.clone(),
+            verifier: self.verifier.clone(),
         }
     }
 }
@@ -52,7 +62,7 @@ impl<S> Layer<S> for AdminSecretLayer {
 #[derive(Clone)]
 pub struct AdminSecret<S> {
     inner: S,
-    secret: String,
+    verifier: AdminSecretVerifier,
 }
 
 impl<S> Service<Request<Body>> for AdminSecret<S>
@@ -75,7 +85,7 @@ where
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         match req.headers().typed_try_get::<XShuttleAdminSecret>() {
-            Ok(Some(secret)) if secret.0 == self.secret => {
+            Ok(Some(secret)) if (self.verifier)(&secret.0) => {
                 let future = self.inner.call(req);
 
                 StatusCodeFuture::Poll(future)
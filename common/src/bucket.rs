@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+use strum::Display;
+
+#[derive(Clone, Debug, Deserialize, Display, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Type {
+    Shared,
+    AwsS3,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BucketReadyInfo {
+    bucket_name: String,
+    endpoint: String,
+}
+
+impl BucketReadyInfo {
+    pub fn new(bucket_name: String, endpoint: String) -> Self {
+        Self {
+            bucket_name,
+            endpoint,
+        }
+    }
+
+    pub fn connection_string_public(&self) -> String {
+        format!("{}/{}", self.endpoint, self.bucket_name)
+    }
+}
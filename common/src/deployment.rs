@@ -11,6 +11,7 @@ pub enum State {
     Loading,
     Running,
     Completed,
+    Stopping,
     Stopped,
     Crashed,
     Unknown,
@@ -1,5 +1,6 @@
 #[cfg(feature = "backend")]
 pub mod backends;
+pub mod bucket;
 pub mod database;
 pub mod deployment;
 pub mod log;
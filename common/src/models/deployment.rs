@@ -36,7 +36,7 @@ impl State {
         match self {
             State::Queued | State::Building | State::Built | State::Loading => Color::Cyan,
             State::Running => Color::Green,
-            State::Completed | State::Stopped => Color::Blue,
+            State::Completed | State::Stopping | State::Stopped => Color::Blue,
             State::Crashed => Color::Red,
             State::Unknown => Color::Yellow,
         }
@@ -4,7 +4,10 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use uuid::Uuid;
 
-use crate::{database, DatabaseReadyInfo};
+use crate::{
+    bucket::{self, BucketReadyInfo},
+    database, DatabaseReadyInfo,
+};
 
 #[derive(Deserialize, Serialize)]
 pub struct Response {
@@ -19,17 +22,28 @@ pub trait ResourceInfo {
     fn connection_string_public(&self) -> String;
 }
 
+impl<T: ResourceInfo + ?Sized> ResourceInfo for Box<T> {
+    fn connection_string_public(&self) -> String {
+        (**self).connection_string_public()
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Type {
     Database(database::Type),
+    Bucket(bucket::Type),
 }
 
 impl Response {
     pub fn get_resource_info(&self) -> impl ResourceInfo {
         match self.r#type {
             Type::Database(_) => {
-                serde_json::from_value::<DatabaseReadyInfo>(self.data.clone()).unwrap()
+                Box::new(serde_json::from_value::<DatabaseReadyInfo>(self.data.clone()).unwrap())
+                    as Box<dyn ResourceInfo + Send + Sync>
+            }
+            Type::Bucket(_) => {
+                Box::new(serde_json::from_value::<BucketReadyInfo>(self.data.clone()).unwrap())
             }
         }
     }
@@ -39,6 +53,7 @@ impl Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Type::Database(db_type) => write!(f, "database::{db_type}"),
+            Type::Bucket(bucket_type) => write!(f, "bucket::{bucket_type}"),
         }
     }
 }
@@ -1,4 +1,5 @@
 use crate::{
+    bucket::BucketReadyInfo,
     models::{deployment, resource, resource::ResourceInfo, secret},
     DatabaseReadyInfo,
 };
@@ -40,6 +41,12 @@ impl ResourceInfo for DatabaseReadyInfo {
     }
 }
 
+impl ResourceInfo for BucketReadyInfo {
+    fn connection_string_public(&self) -> String {
+        self.connection_string_public()
+    }
+}
+
 impl Display for Detailed {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let deploys = get_deployments_table(&self.deployments, &self.name);
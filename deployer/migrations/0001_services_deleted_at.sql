@@ -0,0 +1 @@
+ALTER TABLE services ADD COLUMN deleted_at INTEGER; -- Unix epoch of when the service was soft-deleted, if it was.
@@ -0,0 +1,2 @@
+ALTER TABLE logs ADD COLUMN trace_id TEXT; -- OpenTelemetry trace the log was emitted in, if any.
+ALTER TABLE logs ADD COLUMN span_id TEXT; -- OpenTelemetry span the log was emitted in, if any.
@@ -0,0 +1 @@
+ALTER TABLE services ADD COLUMN user_id TEXT; -- Identifier of the user owning the service, if it has an owner.
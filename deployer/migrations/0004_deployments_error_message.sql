@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN error_message TEXT; -- Reason the deployment crashed, if it did.
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS deployments_state_service_id ON deployments (state, service_id); -- Finding the runnable deployments of each service.
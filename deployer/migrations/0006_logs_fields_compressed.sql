@@ -0,0 +1 @@
+ALTER TABLE logs ADD COLUMN fields_compressed BOOLEAN NOT NULL DEFAULT 0; -- Whether fields is gzipped JSON rather than plain JSON.
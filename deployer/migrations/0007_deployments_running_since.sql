@@ -0,0 +1 @@
+ALTER TABLE deployments ADD COLUMN running_since INTEGER; -- When the deployment last entered the running state, if it is running.
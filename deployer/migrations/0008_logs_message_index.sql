@@ -0,0 +1,3 @@
+ALTER TABLE logs ADD COLUMN message TEXT COLLATE NOCASE; -- The message of the fields, stored at insert so that it can be searched even when the fields are compressed.
+UPDATE logs SET message = json_extract(fields, '$.message') WHERE NOT fields_compressed; -- Logs which were compressed already stay unsearchable.
+CREATE INDEX IF NOT EXISTS logs_id_message ON logs (id, message); -- Searching the logs of a deployment by message.
@@ -0,0 +1,2 @@
+ALTER TABLE logs ADD COLUMN level_severity INTEGER GENERATED ALWAYS AS (CASE level WHEN 'Trace' THEN 0 WHEN 'Debug' THEN 1 WHEN 'Info' THEN 2 WHEN 'Warn' THEN 3 WHEN 'Error' THEN 4 END) VIRTUAL; -- Ordinal of level matching Level::severity, for range filtering.
+CREATE INDEX IF NOT EXISTS logs_id_level_severity ON logs (id, level_severity); -- Filtering the logs of a deployment by minimum level.
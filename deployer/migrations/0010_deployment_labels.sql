@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS deployment_labels (
+    deployment_id TEXT, -- Identifier of the deployment this label is on.
+    key TEXT,           -- Key of this label, like the git branch.
+    value TEXT,         -- Value of this label.
+    PRIMARY KEY (deployment_id, key),
+    FOREIGN KEY(deployment_id) REFERENCES deployments(id)
+);
+
+CREATE INDEX IF NOT EXISTS deployment_labels_key_value ON deployment_labels (key, value); -- Finding deployments by label.
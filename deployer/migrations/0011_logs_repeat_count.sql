@@ -0,0 +1 @@
+ALTER TABLE logs ADD COLUMN repeat_count INTEGER NOT NULL DEFAULT 1; -- How many identical consecutive event logs this row stands for after compaction.
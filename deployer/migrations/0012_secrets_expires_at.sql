@@ -0,0 +1,2 @@
+ALTER TABLE secrets ADD COLUMN expires_at INTEGER; -- When the secret stops being returned, or NULL when it never expires.
+CREATE INDEX IF NOT EXISTS secrets_expires_at ON secrets (expires_at); -- Sweeping the expired secrets.
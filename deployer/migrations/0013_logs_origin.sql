@@ -0,0 +1,2 @@
+ALTER TABLE logs ADD COLUMN origin TEXT NOT NULL DEFAULT 'Deployer'; -- Whether the log is from the deployer itself or the output of the service it runs.
+CREATE INDEX IF NOT EXISTS logs_id_origin ON logs (id, origin); -- Filtering the logs of a deployment by where they came from.
@@ -0,0 +1,2 @@
+ALTER TABLE deployments ADD COLUMN is_promoted BOOLEAN NOT NULL DEFAULT FALSE; -- Whether this is the deployment marked as production for its service.
+CREATE UNIQUE INDEX IF NOT EXISTS deployments_promoted_service_id ON deployments (service_id) WHERE is_promoted; -- Only one deployment of a service can be promoted.
@@ -0,0 +1 @@
+ALTER TABLE logs ADD COLUMN span_fields JSON; -- Fields of the scope which entered the state of a state log, NULL for event logs.
@@ -0,0 +1 @@
+ALTER TABLE logs ADD COLUMN duration_ms INTEGER; -- How long the state of a state log lasted, set once the scope of the state closed.
@@ -1,19 +1,76 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    convert::Infallible,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use clap::Parser;
 use fqdn::FQDN;
 use hyper::Uri;
+use ring::{
+    constant_time,
+    digest::{self, Digest},
+};
 use shuttle_common::{project::ProjectName, Port};
+use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+use uuid::Uuid;
+
+use crate::persistence::PersistenceConfig;
+
+/// Environment variable the admin secret is read from when no secret file is given
+const ADMIN_SECRET_ENV: &str = "SHUTTLE_ADMIN_SECRET";
+
+/// Special state path which keeps the state database in memory only
+const IN_MEMORY_STATE: &str = ":memory:";
 
 /// Program to handle the deploys for a single project
 /// Handling includes, building, testing, and running each service
 #[derive(Debug, Parser)]
 #[clap(author, version, about)]
 pub struct Args {
-    /// Uri to the `.sqlite` file used to store state
-    #[clap(long, default_value = "./deployer.sqlite")]
+    /// Uri to the `.sqlite` file used to store state, or `:memory:` to not persist it
+    #[clap(long, alias = "database-path", default_value = "./deployer.sqlite")]
     pub state: String,
 
+    /// How many services to keep in memory for looking them up by name. Services are not cached when this is not set
+    #[clap(long)]
+    pub service_cache_capacity: Option<usize>,
+
+    /// Maximum number of connections to the state db
+    #[clap(long, default_value = "10")]
+    pub state_max_connections: u32,
+
+    /// How long to wait on a locked state db before failing
+    #[clap(long, default_value = "5000")]
+    pub state_busy_timeout_ms: u64,
+
+    /// How long to wait for a free connection to the state db before failing
+    #[clap(long, default_value = "5000")]
+    pub state_acquire_timeout_ms: u64,
+
+    /// Journal mode of the state db. Anything but `wal` needs --state-i-understand-the-risk
+    #[clap(long, default_value = "wal")]
+    pub state_journal_mode: SqliteJournalMode,
+
+    /// How hard the state db syncs writes to disk. Anything less than `full` needs --state-i-understand-the-risk
+    #[clap(long, default_value = "full")]
+    pub state_synchronous: SqliteSynchronous,
+
+    /// Allow a journal mode or synchronous mode for the state db which is known to break the deployer
+    #[clap(long)]
+    pub state_i_understand_the_risk: bool,
+
+    /// Size in bytes of the pages of the state db. This only applies when the db is created
+    #[clap(long, default_value = "4096")]
+    pub state_page_size: u32,
+
+    /// Most bytes of the state db each connection memory maps. Zero turns memory mapping off
+    #[clap(long, default_value = "0")]
+    pub state_mmap_size: u64,
+
     /// Address to connect to the provisioning service
     #[clap(long)]
     pub provisioner_address: String,
@@ -22,6 +79,14 @@ pub struct Args {
     #[clap(long, default_value = "5000")]
     pub provisioner_port: Port,
 
+    /// How many times to retry connecting to the provisioner before giving up
+    #[clap(long, default_value = "5")]
+    pub provisioner_connect_retries: u32,
+
+    /// How long to wait before the first retry to connect to the provisioner. This doubles with each retry
+    #[clap(long, default_value = "500")]
+    pub provisioner_connect_backoff_ms: u64,
+
     /// FQDN where the proxy can be reached at
     #[clap(long)]
     pub proxy_fqdn: FQDN,
@@ -42,15 +107,371 @@ pub struct Args {
     #[clap(long)]
     pub project: ProjectName,
 
-    /// Secret that will be used to perform admin tasks on this deployer
+    /// Secret that will be used to perform admin tasks on this deployer. This leaks into process listings, so it is
+    /// only meant for development
+    #[clap(long)]
+    pub admin_secret: Option<AdminSecret>,
+
+    /// File containing the secret that will be used to perform admin tasks on this deployer
     #[clap(long)]
-    pub admin_secret: String,
+    pub admin_secret_file: Option<PathBuf>,
 
     /// Address to reach the authentication service at
     #[clap(long, default_value = "http://127.0.0.1:8008")]
     pub auth_uri: Uri,
 
-    /// Uri to folder to store all artifacts
+    /// Uri to folder to store all artifacts, like the builds and libraries of deployments. It has to exist and be
+    /// writable
     #[clap(long, default_value = "/tmp")]
     pub artifacts_path: PathBuf,
 }
+
+impl Args {
+    /// Check the args which clap cannot check by itself are sensible
+    pub fn validate(&self) -> Result<(), String> {
+        let labels = self
+            .proxy_fqdn
+            .to_string()
+            .split('.')
+            .filter(|label| !label.is_empty())
+            .count();
+        if labels < 2 {
+            return Err(format!(
+                "proxy fqdn '{}' needs at least two labels, like 'shuttleapp.rs'",
+                self.proxy_fqdn
+            ));
+        }
+
+        let provisioner_host = format!("http://{}", self.provisioner_address)
+            .parse::<Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(str::to_string));
+        if provisioner_host.as_deref() != Some(self.provisioner_address.as_str()) {
+            return Err(format!(
+                "provisioner address '{}' is not a valid host",
+                self.provisioner_address
+            ));
+        }
+
+        if self.provisioner_port == 0 {
+            return Err("provisioner port cannot be 0".to_string());
+        }
+
+        check_writable_dir(&self.artifacts_path)
+            .map_err(|error| format!("artifacts path {error}"))?;
+
+        Ok(())
+    }
+
+    /// Whether the state should only be kept in memory
+    pub fn is_in_memory_state(&self) -> bool {
+        self.state == IN_MEMORY_STATE
+    }
+
+    /// Get the tuning of the state db from the `--state-*` args
+    pub fn persistence_config(&self) -> PersistenceConfig {
+        PersistenceConfig {
+            max_connections: self.state_max_connections,
+            busy_timeout: Duration::from_millis(self.state_busy_timeout_ms),
+            acquire_timeout: Duration::from_millis(self.state_acquire_timeout_ms),
+            journal_mode: self.state_journal_mode,
+            synchronous: self.state_synchronous,
+            i_understand_the_risk: self.state_i_understand_the_risk,
+            page_size: self.state_page_size,
+            mmap_size: self.state_mmap_size,
+        }
+    }
+
+    /// Get a hash of the admin secret from `--admin-secret-file`, `--admin-secret` or `SHUTTLE_ADMIN_SECRET`, in
+    /// that order
+    pub fn admin_secret_hash(&self) -> Result<AdminSecretHash, String> {
+        resolve_admin_secret(
+            self.admin_secret_file.as_deref(),
+            self.admin_secret.as_ref().map(|secret| secret.0.as_str()),
+            std::env::var(ADMIN_SECRET_ENV).ok(),
+        )
+        .map(|secret| AdminSecretHash::new(&secret))
+    }
+}
+
+/// Check `path` is a directory files can be created in, by creating and removing a file in it
+fn check_writable_dir(path: &Path) -> Result<(), String> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => return Err(format!("'{}' is not a directory", path.display())),
+        Err(error) => return Err(format!("'{}' cannot be read: {error}", path.display())),
+    }
+
+    let probe = path.join(format!(".write-check-{}", Uuid::new_v4()));
+    std::fs::write(&probe, b"")
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|error| format!("'{}' is not writable: {error}", path.display()))
+}
+
+fn resolve_admin_secret(
+    file: Option<&Path>,
+    flag: Option<&str>,
+    env: Option<String>,
+) -> Result<String, String> {
+    let secret = if let Some(file) = file {
+        std::fs::read_to_string(file)
+            .map_err(|error| {
+                format!(
+                    "failed to read admin secret from '{}': {error}",
+                    file.display()
+                )
+            })?
+            .trim_end()
+            .to_string()
+    } else if let Some(secret) = flag.map(str::to_string).or(env) {
+        secret
+    } else {
+        return Err(format!(
+            "an admin secret is needed from --admin-secret-file, --admin-secret or {ADMIN_SECRET_ENV}"
+        ));
+    };
+
+    if secret.is_empty() {
+        return Err("the admin secret cannot be empty".to_string());
+    }
+
+    Ok(secret)
+}
+
+/// Admin secret given on the command line, which is never printed so that it stays out of the logged args
+#[derive(Clone)]
+pub struct AdminSecret(String);
+
+impl FromStr for AdminSecret {
+    type Err = Infallible;
+
+    fn from_str(secret: &str) -> Result<Self, Self::Err> {
+        Ok(Self(secret.to_string()))
+    }
+}
+
+impl fmt::Debug for AdminSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AdminSecret(<redacted>)")
+    }
+}
+
+/// SHA-256 of the admin secret so that the secret itself is not kept in memory
+#[derive(Clone)]
+pub struct AdminSecretHash(Digest);
+
+impl AdminSecretHash {
+    pub fn new(secret: &str) -> Self {
+        Self(digest::digest(&digest::SHA256, secret.as_bytes()))
+    }
+
+    /// Check in constant time whether `secret` is the admin secret
+    pub fn matches(&self, secret: &str) -> bool {
+        let candidate = digest::digest(&digest::SHA256, secret.as_bytes());
+
+        constant_time::verify_slices_are_equal(self.0.as_ref(), candidate.as_ref()).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, time::Duration};
+
+    use clap::Parser;
+    use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+
+    use super::{resolve_admin_secret, AdminSecretHash, Args};
+    use crate::persistence::PersistenceConfig;
+
+    /// Parse the minimal args, with some of them overridden
+    fn args(overrides: &[(&str, &str)]) -> Args {
+        let mut args = vec![
+            ("--provisioner-address", "provisioner"),
+            ("--proxy-fqdn", "shuttleapp.rs"),
+            ("--project", "my-project"),
+        ];
+        for &(flag, value) in overrides {
+            args.retain(|(existing, _)| *existing != flag);
+            args.push((flag, value));
+        }
+
+        Args::try_parse_from(
+            std::iter::once("deployer")
+                .chain(args.into_iter().flat_map(|(flag, value)| [flag, value])),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn valid_args() {
+        assert_eq!(args(&[]).validate(), Ok(()));
+        assert_eq!(
+            args(&[("--provisioner-address", "10.0.0.5")]).validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn single_label_fqdn() {
+        assert!(args(&[("--proxy-fqdn", "localhost")]).validate().is_err());
+    }
+
+    #[test]
+    fn invalid_provisioner_address() {
+        for address in ["", "http://provisioner", "provisioner:5000", "provi sioner"] {
+            assert!(
+                args(&[("--provisioner-address", address)])
+                    .validate()
+                    .is_err(),
+                "'{address}' should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn zero_provisioner_port() {
+        assert!(args(&[("--provisioner-port", "0")]).validate().is_err());
+    }
+
+    #[test]
+    fn artifacts_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = args(&[("--artifacts-path", dir.path().to_str().unwrap())]);
+
+        assert_eq!(args.artifacts_path, dir.path());
+        assert_eq!(args.validate(), Ok(()));
+    }
+
+    #[test]
+    fn unusable_artifacts_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let file = tempfile::NamedTempFile::new().unwrap();
+
+        for path in [missing.as_path(), file.path()] {
+            assert!(
+                args(&[("--artifacts-path", path.to_str().unwrap())])
+                    .validate()
+                    .is_err(),
+                "'{}' should be rejected",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn database_path() {
+        let args = args(&[("--database-path", "/var/lib/deployer.sqlite")]);
+
+        assert_eq!(args.state, "/var/lib/deployer.sqlite");
+        assert!(!args.is_in_memory_state());
+    }
+
+    #[test]
+    fn in_memory_state() {
+        assert!(args(&[("--state", ":memory:")]).is_in_memory_state());
+        assert!(args(&[("--database-path", ":memory:")]).is_in_memory_state());
+        assert!(!args(&[]).is_in_memory_state());
+    }
+
+    #[test]
+    fn persistence_config() {
+        let config = args(&[]).persistence_config();
+        let default = PersistenceConfig::default();
+        assert_eq!(config.max_connections, default.max_connections);
+        assert_eq!(config.busy_timeout, default.busy_timeout);
+        assert_eq!(config.acquire_timeout, default.acquire_timeout);
+        assert!(matches!(config.journal_mode, SqliteJournalMode::Wal));
+        assert!(matches!(config.synchronous, SqliteSynchronous::Full));
+        assert!(!config.i_understand_the_risk);
+        assert_eq!(config.page_size, default.page_size);
+        assert_eq!(config.mmap_size, default.mmap_size);
+
+        let config = args(&[
+            ("--state-max-connections", "4"),
+            ("--state-busy-timeout-ms", "250"),
+            ("--state-journal-mode", "delete"),
+            ("--state-page-size", "8192"),
+            ("--state-mmap-size", "1048576"),
+        ])
+        .persistence_config();
+        assert_eq!(config.max_connections, 4);
+        assert_eq!(config.busy_timeout, Duration::from_millis(250));
+        assert!(matches!(config.journal_mode, SqliteJournalMode::Delete));
+        assert_eq!(config.page_size, 8192);
+        assert_eq!(config.mmap_size, 1024 * 1024);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn provisioner_connect_retries() {
+        let args = args(&[
+            ("--provisioner-connect-retries", "3"),
+            ("--provisioner-connect-backoff-ms", "250"),
+        ]);
+
+        assert_eq!(args.provisioner_connect_retries, 3);
+        assert_eq!(args.provisioner_connect_backoff_ms, 250);
+    }
+
+    #[test]
+    fn admin_secret_from_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "from-file").unwrap();
+
+        assert_eq!(
+            resolve_admin_secret(
+                Some(file.path()),
+                Some("from-flag"),
+                Some("from-env".to_string())
+            ),
+            Ok("from-file".to_string())
+        );
+    }
+
+    #[test]
+    fn admin_secret_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(resolve_admin_secret(
+            Some(&dir.path().join("missing")),
+            None,
+            Some("from-env".to_string())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn admin_secret_from_env() {
+        assert_eq!(
+            resolve_admin_secret(None, None, Some("from-env".to_string())),
+            Ok("from-env".to_string())
+        );
+    }
+
+    #[test]
+    fn admin_secret_from_flag() {
+        assert_eq!(
+            resolve_admin_secret(None, Some("from-flag"), Some("from-env".to_string())),
+            Ok("from-flag".to_string())
+        );
+        assert!(resolve_admin_secret(None, None, None).is_err());
+        assert!(resolve_admin_secret(None, Some(""), None).is_err());
+    }
+
+    #[test]
+    fn admin_secret_redacted() {
+        let args = args(&[("--admin-secret", "hunter2")]);
+
+        assert!(!format!("{args:?}").contains("hunter2"));
+    }
+
+    #[test]
+    fn admin_secret_hash() {
+        let hash = AdminSecretHash::new("secret");
+
+        assert!(hash.matches("secret"));
+        assert!(!hash.matches("secreT"));
+        assert!(!hash.matches(""));
+    }
+}
@@ -20,20 +20,162 @@
 //! **Warning** Don't log out sensitive info in functions with these annotations
 
 use chrono::{DateTime, Utc};
+use opentelemetry::trace::TraceContextExt;
 use serde_json::json;
 use shuttle_common::STATE_MESSAGE;
-use std::{net::SocketAddr, str::FromStr};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
 use tracing::{error, field::Visit, span, warn, Metadata, Subscriber};
-use tracing_subscriber::Layer;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::{
+    registry::{LookupSpan, SpanRef},
+    Layer,
+};
 use uuid::Uuid;
 
-use crate::persistence::{self, DeploymentState, LogLevel, State};
+use crate::persistence::{self, DeploymentState, LogLevel, LogOrigin, State};
 
 /// Records logs for the deployment progress
 pub trait LogRecorder: Clone + Send + 'static {
     fn record(&self, log: Log);
 }
 
+/// Forwards every log to both its recorders so that logs can be shipped to multiple sinks at once. Nest composites
+/// to fan out to more than two recorders.
+#[derive(Clone)]
+pub struct CompositeLogRecorder<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> CompositeLogRecorder<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+impl<A, B> LogRecorder for CompositeLogRecorder<A, B>
+where
+    A: LogRecorder,
+    B: LogRecorder,
+{
+    fn record(&self, log: Log) {
+        record_isolated(&self.first, log.clone());
+        record_isolated(&self.second, log);
+    }
+}
+
+/// Record to a single sink without letting it bring down the other sinks
+fn record_isolated(recorder: &impl LogRecorder, log: Log) {
+    if panic::catch_unwind(AssertUnwindSafe(|| recorder.record(log))).is_err() {
+        error!("log recorder panicked while recording a log");
+    }
+}
+
+/// Mirrors every log to a file as a JSON line, independent of the state db. The file is moved to `<path>.1` (replacing
+/// any older one) once it would grow past `max_bytes`.
+#[derive(Clone)]
+pub struct FileLogRecorder {
+    sink: Arc<Mutex<FileSink>>,
+    reported_failure: Arc<AtomicBool>,
+}
+
+impl FileLogRecorder {
+    pub fn new(path: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            sink: Arc::new(Mutex::new(FileSink {
+                path: path.into(),
+                max_bytes,
+                file: None,
+                written: 0,
+            })),
+            reported_failure: Default::default(),
+        }
+    }
+}
+
+impl LogRecorder for FileLogRecorder {
+    fn record(&self, log: Log) {
+        // The end of a state only annotates its state log, which was already mirrored
+        if log.r#type == LogType::StateEnd {
+            return;
+        }
+
+        let line = persistence::Log::from(log).to_jsonl();
+        let result = self.sink.lock().unwrap().write_line(&line);
+
+        // Only report the first failure since an unwritable file would otherwise fail for every log. The lock has to be
+        // released first since the error is itself recorded when it happens in a deployment scope.
+        if let Err(error) = result {
+            if !self.reported_failure.swap(true, Ordering::Relaxed) {
+                error!(
+                    error = &error as &dyn std::error::Error,
+                    "failed to mirror logs to file, further failures will not be reported"
+                );
+            }
+        }
+    }
+}
+
+struct FileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    file: Option<File>,
+    written: u64,
+}
+
+impl FileSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        if self.written > 0 && self.written + len > self.max_bytes {
+            self.rotate()?;
+        }
+
+        let file = self.file.as_mut().expect("file to be opened");
+        writeln!(file, "{line}")?;
+        self.written += len;
+
+        Ok(())
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = file.metadata()?.len();
+        self.file = Some(file);
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+
+        self.open()
+    }
+}
+
 /// An event or state transition log
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Log {
@@ -63,16 +205,53 @@ pub struct Log {
 
     pub r#type: LogType,
 
-    pub address: Option<String>,
+    pub address: Option<SocketAddr>,
+
+    /// OpenTelemetry trace the log was emitted in
+    pub trace_id: Option<String>,
+
+    /// OpenTelemetry span the log was emitted in
+    pub span_id: Option<String>,
+
+    /// Whether the log is from the deployer or the service it runs
+    pub origin: LogOrigin,
+
+    /// How long the state took, set on the [LogType::StateEnd] log recorded when a state scope closes
+    pub duration_ms: Option<u64>,
+}
+
+impl Log {
+    /// Marker to let a stream subscriber of deployment `id` know that `skipped` logs were dropped from its stream. The
+    /// stream is shared by all the deployments, so these are not necessarily logs of `id`.
+    pub fn dropped_marker(id: Uuid, state: State, skipped: u64) -> Self {
+        Self {
+            id,
+            state,
+            level: LogLevel::Warn,
+            timestamp: Utc::now(),
+            file: None,
+            line: None,
+            target: String::new(),
+            fields: json!({ "message": format!("{skipped} logs of any deployment dropped from stream") }),
+            r#type: LogType::Event,
+            address: None,
+            trace_id: None,
+            span_id: None,
+            origin: LogOrigin::Deployer,
+            duration_ms: None,
+        }
+    }
 }
 
 impl From<Log> for persistence::Log {
     fn from(log: Log) -> Self {
         // Make sure state message is set for state logs
-        // This is used to know when the end of the build logs has been reached
-        let fields = match log.r#type {
-            LogType::Event => log.fields,
-            LogType::State => json!(STATE_MESSAGE),
+        // This is used to know when the end of the build logs has been reached. The fields of the scope are kept
+        // next to it.
+        let (fields, span_fields) = match log.r#type {
+            LogType::Event => (log.fields, None),
+            LogType::State => (json!(STATE_MESSAGE), Some(log.fields)),
+            LogType::StateEnd => (json!(STATE_MESSAGE), None),
         };
 
         Self {
@@ -84,6 +263,12 @@ impl From<Log> for persistence::Log {
             line: log.line,
             target: log.target,
             fields,
+            trace_id: log.trace_id,
+            span_id: log.span_id,
+            origin: log.origin,
+            span_fields,
+            duration_ms: log.duration_ms,
+            repeat_count: 1,
         }
     }
 }
@@ -105,14 +290,12 @@ impl From<Log> for shuttle_common::LogItem {
 
 impl From<Log> for DeploymentState {
     fn from(log: Log) -> Self {
-        let address = if let Some(address_str) = log.address {
-            match SocketAddr::from_str(&address_str) {
-                Ok(address) => Some(address),
-                Err(err) => {
-                    error!(error = %err, "failed to convert to [SocketAddr]");
-                    None
-                }
-            }
+        // Crashes are described by the `error` field of the event that recorded them
+        let error_message = if log.state == State::Crashed {
+            log.fields
+                .get("error")
+                .and_then(|error| error.as_str())
+                .map(str::to_string)
         } else {
             None
         };
@@ -121,7 +304,8 @@ impl From<Log> for DeploymentState {
             id: log.id,
             state: log.state,
             last_update: log.timestamp,
-            address,
+            address: log.address,
+            error_message,
         }
     }
 }
@@ -130,14 +314,24 @@ impl From<Log> for DeploymentState {
 pub enum LogType {
     Event,
     State,
+    /// The scope of a state closed, with how long the state took in [Log::duration_ms]
+    StateEnd,
 }
 
 /// Tracing subscriber layer which keeps track of a deployment's state
+///
+/// Clones share their minimum level, so a clone can be kept around to change the level of a layer which has already
+/// been installed.
+#[derive(Clone)]
 pub struct DeployLayer<R>
 where
     R: LogRecorder + Send + Sync,
 {
     recorder: R,
+    capture_trace_ids: bool,
+    max_field_len: usize,
+    ignored_targets: Vec<String>,
+    min_severity: Arc<AtomicU8>,
 }
 
 impl<R> DeployLayer<R>
@@ -145,7 +339,70 @@ where
     R: LogRecorder + Send + Sync,
 {
     pub fn new(recorder: R) -> Self {
-        Self { recorder }
+        Self {
+            recorder,
+            capture_trace_ids: true,
+            max_field_len: DEFAULT_MAX_FIELD_LEN,
+            ignored_targets: Vec::new(),
+            min_severity: Arc::new(AtomicU8::new(LogLevel::Trace.severity() as u8)),
+        }
+    }
+
+    /// Create a layer which does not record events from any of the `ignored_targets` or their submodules, like the
+    /// noisy logs of `hyper` or `sqlx`
+    pub fn new_with_filter<T>(recorder: R, ignored_targets: impl IntoIterator<Item = T>) -> Self
+    where
+        T: Into<String>,
+    {
+        Self {
+            ignored_targets: ignored_targets.into_iter().map(Into::into).collect(),
+            ..Self::new(recorder)
+        }
+    }
+
+    /// Set the longest string an event field can serialize to before being truncated
+    pub fn with_max_field_len(mut self, max_field_len: usize) -> Self {
+        self.max_field_len = max_field_len;
+        self
+    }
+
+    /// Set whether the OpenTelemetry trace and span ids of events should be recorded with their logs
+    pub fn with_trace_ids(mut self, capture_trace_ids: bool) -> Self {
+        self.capture_trace_ids = capture_trace_ids;
+        self
+    }
+
+    /// Stop recording events which are less severe than `level`. State transitions are always recorded.
+    pub fn set_min_level(&self, level: LogLevel) {
+        self.min_level_handle().set(level);
+    }
+
+    /// Get a handle to change the least severe level recorded by this layer once it is part of a subscriber
+    pub fn min_level_handle(&self) -> MinLevelHandle {
+        MinLevelHandle(self.min_severity.clone())
+    }
+
+    fn is_below_min_level(&self, level: &tracing::Level) -> bool {
+        LogLevel::from(level).severity() < i64::from(self.min_severity.load(Ordering::Relaxed))
+    }
+
+    fn is_ignored(&self, target: &str) -> bool {
+        self.ignored_targets.iter().any(|ignored| {
+            target
+                .strip_prefix(ignored.as_str())
+                .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+        })
+    }
+}
+
+/// Handle to the least severe level a [DeployLayer] records, which it shares with the layer
+#[derive(Clone)]
+pub struct MinLevelHandle(Arc<AtomicU8>);
+
+impl MinLevelHandle {
+    /// Stop recording events which are less severe than `level`. State transitions are always recorded.
+    pub fn set(&self, level: LogLevel) {
+        self.0.store(level.severity() as u8, Ordering::Relaxed);
     }
 }
 
@@ -155,6 +412,10 @@ where
     R: LogRecorder + Send + Sync + 'static,
 {
     fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if self.is_below_min_level(event.metadata().level()) {
+            return;
+        }
+
         // We only care about events in some state scope
         let scope = if let Some(scope) = ctx.event_scope(event) {
             scope
@@ -162,36 +423,46 @@ where
             return;
         };
 
+        let (trace_id, span_id) = ctx
+            .event_span(event)
+            .filter(|_| self.capture_trace_ids)
+            .map(|span| get_trace_ids(&span))
+            .unwrap_or_default();
+
         // Find the first scope with the scope details containing the current state
         for span in scope.from_root() {
             let extensions = span.extensions();
 
             if let Some(details) = extensions.get::<ScopeDetails>() {
-                let mut visitor = JsonVisitor::default();
+                let mut visitor = JsonVisitor::new(self.max_field_len);
 
                 event.record(&mut visitor);
                 let metadata = event.metadata();
 
                 // Extract details from log::Log interface which is different from tracing
-                let target = if let Some(target) = visitor.0.remove("log.target") {
+                let target = if let Some(target) = visitor.fields.remove("log.target") {
                     target.as_str().unwrap_or_default().to_string()
                 } else {
                     metadata.target().to_string()
                 };
 
-                let line = if let Some(line) = visitor.0.remove("log.line") {
+                let line = if let Some(line) = visitor.fields.remove("log.line") {
                     line.as_u64().and_then(|u| u32::try_from(u).ok())
                 } else {
                     metadata.line()
                 };
 
-                let file = if let Some(file) = visitor.0.remove("log.file") {
+                let file = if let Some(file) = visitor.fields.remove("log.file") {
                     file.as_str().map(str::to_string)
                 } else {
                     metadata.file().map(str::to_string)
                 };
 
-                visitor.0.remove("log.module_path");
+                visitor.fields.remove("log.module_path");
+
+                if self.is_ignored(&target) {
+                    break;
+                }
 
                 self.recorder.record(Log {
                     id: details.id,
@@ -201,9 +472,13 @@ where
                     file,
                     line,
                     target,
-                    fields: serde_json::Value::Object(visitor.0),
+                    fields: serde_json::Value::Object(visitor.fields),
                     r#type: LogType::Event,
                     address: None,
+                    trace_id,
+                    span_id,
+                    origin: LogOrigin::Deployer,
+                    duration_ms: None,
                 });
                 break;
             }
@@ -221,11 +496,12 @@ where
             return;
         }
 
-        let mut visitor = NewStateVisitor::default();
+        let mut visitor = NewStateVisitor::new(self.max_field_len);
 
         attrs.record(&mut visitor);
 
         let details = visitor.details;
+        let fields = visitor.extra.fields;
 
         if details.id.is_nil() {
             warn!("scope details does not have a valid id");
@@ -245,21 +521,106 @@ where
             file: metadata.file().map(str::to_string),
             line: metadata.line(),
             target: metadata.target().to_string(),
-            fields: Default::default(),
+            fields: serde_json::Value::Object(fields),
             r#type: LogType::State,
-            address: details.address.clone(),
+            address: details.address,
+            trace_id: None,
+            span_id: None,
+            origin: LogOrigin::Deployer,
+            duration_ms: None,
         });
 
         extensions.insert::<ScopeDetails>(details);
+        extensions.insert(ScopeStart(Instant::now()));
     }
+
+    fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let span = if let Some(span) = ctx.span(&id) {
+            span
+        } else {
+            return;
+        };
+
+        let (details, duration) = {
+            let extensions = span.extensions();
+
+            match (
+                extensions.get::<ScopeDetails>(),
+                extensions.get::<ScopeStart>(),
+            ) {
+                (Some(details), Some(ScopeStart(start))) => {
+                    ((details.id, details.state), start.elapsed())
+                }
+                // Not a state scope
+                _ => return,
+            }
+        };
+        let (id, state) = details;
+
+        let (trace_id, span_id) = if self.capture_trace_ids {
+            get_trace_ids(&span)
+        } else {
+            Default::default()
+        };
+        let duration_ms = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        let metadata = span.metadata();
+
+        self.recorder.record(Log {
+            id,
+            state,
+            level: metadata.level().into(),
+            timestamp: Utc::now(),
+            file: metadata.file().map(str::to_string),
+            line: metadata.line(),
+            target: metadata.target().to_string(),
+            fields: json!({}),
+            r#type: LogType::StateEnd,
+            address: None,
+            trace_id,
+            span_id,
+            origin: LogOrigin::Deployer,
+            duration_ms: Some(duration_ms),
+        });
+    }
+}
+
+/// Get the OpenTelemetry trace and span ids of a span, if it is being exported
+fn get_trace_ids<S>(span: &SpanRef<'_, S>) -> (Option<String>, Option<String>)
+where
+    S: for<'lookup> LookupSpan<'lookup>,
+{
+    let extensions = span.extensions();
+    let data = if let Some(data) = extensions.get::<OtelData>() {
+        data
+    } else {
+        return (None, None);
+    };
+
+    // A span joins the trace of its parent when it has one, like a request context set with `set_parent`. So the
+    // builder's own trace id only applies to root spans
+    let parent = data.parent_cx.span();
+    let context = parent.span_context();
+    let trace_id = if context.is_valid() {
+        Some(context.trace_id())
+    } else {
+        data.builder.trace_id
+    };
+
+    (
+        trace_id.map(|id| id.to_string()),
+        data.builder.span_id.map(|id| id.to_string()),
+    )
 }
 
+/// When a deployment scope was entered, to know how long its state took
+struct ScopeStart(Instant);
+
 /// Used to keep track of the current state a deployment scope is in
 #[derive(Debug, Default)]
 struct ScopeDetails {
     id: Uuid,
     state: State,
-    address: Option<String>,
+    address: Option<SocketAddr>,
 }
 
 impl From<&tracing::Level> for LogLevel {
@@ -274,13 +635,21 @@ impl From<&tracing::Level> for LogLevel {
     }
 }
 
-/// This visitor is meant to extract the `ScopeDetails` for any scope with `name` and `status` fields
-#[derive(Default)]
+/// This visitor is meant to extract the `ScopeDetails` for any scope with `name` and `status` fields. Any other
+/// fields of the scope are kept as the fields of its state log.
 struct NewStateVisitor {
     details: ScopeDetails,
+    extra: JsonVisitor,
 }
 
 impl NewStateVisitor {
+    fn new(max_field_len: usize) -> Self {
+        Self {
+            details: Default::default(),
+            extra: JsonVisitor::new(max_field_len),
+        }
+    }
+
     /// Field containing the deployment identifier
     const ID_IDENT: &'static str = "id";
 
@@ -297,48 +666,116 @@ impl NewStateVisitor {
     }
 }
 
-impl Visit for NewStateVisitor {
-    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+impl NewStateVisitor {
+    fn record_value(&mut self, field: &tracing::field::Field, value: &str) {
         if field.name() == Self::STATE_IDENT {
-            self.details.state = State::from_str(&format!("{value:?}")).unwrap_or_default();
+            self.details.state = match State::from_str(value) {
+                Ok(state) => state,
+                Err(error) => {
+                    warn!(%error, state = value, "scope details does not have a valid state");
+                    State::default()
+                }
+            };
         } else if field.name() == Self::ID_IDENT {
-            self.details.id = Uuid::try_parse(&format!("{value:?}")).unwrap_or_default();
+            self.details.id = Uuid::try_parse(value).unwrap_or_default();
         } else if field.name() == Self::ADDRESS_IDENT {
-            self.details.address = Some(format!("{value:?}"));
+            self.details.address = match persistence::parse_address(value) {
+                Ok(address) => Some(address),
+                Err(error) => {
+                    warn!(%error, "scope details does not have a valid address");
+                    None
+                }
+            };
+        } else {
+            self.extra.record_string(field, value.to_string());
         }
     }
 }
 
-#[derive(Default)]
-struct JsonVisitor(serde_json::Map<String, serde_json::Value>);
+impl Visit for NewStateVisitor {
+    // Plain strings would otherwise be quoted by their `Debug` formatting
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.record_value(field, value);
+    }
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.extra.record_bool(field, value);
+    }
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.extra.record_u64(field, value);
+    }
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.extra.record_i64(field, value);
+    }
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.extra.record_f64(field, value);
+    }
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.record_value(field, &format!("{value:?}"));
+    }
+}
+
+/// Largest string an event field can serialize to before being truncated
+const DEFAULT_MAX_FIELD_LEN: usize = 64 * 1024;
+
+/// Marker put at the end of truncated fields
+const TRUNCATED_MARKER: &str = "...[truncated]";
+
+struct JsonVisitor {
+    fields: serde_json::Map<String, serde_json::Value>,
+    max_field_len: usize,
+}
+
+impl JsonVisitor {
+    fn new(max_field_len: usize) -> Self {
+        Self {
+            fields: Default::default(),
+            max_field_len,
+        }
+    }
+
+    /// Record a string field, truncating it (and recording its original length) when it is too big to keep whole
+    fn record_string(&mut self, field: &tracing::field::Field, mut value: String) {
+        if value.len() > self.max_field_len {
+            self.fields
+                .insert(format!("{}.original_len", field.name()), json!(value.len()));
+
+            let mut end = self.max_field_len;
+            while !value.is_char_boundary(end) {
+                end -= 1;
+            }
+            value.truncate(end);
+            value.push_str(TRUNCATED_MARKER);
+        }
+
+        self.fields.insert(field.name().to_string(), json!(value));
+    }
+}
 
 impl Visit for JsonVisitor {
     fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
-        self.0.insert(field.name().to_string(), json!(value));
+        self.record_string(field, value.to_string());
     }
     fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
-        self.0.insert(field.name().to_string(), json!(value));
+        self.fields.insert(field.name().to_string(), json!(value));
     }
     fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
-        self.0.insert(field.name().to_string(), json!(value));
+        self.fields.insert(field.name().to_string(), json!(value));
     }
     fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
-        self.0.insert(field.name().to_string(), json!(value));
+        self.fields.insert(field.name().to_string(), json!(value));
     }
     fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
-        self.0.insert(field.name().to_string(), json!(value));
+        self.fields.insert(field.name().to_string(), json!(value));
     }
     fn record_error(
         &mut self,
         field: &tracing::field::Field,
         value: &(dyn std::error::Error + 'static),
     ) {
-        self.0
-            .insert(field.name().to_string(), json!(value.to_string()));
+        self.record_string(field, value.to_string());
     }
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        self.0
-            .insert(field.name().to_string(), json!(format!("{value:?}")));
+        self.record_string(field, format!("{value:?}"));
     }
 }
 
@@ -347,6 +784,7 @@ mod tests {
     use std::{
         collections::BTreeMap,
         fs::read_dir,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
         path::PathBuf,
         sync::{Arc, Mutex},
         time::Duration,
@@ -355,8 +793,11 @@ mod tests {
     use axum::body::Bytes;
     use ctor::ctor;
     use flate2::{write::GzEncoder, Compression};
+    use opentelemetry::trace::TracerProvider;
+    use serde_json::json;
     use shuttle_common::backends::auth::Claim;
     use shuttle_service::Logger;
+    use strum::IntoEnumIterator;
     use tokio::{select, sync::mpsc, time::sleep};
     use tracing_subscriber::prelude::*;
     use uuid::Uuid;
@@ -367,10 +808,10 @@ mod tests {
             runtime_logger, storage_manager::StorageManager, ActiveDeploymentsGetter, Built,
             DeploymentManager, Queued,
         },
-        persistence::{SecretRecorder, State},
+        persistence::{self, SecretRecorder, SecretSyncReport, State},
     };
 
-    use super::{DeployLayer, Log, LogRecorder};
+    use super::{CompositeLogRecorder, DeployLayer, FileLogRecorder, Log, LogRecorder};
 
     #[ctor]
     static RECORDER: Arc<Mutex<RecorderMock>> = {
@@ -443,6 +884,37 @@ mod tests {
         ) -> Result<(), Self::Err> {
             panic!("no tests should set secrets")
         }
+
+        async fn insert_secret_with_ttl(
+            &self,
+            _service_id: &Uuid,
+            _key: &str,
+            _value: &str,
+            _ttl: chrono::Duration,
+        ) -> Result<(), Self::Err> {
+            panic!("no tests should set secrets")
+        }
+
+        async fn sync_secrets(
+            &self,
+            _service_id: &Uuid,
+            _desired: &BTreeMap<String, String>,
+        ) -> Result<SecretSyncReport, Self::Err> {
+            panic!("no tests should set secrets")
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct EventRecorderMock {
+        events: Arc<Mutex<Vec<Log>>>,
+    }
+
+    impl LogRecorder for EventRecorderMock {
+        fn record(&self, log: Log) {
+            if log.r#type == LogType::Event {
+                self.events.lock().unwrap().push(log);
+            }
+        }
     }
 
     impl<R: LogRecorder> LogRecorder for Arc<Mutex<R>> {
@@ -658,6 +1130,11 @@ mod tests {
                     state: State::Running,
                     has_address: true,
                 },
+                StateLog {
+                    id,
+                    state: State::Stopping,
+                    has_address: false,
+                },
                 StateLog {
                     id,
                     state: State::Stopped,
@@ -940,7 +1417,7 @@ mod tests {
                 id,
                 service_name: "nil_id".to_string(),
                 service_id: Uuid::new_v4(),
-                data: Bytes::from("violets are red").to_vec(),
+                data: Bytes::from("violets are red").to_vec().into(),
                 will_run_tests: false,
                 tracing_context: Default::default(),
                 claim: None,
@@ -959,6 +1436,399 @@ mod tests {
         );
     }
 
+    #[test]
+    fn trace_ids() {
+        let recorder = EventRecorderMock::default();
+        let tracer = opentelemetry::sdk::trace::TracerProvider::builder()
+            .build()
+            .tracer("deploy-layer-test");
+        let subscriber = tracing_subscriber::registry()
+            .with(DeployLayer::new(recorder.clone()))
+            .with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let id = Uuid::new_v4();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _guard = tracing::info_span!("state", id = %id, state = %State::Building).entered();
+            tracing::info!("building");
+        });
+
+        let events = recorder.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events
+            .iter()
+            .all(|event| event.trace_id.is_some() && event.span_id.is_some()));
+    }
+
+    #[test]
+    fn trace_ids_from_parent_context() {
+        use opentelemetry::trace::{
+            SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+        };
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let recorder = EventRecorderMock::default();
+        let tracer = opentelemetry::sdk::trace::TracerProvider::builder()
+            .build()
+            .tracer("deploy-layer-test");
+        let subscriber = tracing_subscriber::registry()
+            .with(DeployLayer::new(recorder.clone()))
+            .with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let id = Uuid::new_v4();
+
+        // As extracted from the `tracing_context` of an incoming request
+        let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+        let parent_cx = opentelemetry::Context::new().with_remote_span_context(SpanContext::new(
+            trace_id,
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        ));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("state", id = %id, state = %State::Building);
+            span.set_parent(parent_cx);
+            let _guard = span.entered();
+            tracing::info!("building");
+        });
+
+        let events = recorder.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events
+            .iter()
+            .all(|event| event.trace_id == Some(trace_id.to_string()) && event.span_id.is_some()));
+    }
+
+    #[test]
+    fn trace_ids_without_context() {
+        let recorder = EventRecorderMock::default();
+        let subscriber = tracing_subscriber::registry().with(DeployLayer::new(recorder.clone()));
+        let id = Uuid::new_v4();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _guard = tracing::info_span!("state", id = %id, state = %State::Building).entered();
+            tracing::info!("building");
+        });
+
+        let events = recorder.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events
+            .iter()
+            .all(|event| event.trace_id.is_none() && event.span_id.is_none()));
+    }
+
+    #[test]
+    fn min_level() {
+        let recorder = EventRecorderMock::default();
+        let layer = DeployLayer::new(recorder.clone());
+        let handle = layer.min_level_handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let id = Uuid::new_v4();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _guard = tracing::info_span!("state", id = %id, state = %State::Building).entered();
+            tracing::info!("before");
+
+            handle.set(persistence::LogLevel::Warn);
+            tracing::info!("dropped");
+            tracing::error!("kept");
+        });
+
+        let events = recorder.events.lock().unwrap();
+        let messages: Vec<_> = events
+            .iter()
+            .filter_map(|event| event.fields["message"].as_str())
+            .collect();
+        assert_eq!(messages, ["before", "kept"]);
+    }
+
+    #[test]
+    fn ignored_targets() {
+        let recorder = EventRecorderMock::default();
+        let subscriber = tracing_subscriber::registry().with(DeployLayer::new_with_filter(
+            recorder.clone(),
+            ["hyper", "sqlx"],
+        ));
+        let id = Uuid::new_v4();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _guard = tracing::info_span!("state", id = %id, state = %State::Building).entered();
+            tracing::info!(target: "hyper::proto::h1", "noisy");
+            tracing::info!(target: "sqlx", "noisy");
+            tracing::info!(target: "hyperlocal", "kept");
+            tracing::info!("kept");
+        });
+
+        let events = recorder.events.lock().unwrap();
+        let targets: Vec<_> = events.iter().map(|event| event.target.as_str()).collect();
+        assert_eq!(targets, ["hyperlocal", module_path!()]);
+    }
+
+    #[test]
+    fn file_recorder() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deployer.jsonl");
+        let recorder = FileLogRecorder::new(&path, 1024 * 1024);
+        let id = Uuid::new_v4();
+
+        let logs: Vec<_> = (0..3)
+            .map(|i| Log {
+                fields: json!({ "message": format!("line {i}") }),
+                ..Log::dropped_marker(id, State::Running, 0)
+            })
+            .collect();
+        for log in logs.iter() {
+            recorder.record(log.clone());
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents
+            .lines()
+            .map(|line| persistence::Log::from_jsonl(line).unwrap())
+            .collect();
+        let expected: Vec<persistence::Log> = logs.into_iter().map(Into::into).collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn file_recorder_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deployer.jsonl");
+        let log = Log::dropped_marker(Uuid::new_v4(), State::Running, 0);
+        let line_len = persistence::Log::from(log.clone()).to_jsonl().len() as u64 + 1;
+
+        // Room for two lines per file
+        let recorder = FileLogRecorder::new(&path, line_len * 2);
+        for _ in 0..3 {
+            recorder.record(log.clone());
+        }
+
+        let line_count =
+            |path: &std::path::Path| std::fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(line_count(&dir.path().join("deployer.jsonl.1")), 2);
+        assert_eq!(line_count(&path), 1);
+    }
+
+    #[test]
+    fn file_recorder_unwritable() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder =
+            FileLogRecorder::new(dir.path().join("missing").join("deployer.jsonl"), 1024);
+
+        // Should not panic, but only report the failure
+        for _ in 0..2 {
+            recorder.record(Log::dropped_marker(Uuid::new_v4(), State::Running, 0));
+        }
+        assert!(recorder
+            .reported_failure
+            .load(std::sync::atomic::Ordering::Relaxed));
+    }
+
+    #[test]
+    fn span_timing() {
+        #[derive(Clone, Default)]
+        struct AllRecorderMock(Arc<Mutex<Vec<Log>>>);
+
+        impl LogRecorder for AllRecorderMock {
+            fn record(&self, log: Log) {
+                self.0.lock().unwrap().push(log);
+            }
+        }
+
+        let recorder = AllRecorderMock::default();
+        let subscriber = tracing_subscriber::registry().with(DeployLayer::new(recorder.clone()));
+        let id = Uuid::new_v4();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _guard = tracing::info_span!("state", id = %id, state = %State::Building).entered();
+            tracing::info!("building");
+            std::thread::sleep(Duration::from_millis(20));
+        });
+
+        let logs = recorder.0.lock().unwrap();
+        let types: Vec<_> = logs.iter().map(|log| log.r#type.clone()).collect();
+        assert_eq!(
+            types,
+            [LogType::State, LogType::Event, LogType::StateEnd],
+            "the duration should not be recorded as an extra event"
+        );
+        assert!(logs[..2].iter().all(|log| log.duration_ms.is_none()));
+
+        let end = &logs[2];
+        assert_eq!(end.id, id);
+        assert_eq!(end.state, State::Building);
+        assert!(end.duration_ms.unwrap() >= 20);
+    }
+
+    #[test]
+    fn string_scope_fields() {
+        let states = record_scope_states(|id| {
+            let id = id.to_string();
+
+            let _guard = tracing::info_span!(
+                "state",
+                id = id.as_str(),
+                state = "Running",
+                address = "127.0.0.1:8000"
+            )
+            .entered();
+        });
+
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].state, State::Running);
+        assert_eq!(
+            states[0].address,
+            Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8000))
+        );
+    }
+
+    #[test]
+    fn scope_address() {
+        let states = record_scope_states(|id| {
+            let address = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 8000);
+            let _guard =
+                tracing::info_span!("state", id = %id, state = %State::Running, address = %address)
+                    .entered();
+        });
+
+        assert_eq!(
+            states[0].address,
+            Some(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 8000))
+        );
+
+        let states = record_scope_states(|id| {
+            let _guard = tracing::info_span!("state", id = %id, state = %State::Running, address = "localhost")
+                .entered();
+        });
+
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].address, None);
+    }
+
+    #[test]
+    fn scope_extra_fields() {
+        let states = record_scope_states(|id| {
+            let _guard = tracing::info_span!(
+                "state",
+                id = %id,
+                state = %State::Loading,
+                service_name = "hello-world",
+                attempt = 2
+            )
+            .entered();
+        });
+
+        assert_eq!(states.len(), 1);
+        assert_eq!(
+            states[0].fields,
+            json!({ "service_name": "hello-world", "attempt": 2 })
+        );
+    }
+
+    #[test]
+    fn scope_states() {
+        for state in State::iter() {
+            let states = record_scope_states(|id| {
+                let _display = tracing::info_span!("state", id = %id, state = %state).entered();
+                let _debug = tracing::info_span!("state", id = %id, state = ?state).entered();
+            });
+
+            assert_eq!(states.len(), 2);
+            assert!(states.iter().all(|log| log.state == state), "{state}");
+        }
+    }
+
+    /// Get the state logs recorded while running `f` with the id of the deployment it should use
+    fn record_scope_states(f: impl FnOnce(Uuid)) -> Vec<Log> {
+        #[derive(Clone, Default)]
+        struct StateRecorderMock(Arc<Mutex<Vec<Log>>>);
+
+        impl LogRecorder for StateRecorderMock {
+            fn record(&self, log: Log) {
+                if log.r#type == LogType::State {
+                    self.0.lock().unwrap().push(log);
+                }
+            }
+        }
+
+        let recorder = StateRecorderMock::default();
+        let subscriber = tracing_subscriber::registry().with(DeployLayer::new(recorder.clone()));
+        let id = Uuid::new_v4();
+
+        tracing::subscriber::with_default(subscriber, || f(id));
+
+        let states = recorder.0.lock().unwrap().clone();
+        assert!(states.iter().all(|log| log.id == id));
+
+        states
+    }
+
+    #[test]
+    fn oversized_fields() {
+        let recorder = EventRecorderMock::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(DeployLayer::new(recorder.clone()).with_max_field_len(16));
+        let id = Uuid::new_v4();
+        let huge = "a".repeat(100);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _guard = tracing::info_span!("state", id = %id, state = %State::Building).entered();
+            tracing::info!(huge = huge.as_str(), small = "fits", "building");
+        });
+
+        let events = recorder.events.lock().unwrap();
+        let fields = &events[0].fields;
+
+        assert_eq!(
+            fields["huge"],
+            json!(format!("{}...[truncated]", "a".repeat(16)))
+        );
+        assert_eq!(fields["huge.original_len"], json!(100));
+        assert_eq!(fields["small"], json!("fits"));
+        assert!(fields.get("small.original_len").is_none());
+    }
+
+    #[test]
+    fn composite_recorder() {
+        let first = EventRecorderMock::default();
+        let second = EventRecorderMock::default();
+        let subscriber = tracing_subscriber::registry().with(DeployLayer::new(
+            CompositeLogRecorder::new(first.clone(), second.clone()),
+        ));
+        let id = Uuid::new_v4();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _guard = tracing::info_span!("state", id = %id, state = %State::Building).entered();
+            tracing::info!("building");
+            tracing::info!("still building");
+        });
+
+        let first = first.events.lock().unwrap();
+        let second = second.events.lock().unwrap();
+        assert_eq!(first.len(), 2);
+        assert_eq!(*first, *second);
+    }
+
+    #[test]
+    fn composite_recorder_bad_sink() {
+        #[derive(Clone)]
+        struct PanickingRecorder;
+
+        impl LogRecorder for PanickingRecorder {
+            fn record(&self, _log: Log) {
+                panic!("sink is down");
+            }
+        }
+
+        let good = EventRecorderMock::default();
+        let recorder = CompositeLogRecorder::new(PanickingRecorder, good.clone());
+
+        recorder.record(Log::dropped_marker(Uuid::new_v4(), State::Building, 1));
+        recorder.record(Log::dropped_marker(Uuid::new_v4(), State::Building, 2));
+
+        assert_eq!(good.events.lock().unwrap().len(), 2);
+    }
+
     fn get_deployment_manager() -> DeploymentManager {
         DeploymentManager::builder()
             .abstract_factory(StubAbstractProvisionerFactory)
@@ -997,7 +1867,7 @@ mod tests {
             id: Uuid::new_v4(),
             service_name: format!("deploy-layer-{name}"),
             service_id: Uuid::new_v4(),
-            data: bytes,
+            data: bytes.into(),
             will_run_tests: false,
             tracing_context: Default::default(),
             claim: None,
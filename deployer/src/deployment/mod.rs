@@ -8,7 +8,7 @@ mod storage_manager;
 
 use std::path::PathBuf;
 
-pub use queue::Queued;
+pub use queue::{Payload, Queued};
 pub use run::{ActiveDeploymentsGetter, Built};
 use tracing::{instrument, Span};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
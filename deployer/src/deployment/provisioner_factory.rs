@@ -1,4 +1,4 @@
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{collections::BTreeMap, future::Future, path::PathBuf, time::Duration};
 
 use async_trait::async_trait;
 use shuttle_common::{
@@ -18,7 +18,7 @@ use tonic::{
     Request,
 };
 use tower::ServiceBuilder;
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 use uuid::Uuid;
 
 use crate::persistence::{Resource, ResourceManager, ResourceType, SecretGetter};
@@ -48,6 +48,8 @@ pub struct AbstractProvisionerFactory<R: ResourceManager, S: SecretGetter> {
     provisioner_uri: Endpoint,
     resource_manager: R,
     secret_getter: S,
+    connect_retries: u32,
+    connect_backoff: Duration,
 }
 
 #[async_trait]
@@ -63,7 +65,15 @@ impl<R: ResourceManager, S: SecretGetter> AbstractFactory for AbstractProvisione
         storage_manager: StorageManager,
         claim: Option<Claim>,
     ) -> Result<Self::Output, Self::Error> {
-        let channel = self.provisioner_uri.clone().connect().await?;
+        let channel = retry_with_backoff(self.connect_retries, self.connect_backoff, || {
+            let provisioner_uri = self.provisioner_uri.clone();
+            async move { provisioner_uri.connect().await }
+        })
+        .await
+        .map_err(|error| ProvisionerError::ConnectRetriesExhausted {
+            attempts: self.connect_retries + 1,
+            error,
+        })?;
         let channel = ServiceBuilder::new()
             .layer(ClaimLayer)
             .layer(InjectPropagationLayer)
@@ -92,14 +102,62 @@ impl<R: ResourceManager, S: SecretGetter> AbstractProvisionerFactory<R, S> {
             provisioner_uri,
             resource_manager,
             secret_getter,
+            connect_retries: 0,
+            connect_backoff: Duration::ZERO,
         }
     }
+
+    /// Retry connecting to the provisioner up to `retries` times, doubling the wait from `backoff` after each failure
+    pub fn with_connect_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.connect_retries = retries;
+        self.connect_backoff = backoff;
+
+        self
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum ProvisionerError {
     #[error("failed to connect to provisioner: {0}")]
     TonicClient(#[from] tonic::transport::Error),
+    #[error("failed to connect to provisioner after {attempts} attempts: {error}")]
+    ConnectRetriesExhausted {
+        attempts: u32,
+        error: tonic::transport::Error,
+    },
+}
+
+/// Get how long to wait before the retry following the failed `attempt` (starting at 0)
+pub fn backoff_delay(base: Duration, attempt: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempt))
+}
+
+/// Run `f` until it succeeds or has been retried `retries` times, returning the last error in the latter case
+pub async fn retry_with_backoff<F, Fut, T, E>(
+    retries: u32,
+    base: Duration,
+    mut f: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    let mut attempt = 0;
+
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt < retries => {
+                let delay = backoff_delay(base, attempt);
+                warn!(%error, attempt, ?delay, "attempt failed, retrying");
+
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
 }
 
 /// A factory (service locator) which goes through the provisioner crate
@@ -249,3 +307,55 @@ impl<R: ResourceManager, S: SecretGetter> Factory for ProvisionerFactory<R, S> {
             .map_err(Into::into)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{backoff_delay, retry_with_backoff};
+
+    #[test]
+    fn backoff_doubles() {
+        let base = Duration::from_millis(100);
+
+        assert_eq!(backoff_delay(base, 0), Duration::from_millis(100));
+        assert_eq!(backoff_delay(base, 1), Duration::from_millis(200));
+        assert_eq!(backoff_delay(base, 4), Duration::from_millis(1600));
+        assert_eq!(backoff_delay(Duration::MAX, 1), Duration::MAX);
+    }
+
+    #[tokio::test]
+    async fn retry_succeeds_eventually() {
+        let mut calls = 0;
+
+        let result = retry_with_backoff(3, Duration::from_millis(1), || {
+            calls += 1;
+            let calls = calls;
+            async move {
+                if calls < 3 {
+                    Err("not ready")
+                } else {
+                    Ok(calls)
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(result, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn retry_exhausted() {
+        let mut calls = 0;
+
+        let result: Result<(), _> = retry_with_backoff(2, Duration::from_millis(1), || {
+            calls += 1;
+            let calls = calls;
+            async move { Err(format!("failure {calls}")) }
+        })
+        .await;
+
+        assert_eq!(result, Err("failure 3".to_string()));
+        assert_eq!(calls, 3);
+    }
+}
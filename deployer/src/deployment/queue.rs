@@ -3,7 +3,7 @@ use super::gateway_client::BuildQueueClient;
 use super::storage_manager::StorageManager;
 use super::{Built, QueueReceiver, RunSender, State};
 use crate::error::{Error, Result, TestError};
-use crate::persistence::{LogLevel, SecretRecorder};
+use crate::persistence::{LogLevel, LogOrigin, SecretRecorder};
 
 use cargo::util::interning::InternedString;
 use cargo_metadata::Message;
@@ -14,14 +14,14 @@ use serde_json::json;
 use shuttle_common::backends::auth::Claim;
 use shuttle_service::loader::{build_crate, get_config};
 use tokio::time::{sleep, timeout};
-use tracing::{debug, debug_span, error, info, instrument, trace, warn, Instrument, Span};
+use tracing::{debug_span, error, info, instrument, trace, warn, Instrument, Span};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 use uuid::Uuid;
 
 use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use std::fs::remove_file;
-use std::io::Read;
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
@@ -140,11 +140,47 @@ async fn promote_to_run(mut built: Built, run_send: RunSender) {
     }
 }
 
+/// The `.tar.gz` a deployment is built from. Only a reader is kept so that big archives can be streamed from disk while
+/// they are extracted rather than being held in memory.
+pub struct Payload(Box<dyn Read + Send>);
+
+impl Payload {
+    pub fn from_reader(reader: impl Read + Send + 'static) -> Self {
+        Self(Box::new(reader))
+    }
+
+    /// Create a file at `path` to spool an upload into. The file is unlinked straight away, so it is gone from disk as
+    /// soon as it is dropped, even when the upload fails halfway.
+    pub fn spool_file(path: impl AsRef<Path>) -> io::Result<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        remove_file(path)?;
+
+        Ok(file)
+    }
+
+    /// Stream the archive from the start of a file made by [Payload::spool_file]
+    pub fn from_spooled(mut file: File) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(0))?;
+
+        Ok(Self::from_reader(BufReader::new(file)))
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(data: Vec<u8>) -> Self {
+        Self::from_reader(Cursor::new(data))
+    }
+}
+
 pub struct Queued {
     pub id: Uuid,
     pub service_name: String,
     pub service_id: Uuid,
-    pub data: Vec<u8>,
+    pub data: Payload,
     pub will_run_tests: bool,
     pub tracing_context: HashMap<String, String>,
     pub claim: Option<Claim>,
@@ -162,7 +198,7 @@ impl Queued {
 
         let project_path = storage_manager.service_build_path(&self.service_name)?;
 
-        extract_tar_gz_data(self.data.as_slice(), &project_path).await?;
+        extract_tar_gz_data(self.data.0, &project_path).await?;
 
         let secrets = get_secrets(&project_path).await?;
         set_secrets(secrets, &self.service_id, secret_recorder).await?;
@@ -188,6 +224,10 @@ impl Queued {
                         fields: json!({ "build_line": line }),
                         r#type: LogType::Event,
                         address: None,
+                        trace_id: None,
+                        span_id: None,
+                        origin: LogOrigin::Deployer,
+                        duration_ms: None,
                     },
                     message => Log {
                         id,
@@ -200,6 +240,10 @@ impl Queued {
                         fields: serde_json::to_value(message).unwrap(),
                         r#type: LogType::Event,
                         address: None,
+                        trace_id: None,
+                        span_id: None,
+                        origin: LogOrigin::Deployer,
+                        duration_ms: None,
                     },
                 };
                 log_recorder.record(log);
@@ -268,19 +312,15 @@ async fn set_secrets(
     service_id: &Uuid,
     secret_recorder: impl SecretRecorder,
 ) -> Result<()> {
-    for (key, value) in secrets.into_iter() {
-        debug!(key, "setting secret");
-
-        secret_recorder
-            .insert_secret(service_id, &key, &value)
-            .await
-            .map_err(|e| Error::SecretsSet(Box::new(e)))?;
-    }
+    secret_recorder
+        .insert_secrets(service_id, &secrets)
+        .await
+        .map_err(|e| Error::SecretsSet(Box::new(e)))?;
 
     Ok(())
 }
 
-/// Equivalent to the command: `tar -xzf --strip-components 1`
+/// Equivalent to the command: `tar -xzf --strip-components 1`. Entries are unpacked as they are read from `data`.
 #[instrument(skip(data, dest))]
 async fn extract_tar_gz_data(data: impl Read, dest: impl AsRef<Path>) -> Result<()> {
     let tar = GzDecoder::new(data);
@@ -403,12 +443,19 @@ async fn store_lib(
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::BTreeMap, fs::File, io::Write, path::Path};
+    use std::{
+        collections::BTreeMap,
+        fs::File,
+        io::{self, Read, Write},
+        path::Path,
+    };
 
+    use flate2::{write::GzEncoder, Compression};
     use tempfile::Builder;
     use tokio::fs;
     use uuid::Uuid;
 
+    use super::Payload;
     use crate::{deployment::storage_manager::StorageManager, error::TestError};
 
     #[tokio::test]
@@ -514,6 +561,84 @@ ff0e55bda1ff01000000000000000000e0079c01ff12a55500280000",
             .unwrap();
     }
 
+    #[tokio::test]
+    async fn extract_streamed_upload() {
+        let dir = Builder::new()
+            .prefix("shuttle-streamed-extraction-test")
+            .tempdir()
+            .unwrap();
+        let upload_path = dir.path().join("upload.tar.gz");
+        let dest = dir.path().join("build");
+        fs::create_dir(&dest).await.unwrap();
+
+        // The archive is written into a spool file the way an upload is, and extracted from it
+        const BIG_FILE_LEN: u64 = 64 * 1024 * 1024;
+        let spool = Payload::spool_file(&upload_path).unwrap();
+        assert!(
+            !upload_path.exists(),
+            "the upload should be unlinked once it is created"
+        );
+
+        let mut tar = tar::Builder::new(GzEncoder::new(spool, Compression::fast()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(BIG_FILE_LEN);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(
+            &mut header,
+            "project/big.bin",
+            io::repeat(0).take(BIG_FILE_LEN),
+        )
+        .unwrap();
+        let spool = tar.into_inner().unwrap().finish().unwrap();
+
+        let payload = Payload::from_spooled(spool).unwrap();
+        let mut reader = ReadRecorder {
+            inner: payload.0,
+            largest_buf: 0,
+            total_read: 0,
+        };
+
+        super::extract_tar_gz_data(&mut reader, &dest)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fs::metadata(dest.join("big.bin")).await.unwrap().len(),
+            BIG_FILE_LEN
+        );
+        assert!(
+            reader.total_read > reader.largest_buf as u64,
+            "the upload should be streamed over more than one read"
+        );
+        assert!(
+            reader.largest_buf <= MAX_READ_LEN,
+            "the upload should be read in small chunks, but {} bytes were asked for at once",
+            reader.largest_buf
+        );
+    }
+
+    /// The most bytes extracting is expected to ask for from an upload at once. It is far below the size of the big file
+    /// in `extract_streamed_upload`, so the upload is never held in memory whole.
+    const MAX_READ_LEN: usize = 64 * 1024;
+
+    /// Records how an upload is read while passing the reads through to `inner`
+    struct ReadRecorder<R> {
+        inner: R,
+        largest_buf: usize,
+        total_read: u64,
+    }
+
+    impl<R: Read> Read for ReadRecorder<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.largest_buf = self.largest_buf.max(buf.len());
+            let read = self.inner.read(buf)?;
+            self.total_read += read as u64;
+
+            Ok(read)
+        }
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn run_pre_deploy_tests() {
         let root = Path::new(env!("CARGO_MANIFEST_DIR"));
@@ -162,6 +162,11 @@ fn completed_cleanup(_id: &Uuid) {
     info!("service finished all on its own");
 }
 
+#[instrument(skip(_id), fields(id = %_id, state = %State::Stopping))]
+fn stopping(_id: &Uuid) {
+    info!("service is being stopped by the user");
+}
+
 #[instrument(skip(_id), fields(id = %_id, state = %State::Stopped))]
 fn stopped_cleanup(_id: &Uuid) {
     info!("service was stopped by the user");
@@ -248,6 +253,7 @@ async fn run(
              Ok(kill_id) = kill_recv.recv() => {
                  if kill_id == id {
                      debug!("deployment '{id}' killed");
+                     stopping(&id);
                      handle.abort();
                      result = handle.await;
                      break;
@@ -4,6 +4,7 @@ use tokio::sync::mpsc::{self, UnboundedSender};
 use uuid::Uuid;
 
 use super::deploy_layer::{self, LogType};
+use crate::persistence::LogOrigin;
 
 pub trait Factory: Send + 'static {
     fn get_logger(&self, id: Uuid) -> Logger;
@@ -49,6 +50,10 @@ impl From<LogItem> for deploy_layer::Log {
             fields: serde_json::from_slice(&log.fields).unwrap(),
             r#type: LogType::Event,
             address: None,
+            trace_id: None,
+            span_id: None,
+            origin: LogOrigin::Runtime,
+            duration_ms: None,
         }
     }
 }
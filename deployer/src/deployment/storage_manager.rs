@@ -37,6 +37,21 @@ impl StorageManager {
         Ok(libs_path)
     }
 
+    /// Path of the directory that keeps uploaded archives until they are extracted
+    pub fn uploads_path(&self) -> Result<PathBuf, io::Error> {
+        let uploads_path = self.artifacts_path.join("shuttle-uploads");
+        fs::create_dir_all(&uploads_path)?;
+
+        Ok(uploads_path)
+    }
+
+    /// Path to the uploaded `.tar.gz` of a deployment
+    pub fn deployment_upload_path(&self, deployment_id: &Uuid) -> Result<PathBuf, io::Error> {
+        let upload_path = self.uploads_path()?.join(format!("{deployment_id}.tar.gz"));
+
+        Ok(upload_path)
+    }
+
     /// Path to `.so` for a service
     pub fn deployment_library_path(&self, deployment_id: &Uuid) -> Result<PathBuf, io::Error> {
         let library_path = self.libraries_path()?.join(deployment_id.to_string());
@@ -14,12 +14,8 @@ pub enum Error {
     Streaming(#[from] axum::Error),
     #[error("Persistence failure: {0}")]
     Persistence(#[from] crate::persistence::PersistenceError),
-    #[error("Failed to convert {from} to {to}")]
-    Convert {
-        from: String,
-        to: String,
-        message: String,
-    },
+    #[error("Invalid address: {0}")]
+    AddressParse(#[from] crate::persistence::AddressParseError),
     #[error("record could not be found")]
     NotFound,
     #[error("Custom error: {0}")]
@@ -45,6 +41,21 @@ impl IntoResponse for Error {
 
         let code = match self {
             Error::NotFound => StatusCode::NOT_FOUND,
+            Error::Persistence(crate::persistence::PersistenceError::PoolTimeout) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Error::Persistence(crate::persistence::PersistenceError::TooManyActiveDeployments(
+                _,
+            )) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Persistence(crate::persistence::PersistenceError::NotFound) => {
+                StatusCode::NOT_FOUND
+            }
+            Error::Persistence(crate::persistence::PersistenceError::UniqueViolation(_)) => {
+                StatusCode::CONFLICT
+            }
+            Error::Persistence(crate::persistence::PersistenceError::ConnectionClosed(_)) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
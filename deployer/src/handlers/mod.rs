@@ -5,9 +5,8 @@ use axum::extract::{Extension, Path, Query};
 use axum::handler::Handler;
 use axum::headers::HeaderMapExt;
 use axum::middleware::from_extractor;
-use axum::routing::{get, post, Router};
+use axum::routing::{get, post, put, Router};
 use axum::{extract::BodyStream, Json};
-use bytes::BufMut;
 use chrono::{TimeZone, Utc};
 use fqdn::FQDN;
 use futures::StreamExt;
@@ -21,11 +20,17 @@ use shuttle_common::models::secret;
 use shuttle_common::project::ProjectName;
 use shuttle_common::{request_span, LogItem};
 use shuttle_service::loader::clean_crate;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::error::RecvError;
 use tracing::{debug, error, field, instrument, trace};
 use uuid::Uuid;
 
-use crate::deployment::{DeploymentManager, Queued};
-use crate::persistence::{Deployment, Log, Persistence, ResourceManager, SecretGetter, State};
+use crate::args::AdminSecretHash;
+use crate::deployment::deploy_layer::{self, MinLevelHandle};
+use crate::deployment::{DeploymentManager, Payload, Queued};
+use crate::persistence::{
+    Deployment, Log, LogLevel, Persistence, ResourceManager, SecretGetter, State,
+};
 
 use std::collections::HashMap;
 
@@ -36,8 +41,9 @@ mod project;
 pub async fn make_router(
     persistence: Persistence,
     deployment_manager: DeploymentManager,
+    min_log_level: MinLevelHandle,
     proxy_fqdn: FQDN,
-    admin_secret: String,
+    admin_secret: AdminSecretHash,
     auth_uri: Uri,
     project_name: ProjectName,
 ) -> Router {
@@ -77,11 +83,18 @@ pub async fn make_router(
             "/projects/:project_name/clean",
             post(post_clean.layer(ScopedLayer::new(vec![Scope::DeploymentPush]))),
         )
+        .route(
+            "/projects/:project_name/admin/log-level",
+            put(put_log_level.layer(ScopedLayer::new(vec![Scope::Admin]))),
+        )
         .layer(Extension(persistence))
         .layer(Extension(deployment_manager))
+        .layer(Extension(min_log_level))
         .layer(Extension(proxy_fqdn))
         .layer(JwtAuthenticationLayer::new(AuthPublicKey::new(auth_uri)))
-        .layer(AdminSecretLayer::new(admin_secret))
+        .layer(AdminSecretLayer::with_verifier(move |secret| {
+            admin_secret.matches(secret)
+        }))
         // This route should be below the auth bearer since it does not need authentication
         .route("/projects/:project_name/status", get(get_status))
         .route_layer(from_extractor::<Metrics>())
@@ -208,15 +221,27 @@ async fn post_service(
         state: State::Queued,
         last_update: Utc::now(),
         address: None,
+        error_message: None,
+        running_since: None,
     };
 
-    let mut data = Vec::new();
+    // Spool the upload to disk so that big projects are not held in memory until they are extracted
+    let upload_path = deployment_manager
+        .storage_manager()
+        .deployment_upload_path(&id)
+        .map_err(anyhow::Error::from)?;
+    let mut upload =
+        tokio::fs::File::from_std(Payload::spool_file(&upload_path).map_err(anyhow::Error::from)?);
+    let mut received = 0;
     while let Some(buf) = stream.next().await {
         let buf = buf?;
         debug!("Received {} bytes", buf.len());
-        data.put(buf);
+        received += buf.len();
+        upload.write_all(&buf).await.map_err(anyhow::Error::from)?;
     }
-    debug!("Received a total of {} bytes", data.len());
+    upload.flush().await.map_err(anyhow::Error::from)?;
+    debug!("Received a total of {received} bytes");
+    let data = Payload::from_spooled(upload.into_std().await).map_err(anyhow::Error::from)?;
 
     persistence.insert_deployment(deployment.clone()).await?;
 
@@ -326,7 +351,7 @@ async fn get_logs_subscribe(
 }
 
 async fn logs_websocket_handler(mut s: WebSocket, persistence: Persistence, id: Uuid) {
-    let mut log_recv = persistence.get_log_subscriber();
+    let mut log_recv = persistence.subscribe_to_deployment(&id);
     let backlog = match persistence.get_deployment_logs(&id).await {
         Ok(backlog) => backlog,
         Err(error) => {
@@ -345,9 +370,11 @@ async fn logs_websocket_handler(mut s: WebSocket, persistence: Persistence, id:
 
     // Unwrap is safe because it only returns None for out of range numbers or invalid nanosecond
     let mut last_timestamp = Utc.timestamp_opt(0, 0).unwrap();
+    let mut last_state = State::default();
 
     for log in backlog.into_iter() {
         last_timestamp = log.timestamp;
+        last_state = log.state;
         if let Some(log_item) = Option::<LogItem>::from(log) {
             let msg = serde_json::to_string(&log_item).expect("to convert log item to json");
             let sent = s.send(ws::Message::Text(msg)).await;
@@ -359,19 +386,47 @@ async fn logs_websocket_handler(mut s: WebSocket, persistence: Persistence, id:
         }
     }
 
-    while let Ok(log) = log_recv.recv().await {
-        trace!(?log, "received log from broadcast channel");
+    loop {
+        let log_item = match log_recv.recv().await {
+            Ok(log) => {
+                trace!(?log, "received log from broadcast channel");
 
-        if log.id == id && log.timestamp > last_timestamp {
-            if let Some(log_item) = Option::<LogItem>::from(Log::from(log)) {
-                let msg = serde_json::to_string(&log_item).expect("to convert log item to json");
-                let sent = s.send(ws::Message::Text(msg)).await;
+                if log.id != id || log.timestamp <= last_timestamp {
+                    continue;
+                }
+
+                last_state = log.state;
 
-                // Client disconnected?
-                if sent.is_err() {
-                    return;
+                match Option::<LogItem>::from(Log::from(log)) {
+                    Some(log_item) => log_item,
+                    None => continue,
+                }
+            }
+            Err(RecvError::Lagged(skipped)) if persistence.stream_lag_markers() => {
+                debug!(
+                    skipped,
+                    "log subscriber lagged behind the broadcast channel"
+                );
+
+                let marker = deploy_layer::Log::dropped_marker(id, last_state, skipped);
+                if let Err(error) = persistence.record_dropped_marker(&marker).await {
+                    error!(
+                        error = &error as &dyn std::error::Error,
+                        "failed to record dropped logs marker"
+                    );
                 }
+
+                marker.into()
             }
+            Err(_) => break,
+        };
+
+        let msg = serde_json::to_string(&log_item).expect("to convert log item to json");
+        let sent = s.send(ws::Message::Text(msg)).await;
+
+        // Client disconnected?
+        if sent.is_err() {
+            return;
         }
     }
 
@@ -411,6 +466,17 @@ async fn post_clean(
     Ok(Json(lines))
 }
 
+/// Change the least severe level of deployment logs which are recorded, without restarting the deployer
+#[instrument(skip(min_log_level))]
+async fn put_log_level(
+    Extension(min_log_level): Extension<MinLevelHandle>,
+    Json(level): Json<LogLevel>,
+) -> Json<LogLevel> {
+    min_log_level.set(level.clone());
+
+    Json(level)
+}
+
 async fn get_status() -> String {
     "Ok".to_string()
 }
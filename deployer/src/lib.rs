@@ -2,7 +2,10 @@ use std::{convert::Infallible, net::SocketAddr};
 
 pub use args::Args;
 pub use deployment::{
-    deploy_layer::DeployLayer, provisioner_factory::AbstractProvisionerFactory,
+    deploy_layer::{
+        CompositeLogRecorder, DeployLayer, FileLogRecorder, LogRecorder, MinLevelHandle,
+    },
+    provisioner_factory::AbstractProvisionerFactory,
     runtime_logger::RuntimeLoggerFactory,
 };
 use deployment::{provisioner_factory, runtime_logger, Built, DeploymentManager};
@@ -11,7 +14,11 @@ use hyper::{
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
 };
-pub use persistence::Persistence;
+#[cfg(feature = "blocking")]
+pub use persistence::blocking::BlockingPersistence;
+pub use persistence::{
+    format_logs_as_text, LogLevel, Persistence, PersistenceConfig, PersistenceHandle,
+};
 use proxy::AddressGetter;
 use tracing::{error, info};
 
@@ -28,8 +35,13 @@ pub async fn start(
     abstract_factory: impl provisioner_factory::AbstractFactory,
     runtime_logger_factory: impl runtime_logger::Factory,
     persistence: Persistence,
+    min_log_level: MinLevelHandle,
     args: Args,
 ) {
+    let admin_secret = args
+        .admin_secret_hash()
+        .unwrap_or_else(|error| panic!("{error}"));
+
     let deployment_manager = DeploymentManager::builder()
         .abstract_factory(abstract_factory)
         .runtime_logger_factory(runtime_logger_factory)
@@ -40,9 +52,7 @@ pub async fn start(
         .queue_client(GatewayClient::new(args.gateway_uri))
         .build();
 
-    persistence.cleanup_invalid_states().await.unwrap();
-
-    let runnable_deployments = persistence.get_all_runnable_deployments().await.unwrap();
+    let runnable_deployments = persistence.recover_on_startup().await.unwrap().runnable;
     info!(count = %runnable_deployments.len(), "enqueuing runnable deployments");
     for existing_deployment in runnable_deployments {
         let built = Built {
@@ -58,8 +68,9 @@ pub async fn start(
     let router = handlers::make_router(
         persistence,
         deployment_manager,
+        min_log_level,
         args.proxy_fqdn,
-        args.admin_secret,
+        admin_secret,
         args.auth_uri,
         args.project,
     )
@@ -1,28 +1,58 @@
+use std::time::Duration;
+
 use clap::Parser;
 use shuttle_common::backends::tracing::setup_tracing;
 use shuttle_deployer::{
-    start, start_proxy, AbstractProvisionerFactory, Args, DeployLayer, Persistence,
+    start, start_proxy, AbstractProvisionerFactory, Args, DeployLayer, LogLevel, Persistence,
     RuntimeLoggerFactory,
 };
 use tokio::select;
 use tonic::transport::Endpoint;
-use tracing::trace;
+use tracing::{trace, warn};
 use tracing_subscriber::prelude::*;
 
+/// Environment variable with the least severe level of deployment logs to record, like `warn`
+const MIN_LOG_LEVEL_ENV: &str = "SHUTTLE_DEPLOYER_MIN_LOG_LEVEL";
+
 // The `multi_thread` is needed to prevent a deadlock in shuttle_service::loader::build_crate() which spawns two threads
 // Without this, both threads just don't start up
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
     let args = Args::parse();
 
+    if let Err(error) = args.validate() {
+        eprintln!("invalid arguments: {error}");
+        std::process::exit(1);
+    }
+
     trace!(args = ?args, "parsed args");
 
-    let (persistence, _) = Persistence::new(&args.state).await;
+    let (persistence, _) = if args.is_in_memory_state() {
+        Persistence::new_in_memory().await
+    } else {
+        Persistence::new(&args.state, args.persistence_config()).await
+    };
+    let persistence = match args.service_cache_capacity {
+        Some(capacity) => persistence.with_service_cache(capacity),
+        None => persistence,
+    };
+    let deploy_layer = DeployLayer::new(persistence.clone());
+    let min_log_level_handle = deploy_layer.min_level_handle();
+    let min_log_level = std::env::var(MIN_LOG_LEVEL_ENV)
+        .ok()
+        .map(|level| level.parse::<LogLevel>().map_err(|_| level));
+    if let Some(Ok(level)) = &min_log_level {
+        min_log_level_handle.set(level.clone());
+    }
     setup_tracing(
-        tracing_subscriber::registry().with(DeployLayer::new(persistence.clone())),
+        tracing_subscriber::registry().with(deploy_layer),
         "deployer",
     );
 
+    if let Some(Err(level)) = min_log_level {
+        warn!(value = %level, "ignoring unknown level in {MIN_LOG_LEVEL_ENV}");
+    }
+
     let provisioner_uri = Endpoint::try_from(format!(
         "http://{}:{}",
         args.provisioner_address, args.provisioner_port
@@ -30,12 +60,16 @@ async fn main() {
     .expect("provisioner uri is not valid");
 
     let abstract_factory =
-        AbstractProvisionerFactory::new(provisioner_uri, persistence.clone(), persistence.clone());
+        AbstractProvisionerFactory::new(provisioner_uri, persistence.clone(), persistence.clone())
+            .with_connect_retries(
+                args.provisioner_connect_retries,
+                Duration::from_millis(args.provisioner_connect_backoff_ms),
+            );
 
     let runtime_logger_factory = RuntimeLoggerFactory::new(persistence.get_log_sender());
 
     select! {
         _ = start_proxy(args.proxy_address, args.proxy_fqdn.clone(), persistence.clone()) => {},
-        _ = start(abstract_factory, runtime_logger_factory, persistence, args) => {},
+        _ = start(abstract_factory, runtime_logger_factory, persistence, min_log_level_handle, args) => {},
     }
 }
@@ -0,0 +1,120 @@
+//! Synchronous wrappers around [Persistence] for embedding it in code which is not async, like an admin CLI.
+//!
+//! Each call blocks the current thread on the shared runtime. They panic when called from inside an async context,
+//! like every other [Handle::block_on].
+
+use tokio::runtime::Handle;
+use uuid::Uuid;
+
+use super::{Deployment, Log, Persistence, Result, Service};
+
+/// A [Persistence] which can be used without an async runtime of its own
+#[derive(Clone)]
+pub struct BlockingPersistence {
+    persistence: Persistence,
+    handle: Handle,
+}
+
+impl BlockingPersistence {
+    /// Run the calls of `persistence` on the runtime of `handle`
+    pub fn new(persistence: Persistence, handle: Handle) -> Self {
+        Self {
+            persistence,
+            handle,
+        }
+    }
+
+    pub fn get_deployment_blocking(&self, id: &Uuid) -> Result<Option<Deployment>> {
+        self.handle.block_on(self.persistence.get_deployment(id))
+    }
+
+    pub fn get_deployments_blocking(&self, service_id: &Uuid) -> Result<Vec<Deployment>> {
+        self.handle
+            .block_on(self.persistence.get_deployments(service_id))
+    }
+
+    pub fn get_deployment_logs_blocking(&self, id: &Uuid) -> Result<Vec<Log>> {
+        self.handle
+            .block_on(self.persistence.get_deployment_logs(id))
+    }
+
+    pub fn get_service_by_name_blocking(&self, name: &str) -> Result<Option<Service>> {
+        self.handle
+            .block_on(self.persistence.get_service_by_name(name))
+    }
+
+    pub fn get_all_services_blocking(&self) -> Result<Vec<Service>> {
+        self.handle.block_on(self.persistence.get_all_services())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+    use tokio::runtime::Runtime;
+
+    use super::BlockingPersistence;
+    use crate::persistence::{Deployment, Log, LogLevel, LogOrigin, Persistence, State};
+
+    #[test]
+    fn same_as_async() {
+        let runtime = Runtime::new().unwrap();
+        let (p, _) = runtime.block_on(Persistence::new_in_memory());
+        let blocking = BlockingPersistence::new(p.clone(), runtime.handle().clone());
+
+        let service = runtime
+            .block_on(p.get_or_create_service("blocking-service"))
+            .unwrap();
+        let deployment = Deployment::builder(service.id)
+            .state(State::Running)
+            .build();
+        runtime
+            .block_on(p.insert_deployment(deployment.clone()))
+            .unwrap();
+        runtime
+            .block_on(crate::persistence::insert_log(
+                &p.pool,
+                Log {
+                    id: deployment.id,
+                    timestamp: Utc::now(),
+                    state: State::Running,
+                    level: LogLevel::Info,
+                    file: None,
+                    line: None,
+                    target: "tests::same_as_async".to_string(),
+                    fields: json!({ "message": "hello" }),
+                    trace_id: None,
+                    span_id: None,
+                    origin: LogOrigin::Runtime,
+                    span_fields: None,
+                    duration_ms: None,
+                    repeat_count: 1,
+                },
+            ))
+            .unwrap();
+
+        assert_eq!(
+            blocking.get_deployment_blocking(&deployment.id).unwrap(),
+            Some(deployment.clone())
+        );
+        assert_eq!(
+            blocking.get_deployments_blocking(&service.id).unwrap(),
+            runtime.block_on(p.get_deployments(&service.id)).unwrap()
+        );
+        assert_eq!(
+            blocking
+                .get_deployment_logs_blocking(&deployment.id)
+                .unwrap(),
+            runtime
+                .block_on(p.get_deployment_logs(&deployment.id))
+                .unwrap()
+        );
+        assert_eq!(
+            blocking
+                .get_service_by_name_blocking("blocking-service")
+                .unwrap(),
+            Some(service)
+        );
+    }
+}
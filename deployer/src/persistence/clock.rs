@@ -0,0 +1,28 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+/// Source of the timestamps persistence writes, so that tests can pin the time
+#[derive(Clone)]
+pub struct Clock(Arc<dyn Fn() -> DateTime<Utc> + Send + Sync>);
+
+impl Clock {
+    pub fn new(now: impl Fn() -> DateTime<Utc> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(now))
+    }
+
+    /// A clock which is stuck at `time`
+    pub fn fixed(time: DateTime<Utc>) -> Self {
+        Self::new(move || time)
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        (self.0)()
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new(Utc::now)
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+
+use super::error::{Error, Result};
+
+/// Tuning of the connections to the state db
+#[derive(Clone, Debug)]
+pub struct PersistenceConfig {
+    /// Maximum number of connections kept in the pool
+    pub max_connections: u32,
+
+    /// How long a connection waits on a locked db before failing with `database is locked`
+    pub busy_timeout: Duration,
+
+    /// How long a query waits for a free connection from the pool before failing with
+    /// [super::PersistenceError::PoolTimeout]
+    pub acquire_timeout: Duration,
+
+    /// Journal mode of the db. See the warning in [super::Persistence::new] before moving away from WAL, which also
+    /// needs [PersistenceConfig::i_understand_the_risk]
+    pub journal_mode: SqliteJournalMode,
+
+    /// How hard the db syncs writes to disk. Anything less than full needs [PersistenceConfig::i_understand_the_risk]
+    pub synchronous: SqliteSynchronous,
+
+    /// Allow a journal mode or synchronous mode which is known to break the deployer
+    pub i_understand_the_risk: bool,
+
+    /// Size in bytes of the db pages. This only applies when the db is created, since it cannot change once the tables
+    /// exist in WAL mode
+    pub page_size: u32,
+
+    /// Most bytes of the db each connection reads through a memory map rather than through system calls. Zero turns
+    /// memory mapping off
+    pub mmap_size: u64,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            busy_timeout: Duration::from_secs(5),
+            acquire_timeout: Duration::from_secs(5),
+            journal_mode: SqliteJournalMode::Wal,
+            synchronous: SqliteSynchronous::Full,
+            i_understand_the_risk: false,
+            // SQLite's own defaults, until other values are shown to be faster
+            page_size: 4096,
+            mmap_size: 0,
+        }
+    }
+}
+
+impl PersistenceConfig {
+    /// Refuse the journal and synchronous modes which have broken the deployer before, unless the risk is accepted
+    pub fn validate(&self) -> Result<()> {
+        if self.i_understand_the_risk {
+            return Ok(());
+        }
+
+        if !matches!(self.journal_mode, SqliteJournalMode::Wal) {
+            return Err(Error::RiskyConfig(format!(
+                "journal mode {:?}",
+                self.journal_mode
+            )));
+        }
+
+        if !matches!(
+            self.synchronous,
+            SqliteSynchronous::Full | SqliteSynchronous::Extra
+        ) {
+            return Err(Error::RiskyConfig(format!(
+                "synchronous mode {:?}",
+                self.synchronous
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+
+    use super::PersistenceConfig;
+    use crate::persistence::PersistenceError;
+
+    #[test]
+    fn default_is_allowed() {
+        PersistenceConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn risky_modes_are_refused() {
+        let risky = [
+            PersistenceConfig {
+                journal_mode: SqliteJournalMode::Delete,
+                ..Default::default()
+            },
+            PersistenceConfig {
+                synchronous: SqliteSynchronous::Normal,
+                ..Default::default()
+            },
+            PersistenceConfig {
+                synchronous: SqliteSynchronous::Off,
+                ..Default::default()
+            },
+        ];
+
+        for config in risky {
+            assert!(
+                matches!(config.validate(), Err(PersistenceError::RiskyConfig(_))),
+                "{config:?} should be refused"
+            );
+
+            PersistenceConfig {
+                i_understand_the_risk: true,
+                ..config
+            }
+            .validate()
+            .unwrap();
+        }
+    }
+}
@@ -1,28 +1,102 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::net::SocketAddr;
 
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use sqlx::{sqlite::SqliteRow, FromRow, Row};
 use tracing::error;
 use uuid::Uuid;
 
-use super::state::State;
+use super::{error::parse_address, log::Log, service::Service, state::State};
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Deployment {
     pub id: Uuid,
     pub service_id: Uuid,
     pub state: State,
     pub last_update: DateTime<Utc>,
     pub address: Option<SocketAddr>,
+    /// Why the deployment crashed, if it did
+    pub error_message: Option<String>,
+    /// When the deployment entered the running state, if it is running
+    pub running_since: Option<DateTime<Utc>>,
+}
+
+impl Deployment {
+    /// Start building a new [State::Queued] deployment of the service with `service_id`, with a random id which was
+    /// last updated now
+    pub fn builder(service_id: Uuid) -> DeploymentBuilder {
+        DeploymentBuilder {
+            deployment: Self {
+                id: Uuid::new_v4(),
+                service_id,
+                state: State::Queued,
+                last_update: Utc::now(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            },
+        }
+    }
+}
+
+/// Builder for a [Deployment] from [Deployment::builder]. It can be passed to
+/// [super::Persistence::insert_deployment] without calling [DeploymentBuilder::build].
+pub struct DeploymentBuilder {
+    deployment: Deployment,
+}
+
+impl DeploymentBuilder {
+    pub fn id(mut self, id: Uuid) -> Self {
+        self.deployment.id = id;
+        self
+    }
+
+    pub fn state(mut self, state: State) -> Self {
+        self.deployment.state = state;
+        self
+    }
+
+    pub fn last_update(mut self, last_update: DateTime<Utc>) -> Self {
+        self.deployment.last_update = last_update;
+        self
+    }
+
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.deployment.address = Some(address);
+        self
+    }
+
+    pub fn error_message(mut self, error_message: impl Into<String>) -> Self {
+        self.deployment.error_message = Some(error_message.into());
+        self
+    }
+
+    pub fn running_since(mut self, running_since: DateTime<Utc>) -> Self {
+        self.deployment.running_since = Some(running_since);
+        self
+    }
+
+    pub fn build(self) -> Deployment {
+        self.deployment
+    }
+}
+
+impl From<DeploymentBuilder> for Deployment {
+    fn from(builder: DeploymentBuilder) -> Self {
+        builder.build()
+    }
 }
 
 impl FromRow<'_, SqliteRow> for Deployment {
     fn from_row(row: &SqliteRow) -> Result<Self, sqlx::Error> {
         let address = if let Some(address_str) = row.try_get::<Option<String>, _>("address")? {
-            match SocketAddr::from_str(&address_str) {
+            match parse_address(&address_str) {
                 Ok(address) => Some(address),
-                Err(err) => {
-                    error!(error = %err, "failed to parse address from DB");
+                Err(error) => {
+                    error!(
+                        error = &error as &dyn std::error::Error,
+                        "failed to parse address from DB"
+                    );
                     None
                 }
             }
@@ -36,6 +110,8 @@ impl FromRow<'_, SqliteRow> for Deployment {
             state: row.try_get("state")?,
             last_update: row.try_get("last_update")?,
             address,
+            error_message: row.try_get("error_message")?,
+            running_since: row.try_get("running_since")?,
         })
     }
 }
@@ -57,6 +133,7 @@ pub struct DeploymentState {
     pub state: State,
     pub last_update: DateTime<Utc>,
     pub address: Option<SocketAddr>,
+    pub error_message: Option<String>,
 }
 
 #[derive(sqlx::FromRow, Debug, PartialEq, Eq)]
@@ -65,3 +142,33 @@ pub struct DeploymentRunnable {
     pub service_name: String,
     pub service_id: Uuid,
 }
+
+/// The parts of a [Deployment] needed to list the deployments of a service
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, sqlx::FromRow)]
+pub struct DeploymentSummary {
+    pub id: Uuid,
+    pub state: State,
+    pub last_update: DateTime<Utc>,
+    pub has_address: bool,
+}
+
+/// What [super::Persistence::recover_on_startup] did to the deployments
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Number of deployments whose state was made to match their latest state log
+    pub reconciled: u64,
+
+    /// Number of deployments which were stopped for being left in progress
+    pub reset: u64,
+
+    /// Deployments which should be started again
+    pub runnable: Vec<DeploymentRunnable>,
+}
+
+/// A deployment bundled with its service and all its logs
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DeploymentExport {
+    pub deployment: Deployment,
+    pub service: Service,
+    pub logs: Vec<Log>,
+}
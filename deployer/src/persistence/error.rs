@@ -1,7 +1,171 @@
+use std::net::{AddrParseError, SocketAddr};
+use std::str::FromStr;
+
+use super::State;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    /// Any database error which is not classified by one of the other variants
     #[error("Database error: {0}")]
-    Database(#[from] sqlx::Error),
+    Database(sqlx::Error),
+    #[error("Timed out waiting for a database connection")]
+    PoolTimeout,
+    #[error("Database record could not be found")]
+    NotFound,
+    #[error("Database record already exists: {0}")]
+    UniqueViolation(sqlx::Error),
+    #[error("Lost the connection to the database: {0}")]
+    ConnectionClosed(sqlx::Error),
+    #[error("Deployment could not be found")]
+    DeploymentNotFound,
+    #[error("Deployment {0} already exists")]
+    DeploymentExists(uuid::Uuid),
+    #[error("Service already has the most deployments which have not finished it can have ({0})")]
+    TooManyActiveDeployments(u32),
+    #[error("Deployment was updated since the state being written was read")]
+    UpdateConflict,
+    #[error("Deployment cannot move from the {from} state to the {to} state")]
+    InvalidTransition { from: State, to: State },
+    #[error("Service could not be found")]
+    ServiceNotFound,
+    #[error("A service named '{0}' already exists")]
+    ServiceNameTaken(String),
+    #[error("A service with id '{0}' already exists under another name")]
+    ServiceIdTaken(uuid::Uuid),
+    #[error("'{0}' is not a valid secret key, it should match [A-Za-z_][A-Za-z0-9_]*")]
+    InvalidSecretKey(String),
+    #[error("The secret store cannot expire secrets")]
+    SecretExpiryUnsupported,
+    #[error("State db has migration {applied} applied, which is newer than the latest migration {known} this deployer knows")]
+    SchemaAhead { applied: i64, known: i64 },
+    #[error("Refusing to use {0} with the state db, since it is known to break the deployer")]
+    RiskyConfig(String),
+    #[error("Time range starts after it ends")]
+    InvalidTimeRange,
+}
+
+/// Extended sqlite result codes for a `UNIQUE` and a `PRIMARY KEY` constraint failing
+const SQLITE_CONSTRAINT_UNIQUE: &str = "2067";
+const SQLITE_CONSTRAINT_PRIMARYKEY: &str = "1555";
+
+/// Primary sqlite result codes for the database being busy or locked by another connection
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+impl Error {
+    /// Whether the database was busy or locked by another connection, so that trying again shortly could succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Database(sqlx::Error::Database(error)) => error
+                .code()
+                .and_then(|code| code.parse::<i32>().ok())
+                .map_or(false, |code| {
+                    matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED)
+                }),
+            _ => false,
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(error: sqlx::Error) -> Self {
+        match error {
+            sqlx::Error::PoolTimedOut => Self::PoolTimeout,
+            sqlx::Error::RowNotFound => Self::NotFound,
+            sqlx::Error::Io(_) | sqlx::Error::PoolClosed | sqlx::Error::WorkerCrashed => {
+                Self::ConnectionClosed(error)
+            }
+            sqlx::Error::Database(ref database_error)
+                if matches!(
+                    database_error.code().as_deref(),
+                    Some(SQLITE_CONSTRAINT_UNIQUE | SQLITE_CONSTRAINT_PRIMARYKEY)
+                ) =>
+            {
+                Self::UniqueViolation(error)
+            }
+            error => Self::Database(error),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// An address of a deployment which could not be parsed
+#[derive(thiserror::Error, Debug)]
+#[error("'{address}' is not a valid address: {source}")]
+pub struct AddressParseError {
+    pub address: String,
+    pub source: AddrParseError,
+}
+
+/// Parse the address of a deployment, keeping the offending string when it is invalid
+pub fn parse_address(address: &str) -> std::result::Result<SocketAddr, AddressParseError> {
+    SocketAddr::from_str(address).map_err(|source| AddressParseError {
+        address: address.to_string(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use sqlx::SqlitePool;
+
+    use super::Error;
+
+    #[test]
+    fn classify_sqlx_errors() {
+        assert!(matches!(
+            Error::from(sqlx::Error::RowNotFound),
+            Error::NotFound
+        ));
+        assert!(matches!(
+            Error::from(sqlx::Error::PoolTimedOut),
+            Error::PoolTimeout
+        ));
+        assert!(matches!(
+            Error::from(sqlx::Error::PoolClosed),
+            Error::ConnectionClosed(_)
+        ));
+        assert!(matches!(
+            Error::from(sqlx::Error::WorkerCrashed),
+            Error::ConnectionClosed(_)
+        ));
+        assert!(matches!(
+            Error::from(sqlx::Error::Io(io::ErrorKind::BrokenPipe.into())),
+            Error::ConnectionClosed(_)
+        ));
+        assert!(matches!(
+            Error::from(sqlx::Error::ColumnNotFound("missing".to_string())),
+            Error::Database(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn classify_constraint_errors() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::query("CREATE TABLE things (id INTEGER PRIMARY KEY, name TEXT UNIQUE, size INTEGER CHECK (size > 0))")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let insert = |id: i64, name: &'static str, size: i64| {
+            sqlx::query("INSERT INTO things (id, name, size) VALUES (?, ?, ?)")
+                .bind(id)
+                .bind(name)
+                .bind(size)
+                .execute(&pool)
+        };
+        insert(1, "first", 1).await.unwrap();
+
+        let error = Error::from(insert(2, "first", 1).await.unwrap_err());
+        assert!(matches!(error, Error::UniqueViolation(_)), "{error:?}");
+        assert!(!error.is_transient());
+
+        let error = Error::from(insert(1, "second", 1).await.unwrap_err());
+        assert!(matches!(error, Error::UniqueViolation(_)), "{error:?}");
+
+        let error = Error::from(insert(3, "third", 0).await.unwrap_err());
+        assert!(matches!(error, Error::Database(_)), "{error:?}");
+    }
+}
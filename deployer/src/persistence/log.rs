@@ -1,11 +1,23 @@
-use chrono::{DateTime, Utc};
+use std::{
+    fmt,
+    io::{Read, Write},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{de::Error as _, Deserialize, Serialize};
 use serde_json::{json, Value};
 use shuttle_common::STATE_MESSAGE;
+use sqlx::{sqlite::SqliteRow, FromRow, Row};
+use strum::EnumString;
 use uuid::Uuid;
 
 use super::State;
 
-#[derive(Clone, Debug, Eq, PartialEq, sqlx::FromRow)]
+/// Fields which serialize to more bytes than this are stored gzipped
+const COMPRESS_FIELDS_THRESHOLD: usize = 8 * 1024;
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Log {
     pub id: Uuid,
     pub timestamp: DateTime<Utc>,
@@ -15,9 +27,214 @@ pub struct Log {
     pub line: Option<u32>,
     pub target: String,
     pub fields: serde_json::Value,
+    pub trace_id: Option<String>,
+    pub span_id: Option<String>,
+    pub origin: Origin,
+    /// Fields of the scope which entered the state, only set on state logs
+    #[serde(default)]
+    pub span_fields: Option<serde_json::Value>,
+    /// How long the state lasted, only set on state logs once the state is over
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+    /// How many identical consecutive logs this one stands for after [super::Persistence::compact_logs]
+    #[serde(default = "default_repeat_count")]
+    pub repeat_count: u32,
+}
+
+fn default_repeat_count() -> u32 {
+    1
+}
+
+impl FromRow<'_, SqliteRow> for Log {
+    fn from_row(row: &SqliteRow) -> Result<Self, sqlx::Error> {
+        let fields = if row.try_get("fields_compressed")? {
+            let compressed: Vec<u8> = row.try_get("fields")?;
+
+            decompress_fields(&compressed).map_err(|error| sqlx::Error::ColumnDecode {
+                index: "fields".to_string(),
+                source: Box::new(error),
+            })?
+        } else {
+            row.try_get("fields")?
+        };
+
+        Ok(Self {
+            id: row.try_get("id")?,
+            timestamp: row.try_get("timestamp")?,
+            state: row.try_get("state")?,
+            level: row.try_get("level")?,
+            file: row.try_get("file")?,
+            line: row.try_get("line")?,
+            target: row.try_get("target")?,
+            fields,
+            trace_id: row.try_get("trace_id")?,
+            span_id: row.try_get("span_id")?,
+            origin: row.try_get("origin")?,
+            span_fields: row.try_get("span_fields")?,
+            duration_ms: row
+                .try_get::<Option<i64>, _>("duration_ms")?
+                .map(|duration_ms| duration_ms as u64),
+            repeat_count: row.try_get("repeat_count")?,
+        })
+    }
+}
+
+/// Version of the archived JSON lines from [Log::to_jsonl]. Bump it when the shape of [JsonlLog] changes.
+pub const JSONL_SCHEMA_VERSION: u32 = 2;
+
+/// Shape of an archived log line. These names are kept stable even if [Log] changes, for example:
+///
+/// ```json
+/// {"schema_version":2,"deployment_id":"...","timestamp":"2022-12-01T09:00:00Z","state":"Running","level":"Info",
+///  "file":"main.rs","line":12,"target":"app","fields":{"message":"hi"},"trace_id":null,"span_id":null,
+///  "origin":"Runtime","span_fields":null,"duration_ms":null,"repeat_count":1}
+/// ```
+///
+/// Lines from version 1 have no `origin`, `span_fields`, `duration_ms` or `repeat_count`, and are read as a single log
+/// from the deployer.
+#[derive(Deserialize, Serialize)]
+struct JsonlLog {
+    schema_version: u32,
+    deployment_id: Uuid,
+    timestamp: DateTime<Utc>,
+    state: State,
+    level: Level,
+    file: Option<String>,
+    line: Option<u32>,
+    target: String,
+    fields: Value,
+    trace_id: Option<String>,
+    span_id: Option<String>,
+    #[serde(default)]
+    origin: Origin,
+    #[serde(default)]
+    span_fields: Option<Value>,
+    #[serde(default)]
+    duration_ms: Option<u64>,
+    #[serde(default = "default_repeat_count")]
+    repeat_count: u32,
+}
+
+impl Log {
+    /// Serialize to a single line of JSON for archiving, as opposed to the wire format of [shuttle_common::LogItem]
+    pub fn to_jsonl(&self) -> String {
+        let line = JsonlLog {
+            schema_version: JSONL_SCHEMA_VERSION,
+            deployment_id: self.id,
+            timestamp: self.timestamp,
+            state: self.state,
+            level: self.level.clone(),
+            file: self.file.clone(),
+            line: self.line,
+            target: self.target.clone(),
+            fields: self.fields.clone(),
+            trace_id: self.trace_id.clone(),
+            span_id: self.span_id.clone(),
+            origin: self.origin,
+            span_fields: self.span_fields.clone(),
+            duration_ms: self.duration_ms,
+            repeat_count: self.repeat_count,
+        };
+
+        serde_json::to_string(&line).expect("a log to serialize to JSON")
+    }
+
+    /// Parse a line made by [Log::to_jsonl]. Lines of a newer schema than [JSONL_SCHEMA_VERSION] are rejected, since
+    /// their fields might not mean what this version expects.
+    pub fn from_jsonl(line: &str) -> serde_json::Result<Self> {
+        let line: JsonlLog = serde_json::from_str(line)?;
+
+        if line.schema_version > JSONL_SCHEMA_VERSION {
+            return Err(serde_json::Error::custom(format!(
+                "schema version {} is newer than the supported version {JSONL_SCHEMA_VERSION}",
+                line.schema_version
+            )));
+        }
+
+        Ok(Self {
+            id: line.deployment_id,
+            timestamp: line.timestamp,
+            state: line.state,
+            level: line.level,
+            file: line.file,
+            line: line.line,
+            target: line.target,
+            fields: line.fields,
+            trace_id: line.trace_id,
+            span_id: line.span_id,
+            origin: line.origin,
+            span_fields: line.span_fields,
+            duration_ms: line.duration_ms,
+            repeat_count: line.repeat_count,
+        })
+    }
+}
+
+/// Render logs for reading in a terminal, one line per log in the form `<timestamp> <LEVEL> [<target>] <message>`
+pub fn format_logs_as_text(logs: &[Log]) -> String {
+    let mut text = String::new();
+
+    for log in logs {
+        let message = text_message(&log.fields);
+
+        text.push_str(&format!(
+            "{} {} [{}] {message}\n",
+            log.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+            log.level,
+            log.target,
+        ));
+    }
+
+    text
+}
+
+/// Get the human readable part of `fields`, falling back to the raw JSON when there is no message in them
+fn text_message(fields: &Value) -> String {
+    match fields {
+        Value::String(message) => message.clone(),
+        Value::Object(map) => match map.get("message") {
+            Some(Value::String(message)) => message.clone(),
+            _ => extract_message(fields).unwrap_or_else(|| fields.to_string()),
+        },
+        _ => fields.to_string(),
+    }
+}
+
+/// Get the message logs are searched by. It is stored next to the fields so that it can still be searched once the
+/// fields are compressed.
+pub fn search_message(fields: &Value) -> Option<String> {
+    match fields.get("message")? {
+        Value::Null => None,
+        Value::String(message) => Some(message.clone()),
+        message => Some(message.to_string()),
+    }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, sqlx::Type)]
+/// Get the gzipped JSON of `fields` if they are big enough to be worth compressing
+pub fn compress_fields(fields: &Value) -> Option<Vec<u8>> {
+    let json = serde_json::to_vec(fields).ok()?;
+
+    if json.len() <= COMPRESS_FIELDS_THRESHOLD {
+        return None;
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&json).ok()?;
+
+    encoder.finish().ok()
+}
+
+fn decompress_fields(compressed: &[u8]) -> std::io::Result<Value> {
+    let mut json = Vec::new();
+    GzDecoder::new(compressed).read_to_end(&mut json)?;
+
+    Ok(serde_json::from_slice(&json)?)
+}
+
+#[derive(
+    Clone, Debug, Deserialize, EnumString, Eq, Ord, PartialEq, PartialOrd, Serialize, sqlx::Type,
+)]
+#[strum(ascii_case_insensitive)]
 pub enum Level {
     Trace,
     Debug,
@@ -26,6 +243,48 @@ pub enum Level {
     Error,
 }
 
+/// Where a log came from, so that the output of a service can be told apart from the deployer's own diagnostics
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, sqlx::Type)]
+pub enum Origin {
+    /// Events of the deployer as it builds and runs a deployment
+    Deployer,
+    /// Output of the service being run
+    Runtime,
+}
+
+impl Default for Origin {
+    fn default() -> Self {
+        Self::Deployer
+    }
+}
+
+impl Level {
+    /// How severe this level is, which is stored in the `level_severity` column to filter logs by level ranges
+    pub fn severity(&self) -> i64 {
+        match self {
+            Self::Trace => 0,
+            Self::Debug => 1,
+            Self::Info => 2,
+            Self::Warn => 3,
+            Self::Error => 4,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self {
+            Self::Trace => "TRACE",
+            Self::Debug => "DEBUG",
+            Self::Info => "INFO",
+            Self::Warn => "WARN",
+            Self::Error => "ERROR",
+        };
+
+        f.write_str(level)
+    }
+}
+
 impl From<Log> for Option<shuttle_common::LogItem> {
     fn from(log: Log) -> Self {
         if log.state == State::Building {
@@ -109,3 +368,107 @@ fn extract_message(fields: &Value) -> Option<String> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::{format_logs_as_text, Level, Log, Origin, JSONL_SCHEMA_VERSION};
+    use crate::persistence::State;
+
+    #[test]
+    fn jsonl_round_trip() {
+        let log = Log {
+            id: Uuid::parse_str("0c5e5b2e-4b5f-4e1a-9d4c-3f0d3b6a7e01").unwrap(),
+            timestamp: Utc.with_ymd_and_hms(2022, 12, 1, 9, 0, 0).unwrap(),
+            state: State::Running,
+            level: Level::Warn,
+            file: Some("main.rs".to_string()),
+            line: Some(12),
+            target: "app".to_string(),
+            fields: json!({ "message": "slow request" }),
+            trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".to_string()),
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+
+        let line = log.to_jsonl();
+        assert!(!line.contains('\n'));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&line).unwrap(),
+            json!({
+                "schema_version": JSONL_SCHEMA_VERSION,
+                "deployment_id": "0c5e5b2e-4b5f-4e1a-9d4c-3f0d3b6a7e01",
+                "timestamp": "2022-12-01T09:00:00Z",
+                "state": "Running",
+                "level": "Warn",
+                "file": "main.rs",
+                "line": 12,
+                "target": "app",
+                "fields": { "message": "slow request" },
+                "trace_id": "4bf92f3577b34da6a3ce929d0e0e4736",
+                "span_id": null,
+                "origin": "Deployer",
+                "span_fields": null,
+                "duration_ms": null,
+                "repeat_count": 1,
+            })
+        );
+        assert_eq!(Log::from_jsonl(&line).unwrap(), log);
+
+        let newer = line.replace(
+            &format!("\"schema_version\":{JSONL_SCHEMA_VERSION}"),
+            &format!("\"schema_version\":{}", JSONL_SCHEMA_VERSION + 1),
+        );
+        assert_ne!(newer, line);
+        assert!(Log::from_jsonl(&newer).is_err());
+    }
+
+    #[test]
+    fn text_format() {
+        let log = |seconds, level, target: &str, fields| Log {
+            id: Uuid::new_v4(),
+            timestamp: Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, seconds).unwrap(),
+            state: State::Running,
+            level,
+            file: None,
+            line: None,
+            target: target.to_string(),
+            fields,
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+        let logs = [
+            log(
+                33,
+                Level::Info,
+                "app",
+                json!({ "message": "listening on 8000" }),
+            ),
+            log(
+                34,
+                Level::Debug,
+                "cargo",
+                json!({ "build_line": "Compiling app v0.1.0" }),
+            ),
+            log(35, Level::Warn, "app::db", json!({ "retries": 3 })),
+        ];
+
+        assert_eq!(
+            format_logs_as_text(&logs),
+            "2022-04-25T04:43:33Z INFO [app] listening on 8000\n\
+             2022-04-25T04:43:34Z DEBUG [cargo] Compiling app v0.1.0\n\
+             2022-04-25T04:43:35Z WARN [app::db] {\"retries\":3}\n"
+        );
+        assert_eq!(format_logs_as_text(&[]), "");
+    }
+}
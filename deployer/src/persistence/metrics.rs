@@ -0,0 +1,34 @@
+//! Counters for how the state db is used. These are only recorded when the `metrics` feature is enabled.
+#![cfg_attr(not(feature = "metrics"), allow(dead_code, unused_variables))]
+
+use std::time::Duration;
+
+pub const LOGS_INSERTED: &str = "deployer_persistence_logs_inserted_total";
+pub const DEPLOYMENTS_INSERTED: &str = "deployer_persistence_deployments_inserted_total";
+pub const STATE_UPDATES: &str = "deployer_persistence_state_updates_total";
+pub const QUERY_ERRORS: &str = "deployer_persistence_query_errors_total";
+pub const INSERT_LOG_SECONDS: &str = "deployer_persistence_insert_log_seconds";
+
+pub fn log_inserted(latency: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::increment_counter!(LOGS_INSERTED);
+        ::metrics::histogram!(INSERT_LOG_SECONDS, latency.as_secs_f64());
+    }
+}
+
+pub fn deployment_inserted() {
+    #[cfg(feature = "metrics")]
+    ::metrics::increment_counter!(DEPLOYMENTS_INSERTED);
+}
+
+pub fn state_updated() {
+    #[cfg(feature = "metrics")]
+    ::metrics::increment_counter!(STATE_UPDATES);
+}
+
+/// Count a failed `query`
+pub fn query_error(query: &'static str) {
+    #[cfg(feature = "metrics")]
+    ::metrics::increment_counter!(QUERY_ERRORS, "query" => query);
+}
@@ -1,10 +1,16 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod clock;
+mod config;
 mod deployment;
 mod error;
 mod log;
+mod metrics;
 mod resource;
 mod secret;
 mod service;
 mod state;
+mod subscriber;
 mod user;
 
 use crate::deployment::deploy_layer::{self, LogRecorder, LogType};
@@ -12,38 +18,100 @@ use crate::deployment::ActiveDeploymentsGetter;
 use crate::proxy::AddressGetter;
 use error::{Error, Result};
 
-use std::net::SocketAddr;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
 
-use chrono::Utc;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use crossbeam_channel::RecvTimeoutError;
+use futures::{Stream, TryStreamExt};
+use ring::digest;
 use serde_json::json;
 use shuttle_common::STATE_MESSAGE;
-use sqlx::migrate::{MigrateDatabase, Migrator};
-use sqlx::sqlite::{Sqlite, SqliteConnectOptions, SqliteJournalMode, SqlitePool};
+use sqlx::migrate::Migrator;
+use sqlx::query::Query;
+use sqlx::sqlite::{
+    Sqlite, SqliteArguments, SqliteAutoVacuum, SqliteConnectOptions, SqliteConnection, SqlitePool,
+    SqlitePoolOptions,
+};
+use sqlx::{ConnectOptions, FromRow, Row};
+use strum::IntoEnumIterator;
 use tokio::sync::broadcast::{self, Receiver, Sender};
-use tokio::task::JoinHandle;
-use tracing::{error, info, instrument, trace};
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, field, info, instrument, trace, warn, Span};
 use uuid::Uuid;
 
+pub use self::clock::Clock;
+pub use self::config::PersistenceConfig;
 use self::deployment::DeploymentRunnable;
-pub use self::deployment::{Deployment, DeploymentState};
-pub use self::error::Error as PersistenceError;
-pub use self::log::{Level as LogLevel, Log};
+pub use self::deployment::{
+    Deployment, DeploymentBuilder, DeploymentExport, DeploymentState, DeploymentSummary,
+    RecoveryReport,
+};
+pub use self::error::{parse_address, AddressParseError, Error as PersistenceError};
+pub use self::log::{format_logs_as_text, Level as LogLevel, Log, Origin as LogOrigin};
 pub use self::resource::{Resource, ResourceManager, Type as ResourceType};
-use self::secret::Secret;
-pub use self::secret::{SecretGetter, SecretRecorder};
+use self::secret::{validate_secret_key, Secret, SqliteSecretStore};
+pub use self::secret::{SecretGetter, SecretRecorder, SecretStore, SecretSyncReport};
 pub use self::service::Service;
+use self::service::ServiceCache;
 pub use self::state::State;
+pub use self::subscriber::DeploymentLogSubscriber;
+use self::subscriber::{StateWatchers, SubscriberCounts};
 pub use self::user::User;
 
 pub static MIGRATIONS: Migrator = sqlx::migrate!("./migrations");
 
+/// How often the log drain checks whether it was cancelled while no logs are coming in
+const LOG_DRAIN_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
 #[derive(Clone)]
 pub struct Persistence {
     pool: SqlitePool,
     log_send: crossbeam_channel::Sender<deploy_layer::Log>,
     stream_log_send: Sender<deploy_layer::Log>,
+    stream_lag_markers: bool,
+    secret_store: Arc<dyn SecretStore>,
+    /// Whether [Persistence::with_secret_store] replaced the store in the state database
+    custom_secret_store: bool,
+    subscriber_counts: SubscriberCounts,
+    state_watchers: StateWatchers,
+    clock: Clock,
+    log_drain_cancel: CancellationToken,
+    service_cache: Option<ServiceCache>,
+    max_active_deployments: Option<u32>,
+    /// Keeps an in-memory database alive while the pool has no open connections
+    in_memory_anchor: Option<Arc<std::sync::Mutex<SqliteConnection>>>,
+}
+
+/// Handle to the task moving logs from [Persistence::record] into the database. The task finishes once every
+/// [Persistence] (and log sender) has been dropped.
+pub struct PersistenceHandle(JoinHandle<()>);
+
+impl PersistenceHandle {
+    /// Wait for all the received logs to be stored
+    pub async fn join(self) -> std::result::Result<(), JoinError> {
+        self.0.await
+    }
+
+    /// Stop storing logs, even if some are still queued
+    pub fn abort(self) {
+        self.0.abort();
+    }
+}
+
+/// How big the state database is and how many rows its main tables have, for monitoring its growth
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DatabaseStats {
+    /// Size of the database in bytes, from its page count and page size
+    pub size_bytes: u64,
+    pub deployments: u64,
+    pub logs: u64,
+    pub secrets: u64,
+    pub resources: u64,
+    pub services: u64,
 }
 
 impl Persistence {
@@ -51,16 +119,7 @@ impl Persistence {
     /// function creates all necessary tables and sets up a database connection
     /// pool - new connections should be made by cloning [`Persistence`] rather
     /// than repeatedly calling [`Persistence::new`].
-    pub async fn new(path: &str) -> (Self, JoinHandle<()>) {
-        if !Path::new(path).exists() {
-            Sqlite::create_database(path).await.unwrap();
-        }
-
-        info!(
-            "state db: {}",
-            std::fs::canonicalize(path).unwrap().to_string_lossy()
-        );
-
+    pub async fn new(path: &str, config: PersistenceConfig) -> (Self, PersistenceHandle) {
         // We have found in the past that setting synchronous to anything other than the default (full) breaks the
         // broadcast channel in deployer. The broken symptoms are that the ws socket connections won't get any logs
         // from the broadcast channel and would then close. When users did deploys, this would make it seem like the
@@ -68,23 +127,74 @@ impl Persistence {
         // would be returned to the user.
         //
         // If you want to activate a faster synchronous mode, then also do proper testing to confirm this bug is no
-        // longer present.
+        // longer present. Until then the config refuses it unless the risk is explicitly accepted.
+        config.validate().unwrap_or_else(|error| panic!("{error}"));
+
         let sqlite_options = SqliteConnectOptions::from_str(path)
             .unwrap()
-            .journal_mode(SqliteJournalMode::Wal);
+            .create_if_missing(true)
+            // These have to be set with the other connection pragmas, since neither can change once a new file is put
+            // in WAL mode
+            .page_size(config.page_size)
+            .auto_vacuum(SqliteAutoVacuum::Incremental)
+            .journal_mode(config.journal_mode)
+            .synchronous(config.synchronous)
+            .busy_timeout(config.busy_timeout)
+            .pragma("mmap_size", config.mmap_size.to_string())
+            .foreign_keys(true);
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(config.max_connections)
+            .acquire_timeout(config.acquire_timeout)
+            .connect_with(sqlite_options)
+            .await
+            .unwrap();
 
-        let pool = SqlitePool::connect_with(sqlite_options).await.unwrap();
+        info!(
+            "state db: {}",
+            std::fs::canonicalize(path).unwrap().to_string_lossy()
+        );
 
-        Self::from_pool(pool).await
+        let (persistence, handle) = Self::from_pool(pool).await;
+
+        match persistence.applied_migrations().await {
+            Ok(migrations) => info!(?migrations, "state db migrations applied"),
+            Err(error) => error!(
+                error = &error as &dyn std::error::Error,
+                "failed to get applied state db migrations"
+            ),
+        }
+
+        (persistence, handle)
     }
 
-    #[allow(dead_code)]
-    async fn new_in_memory() -> (Self, JoinHandle<()>) {
-        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
-        Self::from_pool(pool).await
+    /// Creates a storage solution which is only kept in memory and is lost once the [Persistence] is dropped
+    pub async fn new_in_memory() -> (Self, PersistenceHandle) {
+        let sqlite_options = SqliteConnectOptions::from_str("sqlite::memory:")
+            .unwrap()
+            .foreign_keys(true);
+
+        // The db is destroyed when its last connection closes, which the pool does for idle connections. So one
+        // connection outside the pool is held for as long as the persistence lives.
+        let anchor = sqlite_options.connect().await.unwrap();
+        let pool = SqlitePool::connect_with(sqlite_options).await.unwrap();
+
+        let (mut persistence, handle) = Self::from_pool(pool).await;
+        persistence.in_memory_anchor = Some(Arc::new(std::sync::Mutex::new(anchor)));
+
+        (persistence, handle)
     }
 
-    async fn from_pool(pool: SqlitePool) -> (Self, JoinHandle<()>) {
+    async fn from_pool(pool: SqlitePool) -> (Self, PersistenceHandle) {
+        check_schema_not_ahead(&pool)
+            .await
+            .unwrap_or_else(|error| panic!("{error}"));
+
+        // This only takes effect on a new database, since it has to be set before any tables are created
+        sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+            .execute(&pool)
+            .await
+            .unwrap();
         MIGRATIONS.run(&pool).await.unwrap();
 
         let (log_send, log_recv): (crossbeam_channel::Sender<deploy_layer::Log>, _) =
@@ -95,10 +205,23 @@ impl Persistence {
 
         let pool_cloned = pool.clone();
 
+        let log_drain_cancel = CancellationToken::new();
+        let log_drain_cancelled = log_drain_cancel.clone();
+
+        let state_watchers = StateWatchers::default();
+        let state_watchers_cloned = state_watchers.clone();
+
         // The logs are received on a non-async thread.
         // This moves them to an async thread
         let handle = tokio::spawn(async move {
-            while let Ok(log) = log_recv.recv() {
+            // Every received log is stored before checking for cancellation so that none are lost
+            while !log_drain_cancelled.is_cancelled() {
+                let log = match log_recv.recv_timeout(LOG_DRAIN_POLL_INTERVAL) {
+                    Ok(log) => log,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+
                 trace!(?log, "persistence received got log");
                 match log.r#type {
                     LogType::Event => {
@@ -110,36 +233,45 @@ impl Persistence {
                                     "failed to insert event log"
                                 )
                             });
+
+                        // Keep the reason for a crash with the deployment itself
+                        if let Some(error_message) = log
+                            .fields
+                            .get("error")
+                            .and_then(|error| error.as_str())
+                            .filter(|_| log.state == State::Crashed)
+                        {
+                            set_error_message(&pool_cloned, &log.id, error_message)
+                                .await
+                                .unwrap_or_else(|error| {
+                                    error!(
+                                        error = &error as &dyn std::error::Error,
+                                        "failed to record crash reason"
+                                    );
+                                    0
+                                });
+                        }
                     }
-                    LogType::State => {
-                        insert_log(
-                            &pool_cloned,
-                            Log {
-                                id: log.id,
-                                timestamp: log.timestamp,
-                                state: log.state,
-                                level: log.level.clone(),
-                                file: log.file.clone(),
-                                line: log.line,
-                                target: String::new(),
-                                fields: json!(STATE_MESSAGE),
-                            },
-                        )
-                        .await
-                        .unwrap_or_else(|error| {
-                            error!(
-                                error = &error as &dyn std::error::Error,
-                                "failed to insert state log"
-                            )
-                        });
-                        update_deployment(&pool_cloned, log.clone())
+                    LogType::State => match store_state_log(&pool_cloned, log.clone()).await {
+                        Ok(0) => warn!(id = %log.id, "no deployment to update the state of"),
+                        Ok(_) => state_watchers_cloned.send(&log.id, log.state),
+                        Err(error) => error!(
+                            error = &error as &dyn std::error::Error,
+                            "failed to store state log"
+                        ),
+                    },
+                    // Only annotates the state log, so there is nothing new to stream
+                    LogType::StateEnd => {
+                        set_state_duration(&pool_cloned, &log)
                             .await
                             .unwrap_or_else(|error| {
                                 error!(
                                     error = &error as &dyn std::error::Error,
-                                    "failed to update deployment state"
-                                )
+                                    "failed to store state duration"
+                                );
+                                0
                             });
+                        continue;
                     }
                 };
 
@@ -160,659 +292,5456 @@ impl Persistence {
         });
 
         let persistence = Self {
+            secret_store: Arc::new(SqliteSecretStore::new(pool.clone())),
+            custom_secret_store: false,
             pool,
             log_send,
             stream_log_send,
+            stream_lag_markers: true,
+            subscriber_counts: Default::default(),
+            state_watchers,
+            clock: Default::default(),
+            log_drain_cancel,
+            service_cache: None,
+            max_active_deployments: None,
+            in_memory_anchor: None,
         };
 
-        (persistence, handle)
+        (persistence, PersistenceHandle(handle))
     }
 
-    pub async fn insert_deployment(&self, deployment: impl Into<Deployment>) -> Result<()> {
-        let deployment = deployment.into();
+    /// Stop storing logs, even if log senders are still held elsewhere, and wait for the logs which were already
+    /// received to be stored. Logs recorded after this are dropped.
+    pub async fn shutdown(&self, handle: PersistenceHandle) -> std::result::Result<(), JoinError> {
+        self.log_drain_cancel.cancel();
 
-        sqlx::query(
-            "INSERT INTO deployments (id, service_id, state, last_update, address) VALUES (?, ?, ?, ?, ?)",
-        )
-        .bind(deployment.id)
-        .bind(deployment.service_id)
-        .bind(deployment.state)
-        .bind(deployment.last_update)
-        .bind(deployment.address.map(|socket| socket.to_string()))
-        .execute(&self.pool)
-        .await
-        .map(|_| ())
-        .map_err(Error::from)
+        handle.join().await
     }
 
-    pub async fn get_deployment(&self, id: &Uuid) -> Result<Option<Deployment>> {
-        get_deployment(&self.pool, id).await
+    /// Get the version and description of all the migrations which have been applied to the database
+    pub async fn applied_migrations(&self) -> Result<Vec<(i64, String)>> {
+        applied_migrations(&self.pool).await
     }
 
-    pub async fn get_deployments(&self, service_id: &Uuid) -> Result<Vec<Deployment>> {
-        sqlx::query_as("SELECT * FROM deployments WHERE service_id = ?")
-            .bind(service_id)
-            .fetch_all(&self.pool)
+    /// Give up to `pages` free pages back to the file system. All the free pages are given back when `pages` is 0.
+    #[instrument(skip(self))]
+    pub async fn incremental_vacuum(&self, pages: u32) -> Result<()> {
+        sqlx::query(&format!("PRAGMA incremental_vacuum({pages})"))
+            .execute(&self.pool)
             .await
+            .map(|_| ())
             .map_err(Error::from)
     }
 
-    pub async fn get_active_deployment(&self, service_id: &Uuid) -> Result<Option<Deployment>> {
-        sqlx::query_as("SELECT * FROM deployments WHERE service_id = ? AND state = ?")
-            .bind(service_id)
-            .bind(State::Running)
-            .fetch_optional(&self.pool)
-            .await
-            .map_err(Error::from)
+    /// Get the size of the database and the row counts of its main tables
+    #[instrument(skip(self))]
+    pub async fn database_size(&self) -> Result<DatabaseStats> {
+        let (page_count,): (i64,) = sqlx::query_as("PRAGMA page_count")
+            .fetch_one(&self.pool)
+            .await?;
+        let (page_size,): (i64,) = sqlx::query_as("PRAGMA page_size")
+            .fetch_one(&self.pool)
+            .await?;
+        let (deployments, logs, secrets, resources, services): (i64, i64, i64, i64, i64) =
+            sqlx::query_as(
+                r#"SELECT
+                    (SELECT COUNT(*) FROM deployments),
+                    (SELECT COUNT(*) FROM logs),
+                    (SELECT COUNT(*) FROM secrets),
+                    (SELECT COUNT(*) FROM resources),
+                    (SELECT COUNT(*) FROM services)"#,
+            )
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(DatabaseStats {
+            size_bytes: (page_count * page_size) as u64,
+            deployments: deployments as u64,
+            logs: logs as u64,
+            secrets: secrets as u64,
+            resources: resources as u64,
+            services: services as u64,
+        })
     }
 
-    // Clean up all invalid states inside persistence
-    pub async fn cleanup_invalid_states(&self) -> Result<()> {
-        sqlx::query("UPDATE deployments SET state = ? WHERE state IN(?, ?, ?, ?)")
-            .bind(State::Stopped)
-            .bind(State::Queued)
-            .bind(State::Built)
-            .bind(State::Building)
-            .bind(State::Loading)
-            .execute(&self.pool)
-            .await?;
+    /// Run [Persistence::incremental_vacuum] for `pages` on every tick of `period`
+    pub fn spawn_incremental_vacuum(
+        &self,
+        period: std::time::Duration,
+        pages: u32,
+    ) -> JoinHandle<()> {
+        let persistence = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+
+            loop {
+                interval.tick().await;
+
+                if let Err(error) = persistence.incremental_vacuum(pages).await {
+                    error!(
+                        error = &error as &dyn std::error::Error,
+                        "failed to run incremental vacuum"
+                    );
+                }
+            }
+        })
+    }
+
+    #[instrument(skip_all, fields(deployment_id = field::Empty, service_id = field::Empty))]
+    pub async fn insert_deployment(&self, deployment: impl Into<Deployment>) -> Result<()> {
+        let deployment = deployment.into();
+        let span = Span::current();
+        span.record("deployment_id", &field::display(deployment.id));
+        span.record("service_id", &field::display(deployment.service_id));
+
+        let limit = match self.max_active_deployments {
+            Some(limit) if !deployment.state.is_terminal() => limit,
+            _ => return insert_deployment(&self.pool, deployment).await,
+        };
+
+        // Counting in the same transaction as the insert stops concurrent inserts from going over the limit together
+        let mut transaction = self.pool.begin().await?;
+
+        let unfinished_states = states_where(|state| !state.is_terminal());
+        let sql = format!(
+            "SELECT COUNT(*) FROM deployments WHERE service_id = ? AND state IN ({})",
+            placeholders(unfinished_states.len())
+        );
+        let mut query = sqlx::query_as(&sql).bind(deployment.service_id);
+        for state in unfinished_states {
+            query = query.bind(state);
+        }
+        let (unfinished,): (i64,) = query.fetch_one(&mut transaction).await?;
+
+        if unfinished >= i64::from(limit) {
+            return Err(Error::TooManyActiveDeployments(limit));
+        }
+
+        insert_deployment(&mut transaction, deployment).await?;
+        transaction.commit().await?;
 
         Ok(())
     }
 
-    pub async fn get_or_create_service(&self, name: &str) -> Result<Service> {
-        if let Some(service) = self.get_service_by_name(name).await? {
-            Ok(service)
-        } else {
-            let service = Service {
-                id: Uuid::new_v4(),
-                name: name.to_string(),
-            };
+    /// Update the state of a deployment, returning the number of deployments updated. Moving to a state which cannot
+    /// follow the current state is rejected with [Error::InvalidTransition].
+    pub async fn update_deployment_state(&self, state: impl Into<DeploymentState>) -> Result<u64> {
+        let state = state.into();
+        let (id, to) = (state.id, state.state);
+        let predecessors = states_where(|from| from.can_transition_to(to));
+
+        match update_deployment(&self.pool, state, None, Some(&predecessors)).await? {
+            0 => {
+                let current = self
+                    .get_deployment(&id)
+                    .await?
+                    .ok_or(Error::DeploymentNotFound)?;
+
+                Err(Error::InvalidTransition {
+                    from: current.state,
+                    to,
+                })
+            }
+            updated => Ok(updated),
+        }
+    }
 
-            sqlx::query("INSERT INTO services (id, name) VALUES (?, ?)")
-                .bind(service.id)
-                .bind(&service.name)
-                .execute(&self.pool)
-                .await?;
+    /// Update the state of a deployment only if it was not updated since `expected_last_update`. This stops an update
+    /// based on stale data from clobbering a newer one.
+    pub async fn update_deployment_state_if_unchanged(
+        &self,
+        state: impl Into<DeploymentState>,
+        expected_last_update: DateTime<Utc>,
+    ) -> Result<()> {
+        let state = state.into();
+        let (id, to) = (state.id, state.state);
+        let predecessors = states_where(|from| from.can_transition_to(to));
+
+        match update_deployment(
+            &self.pool,
+            state,
+            Some(expected_last_update),
+            Some(&predecessors),
+        )
+        .await?
+        {
+            0 => {
+                let current = self
+                    .get_deployment(&id)
+                    .await?
+                    .ok_or(Error::DeploymentNotFound)?;
+
+                if current.last_update != expected_last_update {
+                    return Err(Error::UpdateConflict);
+                }
 
-            Ok(service)
+                Err(Error::InvalidTransition {
+                    from: current.state,
+                    to,
+                })
+            }
+            _ => Ok(()),
         }
     }
 
-    pub async fn get_service_by_name(&self, name: &str) -> Result<Option<Service>> {
-        sqlx::query_as("SELECT * FROM services WHERE name = ?")
-            .bind(name)
-            .fetch_optional(&self.pool)
-            .await
-            .map_err(Error::from)
+    pub async fn get_deployment(&self, id: &Uuid) -> Result<Option<Deployment>> {
+        get_deployment(&self.pool, id).await
     }
 
-    pub async fn delete_service(&self, id: &Uuid) -> Result<()> {
-        sqlx::query("DELETE FROM services WHERE id = ?")
-            .bind(id)
-            .execute(&self.pool)
-            .await
-            .map(|_| ())
-            .map_err(Error::from)
-    }
+    /// Get a deployment along with the service it belongs to
+    #[instrument(skip(self))]
+    pub async fn get_deployment_with_service(
+        &self,
+        id: &Uuid,
+    ) -> Result<Option<(Deployment, Service)>> {
+        let row = sqlx::query(
+            "SELECT d.*, s.name AS service_name FROM deployments AS d JOIN services AS s ON d.service_id = s.id WHERE d.id = ?",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
 
-    pub async fn get_all_services(&self) -> Result<Vec<Service>> {
-        sqlx::query_as("SELECT * FROM services")
-            .fetch_all(&self.pool)
-            .await
-            .map_err(Error::from)
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let deployment = Deployment::from_row(&row)?;
+        let service = Service {
+            id: deployment.service_id,
+            name: row.try_get("service_name")?,
+        };
+
+        Ok(Some((deployment, service)))
     }
 
-    pub async fn get_all_runnable_deployments(&self) -> Result<Vec<DeploymentRunnable>> {
+    /// Get the deployments which crashed after `since`, oldest first. The last `last_update` returned can be used as
+    /// `since` for the next poll.
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn get_crashed_deployments_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<Deployment>> {
         sqlx::query_as(
-            r#"SELECT d.id, service_id, s.name AS service_name
-                FROM deployments AS d
-                JOIN services AS s ON s.id = d.service_id
-                WHERE state = ?
-                ORDER BY last_update"#,
+            "SELECT * FROM deployments WHERE state = ? AND last_update > ? ORDER BY last_update",
         )
-        .bind(State::Running)
+        .bind(State::Crashed)
+        .bind(since)
         .fetch_all(&self.pool)
         .await
+        .map(record_rows)
         .map_err(Error::from)
     }
 
-    pub(crate) async fn get_deployment_logs(&self, id: &Uuid) -> Result<Vec<Log>> {
-        // TODO: stress this a bit
-        get_deployment_logs(&self.pool, id).await
-    }
+    /// Get the deployments which will never run again but still have an address, like when a crash skipped clearing it
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn get_deployments_with_stale_address(&self) -> Result<Vec<Deployment>> {
+        let terminal_states = states_where(State::is_terminal);
+        let sql = format!(
+            "SELECT * FROM deployments WHERE address IS NOT NULL AND state IN ({}) ORDER BY last_update",
+            placeholders(terminal_states.len())
+        );
 
-    pub fn get_log_subscriber(&self) -> Receiver<deploy_layer::Log> {
-        self.stream_log_send.subscribe()
-    }
+        let mut query = sqlx::query_as(&sql);
+        for state in terminal_states {
+            query = query.bind(state);
+        }
 
-    pub fn get_log_sender(&self) -> crossbeam_channel::Sender<deploy_layer::Log> {
-        self.log_send.clone()
+        query
+            .fetch_all(&self.pool)
+            .await
+            .map(record_rows)
+            .map_err(Error::from)
     }
-}
 
-async fn update_deployment(pool: &SqlitePool, state: impl Into<DeploymentState>) -> Result<()> {
-    let state = state.into();
+    /// Clear the addresses found by [Persistence::get_deployments_with_stale_address] so that the proxy stops using
+    /// them. Returns the number of deployments cleared.
+    #[instrument(skip(self))]
+    pub async fn clear_stale_addresses(&self) -> Result<u64> {
+        let terminal_states = states_where(State::is_terminal);
+        let sql = format!(
+            "UPDATE deployments SET address = NULL WHERE address IS NOT NULL AND state IN ({})",
+            placeholders(terminal_states.len())
+        );
 
-    // TODO: Handle moving to 'active_deployments' table for State::Running.
+        let mut query = sqlx::query(&sql);
+        for state in terminal_states {
+            query = query.bind(state);
+        }
 
-    sqlx::query("UPDATE deployments SET state = ?, last_update = ?, address = ? WHERE id = ?")
-        .bind(state.state)
-        .bind(state.last_update)
-        .bind(state.address.map(|socket| socket.to_string()))
-        .bind(state.id)
-        .execute(pool)
-        .await
-        .map(|_| ())
-        .map_err(Error::from)
-}
+        Ok(query.execute(&self.pool).await?.rows_affected())
+    }
 
-async fn get_deployment(pool: &SqlitePool, id: &Uuid) -> Result<Option<Deployment>> {
-    sqlx::query_as("SELECT * FROM deployments WHERE id = ?")
+    /// Move every running deployment of a service to [State::Stopped] and clear their addresses, like when tearing down
+    /// a project. This only changes the state; it does not signal the running processes like killing a deployment
+    /// does. Returns the number of deployments stopped.
+    #[instrument(skip(self))]
+    pub async fn stop_all_deployments(&self, service_id: &Uuid) -> Result<u64> {
+        let result = sqlx::query(
+            "UPDATE deployments SET state = ?, address = NULL, last_update = ? WHERE service_id = ? AND state = ?",
+        )
+        .bind(State::Stopped)
+        .bind(self.clock.now())
+        .bind(service_id)
+        .bind(State::Running)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Put a non-sensitive label, like a git SHA or CI run id, on a deployment. An existing label with the same key is
+    /// replaced.
+    #[instrument(skip(self))]
+    pub async fn set_deployment_label(&self, id: &Uuid, key: &str, value: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO deployment_labels (deployment_id, key, value) VALUES (?, ?, ?)",
+        )
         .bind(id)
-        .fetch_optional(pool)
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
         .await
+        .map(|_| ())
         .map_err(Error::from)
-}
+    }
 
-async fn insert_log(pool: &SqlitePool, log: impl Into<Log>) -> Result<()> {
-    let log = log.into();
+    #[instrument(skip(self))]
+    pub async fn get_deployment_labels(&self, id: &Uuid) -> Result<BTreeMap<String, String>> {
+        sqlx::query_as("SELECT key, value FROM deployment_labels WHERE deployment_id = ?")
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await
+            .map(|labels: Vec<(String, String)>| labels.into_iter().collect())
+            .map_err(Error::from)
+    }
 
-    sqlx::query("INSERT INTO logs (id, timestamp, state, level, file, line, target, fields) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
-        .bind(log.id)
-        .bind(log.timestamp)
-        .bind(log.state)
-        .bind(log.level)
-        .bind(log.file)
-        .bind(log.line)
-        .bind(log.target)
-        .bind(log.fields)
-        .execute(pool)
+    /// Get the deployments which have the label `key` set to `value`, oldest first
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn find_deployments_by_label(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<Deployment>> {
+        sqlx::query_as(
+            r#"SELECT d.* FROM deployments AS d
+                JOIN deployment_labels AS l ON l.deployment_id = d.id
+                WHERE l.key = ? AND l.value = ?
+                ORDER BY d.last_update"#,
+        )
+        .bind(key)
+        .bind(value)
+        .fetch_all(&self.pool)
         .await
-        .map(|_| ())
+        .map(record_rows)
         .map_err(Error::from)
-}
+    }
 
-async fn get_deployment_logs(pool: &SqlitePool, id: &Uuid) -> Result<Vec<Log>> {
-    sqlx::query_as("SELECT * FROM logs WHERE id = ? ORDER BY timestamp")
+    /// Make the state of a deployment match its latest state log, for when the deployer died between logging a state
+    /// change and storing it on the deployment. Only a log newer than the last update of the deployment is applied, since
+    /// changes like [Persistence::stop_all_deployments] are stored without a state log. Returns the state the deployment
+    /// is in afterwards.
+    #[instrument(skip(self))]
+    pub async fn reconcile_deployment_state(&self, id: &Uuid) -> Result<State> {
+        let deployment = self
+            .get_deployment(id)
+            .await?
+            .ok_or(Error::DeploymentNotFound)?;
+
+        let last_state: Option<(State, DateTime<Utc>)> = sqlx::query_as(
+            "SELECT state, timestamp FROM logs WHERE id = ? AND fields = ? AND timestamp > ? ORDER BY timestamp DESC LIMIT 1",
+        )
         .bind(id)
-        .fetch_all(pool)
-        .await
-        .map_err(Error::from)
-}
+        .bind(json!(STATE_MESSAGE))
+        .bind(deployment.last_update)
+        .fetch_optional(&self.pool)
+        .await?;
 
-impl LogRecorder for Persistence {
-    fn record(&self, log: deploy_layer::Log) {
-        self.log_send
-            .send(log)
-            .expect("failed to move log to async thread");
+        match last_state {
+            Some((state, timestamp)) if state != deployment.state => {
+                info!(from = %deployment.state, to = %state, "reconciling deployment state with its logs");
+
+                sqlx::query("UPDATE deployments SET state = ?, last_update = ?, running_since = CASE WHEN ? THEN COALESCE(running_since, ?) ELSE NULL END WHERE id = ?")
+                    .bind(state)
+                    .bind(timestamp)
+                    .bind(state == State::Running)
+                    .bind(timestamp)
+                    .bind(id)
+                    .execute(&self.pool)
+                    .await?;
+
+                Ok(state)
+            }
+            _ => Ok(deployment.state),
+        }
     }
-}
 
-#[async_trait::async_trait]
-impl ResourceManager for Persistence {
-    type Err = Error;
+    /// Get how long a deployment has been running for. This is `None` when it is not running.
+    pub async fn deployment_uptime(&self, id: &Uuid) -> Result<Option<Duration>> {
+        let running_since = self
+            .get_deployment(id)
+            .await?
+            .ok_or(Error::DeploymentNotFound)?
+            .running_since;
 
-    async fn insert_resource(&self, resource: &Resource) -> Result<()> {
-        sqlx::query("INSERT OR REPLACE INTO resources (service_id, type, data) VALUES (?, ?, ?)")
-            .bind(resource.service_id)
-            .bind(resource.r#type)
-            .bind(&resource.data)
-            .execute(&self.pool)
-            .await
-            .map(|_| ())
-            .map_err(Error::from)
+        Ok(running_since.map(|running_since| self.clock.now() - running_since))
     }
 
-    async fn get_resources(&self, service_id: &Uuid) -> Result<Vec<Resource>> {
-        sqlx::query_as(r#"SELECT * FROM resources WHERE service_id = ?"#)
+    /// Get a summary of each deployment of a service, which only reads the columns needed to list them
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn get_deployment_summaries(
+        &self,
+        service_id: &Uuid,
+    ) -> Result<Vec<DeploymentSummary>> {
+        sqlx::query_as(
+            "SELECT id, state, last_update, address IS NOT NULL AS has_address FROM deployments WHERE service_id = ? ORDER BY last_update",
+        )
+        .bind(service_id)
+        .fetch_all(&self.pool)
+        .await
+        .map(record_rows)
+        .map_err(Error::from)
+    }
+
+    #[instrument(skip_all, fields(service_id = %service_id, rows = field::Empty))]
+    pub async fn get_deployments(&self, service_id: &Uuid) -> Result<Vec<Deployment>> {
+        sqlx::query_as("SELECT * FROM deployments WHERE service_id = ?")
             .bind(service_id)
             .fetch_all(&self.pool)
             .await
+            .map(record_rows)
             .map_err(Error::from)
     }
-}
 
-#[async_trait::async_trait]
-impl SecretRecorder for Persistence {
-    type Err = Error;
-
-    async fn insert_secret(&self, service_id: &Uuid, key: &str, value: &str) -> Result<()> {
-        sqlx::query(
-            "INSERT OR REPLACE INTO secrets (service_id, key, value, last_update) VALUES (?, ?, ?, ?)",
+    /// Get a page of the deployments of every service, with the most recently updated first
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn get_all_deployments_paged(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<Deployment>> {
+        sqlx::query_as(
+            "SELECT * FROM deployments ORDER BY last_update DESC, id DESC LIMIT ? OFFSET ?",
         )
-        .bind(service_id)
-        .bind(key)
-        .bind(value)
-        .bind(Utc::now())
-        .execute(&self.pool)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
         .await
-        .map(|_| ())
+        .map(record_rows)
         .map_err(Error::from)
     }
-}
 
-#[async_trait::async_trait]
-impl SecretGetter for Persistence {
-    type Err = Error;
+    /// Get the page of deployments of every service which follows the deployment with `after` as its `last_update` and
+    /// `id`, or the first page when it is `None`. Unlike [Persistence::get_all_deployments_paged], deployments
+    /// inserted while paging do not shift the pages around.
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn get_all_deployments_after(
+        &self,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: u32,
+    ) -> Result<Vec<Deployment>> {
+        let query = match after {
+            Some((last_update, id)) => sqlx::query_as(
+                r#"SELECT * FROM deployments
+                    WHERE last_update < ? OR (last_update = ? AND id < ?)
+                    ORDER BY last_update DESC, id DESC
+                    LIMIT ?"#,
+            )
+            .bind(last_update)
+            .bind(last_update)
+            .bind(id),
+            None => sqlx::query_as(
+                "SELECT * FROM deployments ORDER BY last_update DESC, id DESC LIMIT ?",
+            ),
+        };
 
-    async fn get_secrets(&self, service_id: &Uuid) -> Result<Vec<Secret>> {
-        sqlx::query_as("SELECT * FROM secrets WHERE service_id = ? ORDER BY key")
+        query
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+            .map(record_rows)
+            .map_err(Error::from)
+    }
+
+    /// Get only the ids of the deployments of a service, with the most recently updated first
+    #[instrument(skip_all, fields(service_id = %service_id, rows = field::Empty))]
+    pub async fn get_deployment_ids(&self, service_id: &Uuid) -> Result<Vec<Uuid>> {
+        sqlx::query_as("SELECT id FROM deployments WHERE service_id = ? ORDER BY last_update DESC")
             .bind(service_id)
             .fetch_all(&self.pool)
             .await
+            .map(|ids: Vec<(Uuid,)>| record_rows(ids.into_iter().map(|(id,)| id).collect()))
             .map_err(Error::from)
     }
-}
 
-#[async_trait::async_trait]
-impl AddressGetter for Persistence {
+    /// Get a deployment only if its service is owned by the user
     #[instrument(skip(self))]
-    async fn get_address_for_service(
+    pub async fn get_deployment_for_user(
         &self,
-        service_name: &str,
-    ) -> crate::handlers::Result<Option<std::net::SocketAddr>> {
-        let address_str = sqlx::query_as::<_, (String,)>(
-            r#"SELECT d.address
-                FROM deployments AS d
-                JOIN services AS s ON d.service_id = s.id
-                WHERE s.name = ? AND d.state = ?
-                ORDER BY d.last_update"#,
+        id: &Uuid,
+        user_id: &Uuid,
+    ) -> Result<Option<Deployment>> {
+        sqlx::query_as(
+            "SELECT d.* FROM deployments AS d JOIN services AS s ON d.service_id = s.id WHERE d.id = ? AND s.user_id = ?",
         )
-        .bind(service_name)
-        .bind(State::Running)
+        .bind(id)
+        .bind(user_id)
         .fetch_optional(&self.pool)
         .await
         .map_err(Error::from)
-        .map_err(crate::handlers::Error::Persistence)?;
-
-        if let Some((address_str,)) = address_str {
-            SocketAddr::from_str(&address_str).map(Some).map_err(|err| {
-                crate::handlers::Error::Convert {
-                    from: "String".to_string(),
-                    to: "SocketAddr".to_string(),
-                    message: err.to_string(),
-                }
-            })
-        } else {
-            Ok(None)
-        }
     }
-}
 
-#[async_trait::async_trait]
-impl ActiveDeploymentsGetter for Persistence {
-    type Err = Error;
-
-    async fn get_active_deployments(
+    /// Get the deployments of a service only if it is owned by the user
+    #[instrument(skip(self))]
+    pub async fn get_deployments_for_user(
         &self,
         service_id: &Uuid,
-    ) -> std::result::Result<Vec<Uuid>, Self::Err> {
-        let ids: Vec<_> = sqlx::query_as::<_, Deployment>(
-            "SELECT * FROM deployments WHERE service_id = ? AND state = ?",
+        user_id: &Uuid,
+    ) -> Result<Vec<Deployment>> {
+        sqlx::query_as(
+            "SELECT d.* FROM deployments AS d JOIN services AS s ON d.service_id = s.id WHERE d.service_id = ? AND s.user_id = ?",
         )
         .bind(service_id)
-        .bind(State::Running)
+        .bind(user_id)
         .fetch_all(&self.pool)
         .await
-        .map_err(Error::from)?
-        .into_iter()
-        .map(|deployment| deployment.id)
-        .collect();
+        .map_err(Error::from)
+    }
 
-        Ok(ids)
+    #[instrument(skip_all, fields(service_id = %service_id))]
+    pub async fn get_active_deployment(&self, service_id: &Uuid) -> Result<Option<Deployment>> {
+        sqlx::query_as("SELECT * FROM deployments WHERE service_id = ? AND state = ?")
+            .bind(service_id)
+            .bind(State::Running)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(Error::from)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::net::{Ipv4Addr, SocketAddr};
+    /// Mark a deployment as the production one of its service, which is kept apart from whichever deployment is
+    /// running. Any other promoted deployment of the service stops being promoted.
+    #[instrument(skip(self))]
+    pub async fn promote_deployment(&self, id: &Uuid) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
 
-    use chrono::{TimeZone, Utc};
-    use rand::Rng;
-    use serde_json::json;
+        let service_id: Option<(Uuid,)> =
+            sqlx::query_as("SELECT service_id FROM deployments WHERE id = ?")
+                .bind(id)
+                .fetch_optional(&mut transaction)
+                .await?;
+        let service_id = match service_id {
+            Some((service_id,)) => service_id,
+            None => return Err(Error::DeploymentNotFound),
+        };
 
-    use super::*;
-    use crate::persistence::{
-        deployment::{Deployment, DeploymentRunnable, DeploymentState},
-        log::{Level, Log},
-        state::State,
-    };
+        sqlx::query(
+            "UPDATE deployments SET is_promoted = FALSE WHERE service_id = ? AND is_promoted",
+        )
+        .bind(service_id)
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query("UPDATE deployments SET is_promoted = TRUE WHERE id = ?")
+            .bind(id)
+            .execute(&mut transaction)
+            .await?;
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn deployment_updates() {
-        let (p, _) = Persistence::new_in_memory().await;
-        let service_id = add_service(&p.pool).await.unwrap();
+        transaction.commit().await?;
 
-        let id = Uuid::new_v4();
-        let deployment = Deployment {
-            id,
-            service_id,
-            state: State::Queued,
-            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap(),
-            address: None,
-        };
+        Ok(())
+    }
 
-        p.insert_deployment(deployment.clone()).await.unwrap();
-        assert_eq!(p.get_deployment(&id).await.unwrap().unwrap(), deployment);
+    /// Get the deployment of a service which was promoted last, as opposed to its active one
+    pub async fn get_promoted_deployment(&self, service_id: &Uuid) -> Result<Option<Deployment>> {
+        sqlx::query_as("SELECT * FROM deployments WHERE service_id = ? AND is_promoted")
+            .bind(service_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(Error::from)
+    }
 
-        update_deployment(
-            &p.pool,
-            DeploymentState {
-                id,
-                state: State::Built,
-                last_update: Utc::now(),
-                address: None,
-            },
+    // Clean up all invalid states inside persistence
+    #[instrument(skip(self))]
+    pub async fn cleanup_invalid_states(&self) -> Result<()> {
+        cleanup_invalid_states_query(&cleanup_invalid_states_sql(), self.clock.now())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Bring the deployments back to a consistent state after the deployer was stopped: every deployment with a state
+    /// log newer than its stored state is reconciled, then the deployments left in progress are reset.
+    #[instrument(skip(self))]
+    pub async fn recover_on_startup(&self) -> Result<RecoveryReport> {
+        // Reconciling has to happen first, since it could move a deployment back to an in progress state
+        let mismatched: Vec<(Uuid,)> = sqlx::query_as(
+            r#"SELECT id FROM deployments AS d
+                WHERE state != (
+                    SELECT state FROM logs
+                    WHERE id = d.id AND fields = ? AND timestamp > d.last_update
+                    ORDER BY timestamp DESC
+                    LIMIT 1
+                )"#,
         )
-        .await
-        .unwrap();
-        let update = p.get_deployment(&id).await.unwrap().unwrap();
-        assert_eq!(update.state, State::Built);
-        assert_ne!(
-            update.last_update,
-            Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap()
+        .bind(json!(STATE_MESSAGE))
+        .fetch_all(&self.pool)
+        .await?;
+
+        for (id,) in mismatched.iter() {
+            self.reconcile_deployment_state(id).await?;
+        }
+
+        // The reset bumps `last_update` past the state logs so that the next recovery does not reconcile it again
+        let reset = self.cleanup_invalid_states_dry_run().await?;
+        self.cleanup_invalid_states().await?;
+
+        let report = RecoveryReport {
+            reconciled: mismatched.len() as u64,
+            reset: reset.len() as u64,
+            runnable: self.get_all_runnable_deployments().await?,
+        };
+        info!(
+            reconciled = report.reconciled,
+            reset = report.reset,
+            runnable = report.runnable.len(),
+            "recovered deployments on startup"
         );
+
+        Ok(report)
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn deployment_active() {
-        let (p, _) = Persistence::new_in_memory().await;
+    /// Get the deployments [Persistence::cleanup_invalid_states] would reset and the state they would be reset to,
+    /// without changing them
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn cleanup_invalid_states_dry_run(&self) -> Result<Vec<(Uuid, State)>> {
+        let rows =
+            cleanup_invalid_states_query(&cleanup_invalid_states_dry_run_sql(), self.clock.now())
+                .fetch_all(&self.pool)
+                .await?;
 
-        let xyz_id = add_service(&p.pool).await.unwrap();
-        let service_id = add_service(&p.pool).await.unwrap();
+        rows.iter()
+            .map(|row| -> Result<(Uuid, State)> { Ok((row.try_get("id")?, row.try_get("state")?)) })
+            .collect::<Result<Vec<_>>>()
+            .map(record_rows)
+    }
 
-        let deployment_crashed = Deployment {
-            id: Uuid::new_v4(),
-            service_id: xyz_id,
-            state: State::Crashed,
-            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 7, 29, 35).unwrap(),
-            address: None,
-        };
-        let deployment_stopped = Deployment {
-            id: Uuid::new_v4(),
-            service_id: xyz_id,
-            state: State::Stopped,
-            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 7, 49, 35).unwrap(),
-            address: None,
-        };
-        let deployment_other = Deployment {
-            id: Uuid::new_v4(),
-            service_id,
-            state: State::Running,
-            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 7, 39, 39).unwrap(),
-            address: None,
-        };
-        let deployment_running = Deployment {
-            id: Uuid::new_v4(),
-            service_id: xyz_id,
-            state: State::Running,
-            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 7, 48, 29).unwrap(),
-            address: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9876)),
-        };
+    // Clean up the invalid states of only one service
+    #[instrument(skip_all, fields(service_id = %service_id))]
+    pub async fn cleanup_invalid_states_for_service(&self, service_id: &Uuid) -> Result<()> {
+        let sql = format!("{} AND service_id = ?", cleanup_invalid_states_sql());
 
-        for deployment in [
-            &deployment_crashed,
-            &deployment_stopped,
-            &deployment_other,
-            &deployment_running,
-        ] {
-            p.insert_deployment(deployment.clone()).await.unwrap();
+        cleanup_invalid_states_query(&sql, self.clock.now())
+            .bind(service_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Get the services which have more than one running deployment, which should never happen, along with those
+    /// deployments from the most to the least recently updated
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn get_services_with_multiple_running(&self) -> Result<Vec<(Uuid, Vec<Uuid>)>> {
+        let rows: Vec<(Uuid, Uuid)> = sqlx::query_as(
+            r#"SELECT service_id, id FROM deployments
+                WHERE state = ? AND service_id IN (
+                    SELECT service_id FROM deployments WHERE state = ? GROUP BY service_id HAVING COUNT(*) > 1
+                )
+                ORDER BY service_id, last_update DESC"#,
+        )
+        .bind(State::Running)
+        .bind(State::Running)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut services: Vec<(Uuid, Vec<Uuid>)> = Vec::new();
+        for (service_id, id) in rows {
+            match services.last_mut() {
+                Some((last_service_id, ids)) if *last_service_id == service_id => ids.push(id),
+                _ => services.push((service_id, vec![id])),
+            }
         }
 
-        assert_eq!(
-            p.get_active_deployment(&xyz_id).await.unwrap().unwrap(),
-            deployment_running
-        );
+        Ok(record_rows(services))
     }
 
-    // Test that we are correctly cleaning up any stale / unexpected states for a deployment
-    // The reason this does not clean up two (or more) running states for a single deployment is because
-    // it should theoretically be impossible for a service to have two deployments in the running state.
-    // And even if a service where to have this, then the start ups of these deployments (more specifically
-    // the last deployment that is starting up) will stop all the deployments correctly.
-    #[tokio::test(flavor = "multi_thread")]
-    async fn cleanup_invalid_states() {
-        let (p, _) = Persistence::new_in_memory().await;
+    /// Keep only the most recently updated running deployment of a service running, stopping the others. Returns the
+    /// number of deployments stopped.
+    #[instrument(skip(self))]
+    pub async fn resolve_double_running(&self, service_id: &Uuid) -> Result<u64> {
+        let mut transaction = self.pool.begin().await?;
 
-        let service_id = add_service(&p.pool).await.unwrap();
+        let latest: Option<(Uuid,)> = sqlx::query_as(
+            "SELECT id FROM deployments WHERE service_id = ? AND state = ? ORDER BY last_update DESC LIMIT 1",
+        )
+        .bind(service_id)
+        .bind(State::Running)
+        .fetch_optional(&mut transaction)
+        .await?;
 
-        let queued_id = Uuid::new_v4();
-        let building_id = Uuid::new_v4();
-        let built_id = Uuid::new_v4();
-        let loading_id = Uuid::new_v4();
+        let latest = match latest {
+            Some((latest,)) => latest,
+            None => return Ok(0),
+        };
 
-        let deployment_crashed = Deployment {
-            id: Uuid::new_v4(),
-            service_id,
-            state: State::Crashed,
-            last_update: Utc::now(),
+        let result = sqlx::query(
+            "UPDATE deployments SET state = ?, address = NULL, last_update = ? WHERE service_id = ? AND state = ? AND id != ?",
+        )
+        .bind(State::Stopped)
+        .bind(self.clock.now())
+        .bind(service_id)
+        .bind(State::Running)
+        .bind(latest)
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        if result.rows_affected() > 0 {
+            warn!(
+                %service_id,
+                kept = %latest,
+                stopped = result.rows_affected(),
+                "stopped the extra running deployments of a service"
+            );
+        }
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_or_create_service(&self, name: &str) -> Result<Service> {
+        if let Some(service) = self.get_service_by_name(name).await? {
+            Ok(service)
+        } else {
+            let service = Service {
+                id: Uuid::new_v4(),
+                name: name.to_string(),
+            };
+            let generation = self.service_cache.as_ref().map(ServiceCache::generation);
+
+            sqlx::query("INSERT INTO services (id, name) VALUES (?, ?)")
+                .bind(service.id)
+                .bind(&service.name)
+                .execute(&self.pool)
+                .await?;
+
+            if let (Some(cache), Some(generation)) = (&self.service_cache, generation) {
+                cache.insert(service.clone(), generation);
+            }
+
+            Ok(service)
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_service_by_name(&self, name: &str) -> Result<Option<Service>> {
+        if let Some(service) = self
+            .service_cache
+            .as_ref()
+            .and_then(|cache| cache.get(name))
+        {
+            return Ok(Some(service));
+        }
+
+        let generation = self.service_cache.as_ref().map(ServiceCache::generation);
+        let service: Option<Service> = sqlx::query_as("SELECT * FROM services WHERE name = ?")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        if let (Some(cache), Some(generation), Some(service)) =
+            (&self.service_cache, generation, &service)
+        {
+            cache.insert(service.clone(), generation);
+        }
+
+        Ok(service)
+    }
+
+    /// Drop a service from the name cache after it was changed or deleted
+    fn invalidate_cached_service(&self, id: &Uuid) {
+        if let Some(cache) = &self.service_cache {
+            cache.invalidate(id);
+        }
+    }
+
+    #[instrument(skip_all, fields(service_id = %id))]
+    pub async fn get_service_by_id(&self, id: &Uuid) -> Result<Option<Service>> {
+        sqlx::query_as("SELECT * FROM services WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(Error::from)
+    }
+
+    /// Get the service a deployment belongs to
+    #[instrument(skip(self))]
+    pub async fn get_service_for_deployment(
+        &self,
+        deployment_id: &Uuid,
+    ) -> Result<Option<Service>> {
+        sqlx::query_as(
+            "SELECT s.* FROM services AS s JOIN deployments AS d ON d.service_id = s.id WHERE d.id = ?",
+        )
+        .bind(deployment_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    /// Change the name of a service, keeping all of its deployments and logs
+    #[instrument(skip(self))]
+    pub async fn rename_service(&self, id: &Uuid, new_name: &str) -> Result<Service> {
+        let mut transaction = self.pool.begin().await?;
+
+        let taken: Option<(Uuid,)> =
+            sqlx::query_as("SELECT id FROM services WHERE name = ? AND id != ?")
+                .bind(new_name)
+                .bind(id)
+                .fetch_optional(&mut transaction)
+                .await?;
+        if taken.is_some() {
+            return Err(Error::ServiceNameTaken(new_name.to_string()));
+        }
+
+        let result = sqlx::query("UPDATE services SET name = ? WHERE id = ?")
+            .bind(new_name)
+            .bind(id)
+            .execute(&mut transaction)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(Error::ServiceNotFound);
+        }
+
+        transaction.commit().await?;
+        self.invalidate_cached_service(id);
+
+        Ok(Service {
+            id: *id,
+            name: new_name.to_string(),
+        })
+    }
+
+    /// Make a user the owner of a service
+    pub async fn set_service_owner(&self, id: &Uuid, user_id: &Uuid) -> Result<()> {
+        let result = sqlx::query("UPDATE services SET user_id = ? WHERE id = ?")
+            .bind(user_id)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::ServiceNotFound);
+        }
+
+        Ok(())
+    }
+
+    pub async fn delete_service(&self, id: &Uuid) -> Result<()> {
+        sqlx::query("DELETE FROM services WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        self.invalidate_cached_service(id);
+
+        Ok(())
+    }
+
+    /// Mark a service as deleted so that it (and everything belonging to it) can be purged later
+    pub async fn soft_delete_service(&self, id: &Uuid) -> Result<()> {
+        sqlx::query("UPDATE services SET deleted_at = ? WHERE id = ?")
+            .bind(self.clock.now())
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Hard-delete all the services which were soft-deleted before `deleted_before`, along with their deployments,
+    /// deployment labels, logs, secrets, and resources. Returns the number of services purged.
+    pub async fn purge_soft_deleted_services(&self, deleted_before: DateTime<Utc>) -> Result<u64> {
+        let mut transaction = self.pool.begin().await?;
+
+        let service_ids: Vec<(Uuid,)> = sqlx::query_as(
+            "SELECT id FROM services WHERE deleted_at IS NOT NULL AND deleted_at < ?",
+        )
+        .bind(deleted_before)
+        .fetch_all(&mut transaction)
+        .await?;
+
+        for (service_id,) in service_ids.iter() {
+            for sql in [
+                "DELETE FROM logs WHERE id IN (SELECT id FROM deployments WHERE service_id = ?)",
+                "DELETE FROM deployment_labels WHERE deployment_id IN (SELECT id FROM deployments WHERE service_id = ?)",
+                "DELETE FROM deployments WHERE service_id = ?",
+                "DELETE FROM secrets WHERE service_id = ?",
+                "DELETE FROM resources WHERE service_id = ?",
+                "DELETE FROM services WHERE id = ?",
+            ] {
+                sqlx::query(sql)
+                    .bind(service_id)
+                    .execute(&mut transaction)
+                    .await?;
+            }
+        }
+
+        transaction.commit().await?;
+
+        for (service_id,) in service_ids.iter() {
+            self.invalidate_cached_service(service_id);
+        }
+
+        Ok(service_ids.len() as u64)
+    }
+
+    /// Delete the stopped, completed, and crashed deployments (and their logs) last updated before `older_than`.
+    /// The most recent `keep_per_service` deployments of each service are always kept. Returns the number of
+    /// deployments deleted.
+    pub async fn prune_deployments(
+        &self,
+        keep_per_service: u32,
+        older_than: DateTime<Utc>,
+    ) -> Result<u64> {
+        let mut transaction = self.pool.begin().await?;
+
+        let terminal_states = states_where(State::is_terminal);
+        let sql = format!(
+            r#"SELECT id
+                FROM (
+                    SELECT id, state, last_update,
+                        ROW_NUMBER() OVER (PARTITION BY service_id ORDER BY last_update DESC) AS position
+                    FROM deployments
+                )
+                WHERE position > ? AND last_update < ? AND state IN ({})"#,
+            placeholders(terminal_states.len())
+        );
+
+        let mut query = sqlx::query_as(&sql).bind(keep_per_service).bind(older_than);
+        for state in terminal_states {
+            query = query.bind(state);
+        }
+
+        let deployment_ids: Vec<(Uuid,)> = query.fetch_all(&mut transaction).await?;
+
+        for (deployment_id,) in deployment_ids.iter() {
+            for sql in [
+                "DELETE FROM logs WHERE id = ?",
+                "DELETE FROM deployment_labels WHERE deployment_id = ?",
+                "DELETE FROM deployments WHERE id = ?",
+            ] {
+                sqlx::query(sql)
+                    .bind(deployment_id)
+                    .execute(&mut transaction)
+                    .await?;
+            }
+        }
+
+        transaction.commit().await?;
+
+        Ok(deployment_ids.len() as u64)
+    }
+
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn get_all_services(&self) -> Result<Vec<Service>> {
+        sqlx::query_as("SELECT * FROM services")
+            .fetch_all(&self.pool)
+            .await
+            .map(record_rows)
+            .map_err(Error::from)
+    }
+
+    /// Get all the services which have at least one running deployment
+    pub async fn get_services_with_running_deployments(&self) -> Result<Vec<Service>> {
+        sqlx::query_as(
+            "SELECT DISTINCT s.* FROM services AS s JOIN deployments AS d ON d.service_id = s.id WHERE d.state = ?",
+        )
+        .bind(State::Running)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    pub async fn get_all_runnable_deployments(&self) -> Result<Vec<DeploymentRunnable>> {
+        self.get_runnable_deployments(None).await
+    }
+
+    /// Get the runnable deployments of only one service
+    pub async fn get_runnable_deployments_for_service(
+        &self,
+        service_name: &str,
+    ) -> Result<Vec<DeploymentRunnable>> {
+        self.get_runnable_deployments(Some(service_name)).await
+    }
+
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    async fn get_runnable_deployments(
+        &self,
+        service_name: Option<&str>,
+    ) -> Result<Vec<DeploymentRunnable>> {
+        let active_states = states_where(State::is_active);
+        let sql = format!(
+            r#"SELECT d.id, service_id, s.name AS service_name
+                FROM deployments AS d
+                JOIN services AS s ON s.id = d.service_id
+                WHERE state IN ({}){}
+                ORDER BY last_update"#,
+            placeholders(active_states.len()),
+            if service_name.is_some() {
+                " AND s.name = ?"
+            } else {
+                ""
+            }
+        );
+
+        let mut query = sqlx::query_as(&sql);
+        for state in active_states {
+            query = query.bind(state);
+        }
+        if let Some(service_name) = service_name {
+            query = query.bind(service_name);
+        }
+
+        query
+            .fetch_all(&self.pool)
+            .await
+            .map(record_rows)
+            .map_err(Error::from)
+    }
+
+    /// Get the ports of the addresses running deployments are bound to, in ascending order
+    #[instrument(skip(self))]
+    pub async fn get_used_ports(&self) -> Result<Vec<u16>> {
+        let active_states = states_where(State::is_active);
+        let sql = format!(
+            "SELECT address FROM deployments WHERE address IS NOT NULL AND state IN ({})",
+            placeholders(active_states.len())
+        );
+
+        let mut query = sqlx::query_as(&sql);
+        for state in active_states {
+            query = query.bind(state);
+        }
+        let addresses: Vec<(String,)> = query.fetch_all(&self.pool).await?;
+
+        let mut ports: Vec<_> = addresses
+            .iter()
+            .filter_map(|(address,)| match parse_address(address) {
+                Ok(address) => Some(address.port()),
+                Err(error) => {
+                    warn!(%error, "skipping the port of a malformed address");
+                    None
+                }
+            })
+            .collect();
+        ports.sort_unstable();
+        ports.dedup();
+
+        Ok(ports)
+    }
+
+    /// Get all the runnable deployments keyed by their service so that a restart can round-robin across services
+    pub async fn get_runnable_deployments_grouped(
+        &self,
+    ) -> Result<HashMap<Uuid, Vec<DeploymentRunnable>>> {
+        let mut grouped: HashMap<_, Vec<_>> = HashMap::new();
+
+        for deployment in self.get_all_runnable_deployments().await? {
+            grouped
+                .entry(deployment.service_id)
+                .or_default()
+                .push(deployment);
+        }
+
+        Ok(grouped)
+    }
+
+    /// Get the deployments of a service which crashed the most, along with how many times they crashed
+    pub async fn get_flakiest_deployments(
+        &self,
+        service_id: &Uuid,
+        limit: i64,
+    ) -> Result<Vec<(Uuid, i64)>> {
+        sqlx::query_as(
+            r#"SELECT l.id, COUNT(*) AS crashes
+                FROM logs AS l
+                JOIN deployments AS d ON d.id = l.id
+                WHERE d.service_id = ? AND l.state = ? AND l.fields = ?
+                GROUP BY l.id
+                ORDER BY crashes DESC
+                LIMIT ?"#,
+        )
+        .bind(service_id)
+        .bind(State::Crashed)
+        .bind(json!(STATE_MESSAGE))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    pub(crate) async fn get_deployment_logs(&self, id: &Uuid) -> Result<Vec<Log>> {
+        // TODO: stress this a bit
+        get_deployment_logs(&self.pool, id).await
+    }
+
+    /// Get the logs of a deployment up to and including the state log marking it as built. Every log is returned while
+    /// the deployment has not been built yet.
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn get_build_logs(&self, id: &Uuid) -> Result<Vec<Log>> {
+        sqlx::query_as(
+            r#"SELECT * FROM logs
+                WHERE id = ? AND timestamp <= COALESCE(
+                    (SELECT MIN(timestamp) FROM logs WHERE id = ? AND state = ? AND fields = ?),
+                    timestamp
+                )
+                ORDER BY timestamp"#,
+        )
+        .bind(id)
+        .bind(id)
+        .bind(State::Built)
+        .bind(json!(STATE_MESSAGE))
+        .fetch_all(&self.pool)
+        .await
+        .map(record_rows)
+        .map_err(Error::from)
+    }
+
+    /// Bundle a deployment with its service and all its logs, for example to attach it to a support ticket
+    pub async fn export_deployment(&self, id: &Uuid) -> Result<DeploymentExport> {
+        let deployment = self
+            .get_deployment(id)
+            .await?
+            .ok_or(Error::DeploymentNotFound)?;
+        let service = self
+            .get_service_by_id(&deployment.service_id)
+            .await?
+            .ok_or(Error::ServiceNotFound)?;
+        let logs = self.get_deployment_logs(id).await?;
+
+        Ok(DeploymentExport {
+            deployment,
+            service,
+            logs,
+        })
+    }
+
+    /// Recreate an exported deployment with its original ids and timestamps, for example to debug it in isolation. The
+    /// service is only created if it does not exist yet. Nothing is imported if any of it fails.
+    #[instrument(skip_all, fields(deployment_id = %export.deployment.id, logs = export.logs.len()))]
+    pub async fn import_deployment(&self, export: &DeploymentExport) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        let existing: Option<(Uuid,)> = sqlx::query_as("SELECT id FROM deployments WHERE id = ?")
+            .bind(export.deployment.id)
+            .fetch_optional(&mut transaction)
+            .await?;
+        if existing.is_some() {
+            return Err(Error::DeploymentExists(export.deployment.id));
+        }
+
+        let service: Option<(Uuid,)> = sqlx::query_as("SELECT id FROM services WHERE name = ?")
+            .bind(&export.service.name)
+            .fetch_optional(&mut transaction)
+            .await?;
+        match service {
+            Some((id,)) if id == export.service.id => {}
+            Some(_) => return Err(Error::ServiceNameTaken(export.service.name.clone())),
+            None => {
+                let other: Option<(Uuid,)> = sqlx::query_as("SELECT id FROM services WHERE id = ?")
+                    .bind(export.service.id)
+                    .fetch_optional(&mut transaction)
+                    .await?;
+                if other.is_some() {
+                    return Err(Error::ServiceIdTaken(export.service.id));
+                }
+
+                sqlx::query("INSERT INTO services (id, name) VALUES (?, ?)")
+                    .bind(export.service.id)
+                    .bind(&export.service.name)
+                    .execute(&mut transaction)
+                    .await?;
+            }
+        }
+
+        insert_deployment(&mut transaction, export.deployment.clone()).await?;
+
+        for log in export.logs.iter() {
+            insert_log_with(&mut transaction, log.clone(), "").await?;
+        }
+
+        transaction.commit().await.map_err(Error::from)
+    }
+
+    /// Get when a deployment last logged anything, to find deployments which went silent
+    #[instrument(skip(self))]
+    pub async fn get_last_log_time(&self, id: &Uuid) -> Result<Option<DateTime<Utc>>> {
+        sqlx::query_as("SELECT MAX(timestamp) FROM logs WHERE id = ?")
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await
+            .map(|(last,)| last)
+            .map_err(Error::from)
+    }
+
+    /// Get the logs of a deployment which are at least as severe as `level`
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn get_deployment_logs_at_least(
+        &self,
+        id: &Uuid,
+        level: LogLevel,
+    ) -> Result<Vec<Log>> {
+        sqlx::query_as("SELECT * FROM logs WHERE id = ? AND level_severity >= ? ORDER BY timestamp")
+            .bind(id)
+            .bind(level.severity())
+            .fetch_all(&self.pool)
+            .await
+            .map(record_rows)
+            .map_err(Error::from)
+    }
+
+    /// Get the logs of a deployment which came from `origin`, like only the output of the service
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn get_deployment_logs_by_origin(
+        &self,
+        id: &Uuid,
+        origin: LogOrigin,
+    ) -> Result<Vec<Log>> {
+        sqlx::query_as("SELECT * FROM logs WHERE id = ? AND origin = ? ORDER BY timestamp")
+            .bind(id)
+            .bind(origin)
+            .fetch_all(&self.pool)
+            .await
+            .map(record_rows)
+            .map_err(Error::from)
+    }
+
+    /// Get the logs of a deployment which happened between `start` and `end` (inclusive)
+    pub async fn get_deployment_logs_between(
+        &self,
+        id: &Uuid,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Log>> {
+        if start > end {
+            return Err(Error::InvalidTimeRange);
+        }
+
+        sqlx::query_as(
+            "SELECT * FROM logs WHERE id = ? AND timestamp BETWEEN ? AND ? ORDER BY timestamp",
+        )
+        .bind(id)
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    /// Get the logs of a deployment with a message matching the `LIKE` `pattern`, ignoring case. Patterns which do
+    /// not start with a wildcard can be looked up in the message index.
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    pub async fn search_logs_by_message(&self, id: &Uuid, pattern: &str) -> Result<Vec<Log>> {
+        sqlx::query_as("SELECT * FROM logs WHERE id = ? AND message LIKE ? ORDER BY timestamp")
+            .bind(id)
+            .bind(pattern)
+            .fetch_all(&self.pool)
+            .await
+            .map(record_rows)
+            .map_err(Error::from)
+    }
+
+    /// Collapse each run of identical consecutive event logs of a deployment into its first log, which keeps how many
+    /// logs it stands for in its [Log::repeat_count]. State logs are never collapsed. Returns the number of logs removed.
+    ///
+    /// The runs are found by the db itself so that the write lock is only held for two statements, however many logs
+    /// the deployment has.
+    #[instrument(skip(self))]
+    pub async fn compact_logs(&self, id: &Uuid) -> Result<u64> {
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query(&format!(
+            r#"{COMPACT_LOGS_RUNS},
+                totals AS (
+                    SELECT head, SUM(repeat_count) AS total FROM runs GROUP BY head HAVING COUNT(*) > 1
+                )
+                UPDATE logs SET repeat_count = (SELECT total FROM totals WHERE head = logs.rowid)
+                WHERE rowid IN (SELECT head FROM totals)"#
+        ))
+        .bind(json!(STATE_MESSAGE))
+        .bind(id)
+        .execute(&mut transaction)
+        .await?;
+
+        let removed = sqlx::query(&format!(
+            "{COMPACT_LOGS_RUNS} DELETE FROM logs WHERE rowid IN (SELECT row_id FROM runs WHERE row_id != head)"
+        ))
+        .bind(json!(STATE_MESSAGE))
+        .bind(id)
+        .execute(&mut transaction)
+        .await?
+        .rows_affected();
+
+        transaction.commit().await?;
+
+        Ok(removed)
+    }
+
+    /// Get the distinct, non-empty targets of the logs of a deployment in sorted order
+    pub async fn get_log_targets(&self, id: &Uuid) -> Result<Vec<String>> {
+        sqlx::query_as(
+            "SELECT DISTINCT target FROM logs WHERE id = ? AND target != '' ORDER BY target",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows: Vec<(String,)>| rows.into_iter().map(|(target,)| target).collect())
+        .map_err(Error::from)
+    }
+
+    /// Get the most recent `limit` logs across every deployment of a service in chronological order. The `id` of each
+    /// log is the deployment it belongs to.
+    #[instrument(skip(self))]
+    pub async fn get_service_logs(&self, service_id: &Uuid, limit: u32) -> Result<Vec<Log>> {
+        sqlx::query_as(
+            r#"SELECT * FROM (
+                SELECT logs.* FROM logs
+                JOIN deployments ON logs.id = deployments.id
+                WHERE deployments.service_id = ?
+                ORDER BY logs.timestamp DESC
+                LIMIT ?
+            ) ORDER BY timestamp"#,
+        )
+        .bind(service_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    /// Stream every log in the database in chronological order. Rows are only read as the stream is polled, so this
+    /// can export all the logs without holding them in memory.
+    pub fn stream_all_logs(&self) -> impl Stream<Item = Result<Log>> + '_ {
+        sqlx::query_as("SELECT * FROM logs ORDER BY timestamp")
+            .fetch(&self.pool)
+            .map_err(Error::from)
+    }
+
+    /// Get the last `n` logs of a deployment in chronological order
+    pub async fn tail_deployment_logs(&self, id: &Uuid, n: u32) -> Result<Vec<Log>> {
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut logs: Vec<Log> =
+            sqlx::query_as("SELECT * FROM logs WHERE id = ? ORDER BY timestamp DESC LIMIT ?")
+                .bind(id)
+                .bind(n)
+                .fetch_all(&self.pool)
+                .await?;
+
+        logs.reverse();
+
+        Ok(logs)
+    }
+
+    /// Get a cheap digest of the logs of a deployment which changes whenever logs are added. Clients can cache it to
+    /// know when the logs need to be fetched again. It is `None` when the deployment has no logs.
+    ///
+    /// The digest is the first 8 bytes of the SHA-256 of the log count and the RFC 3339 time of the last log, so it
+    /// stays the same across releases.
+    pub async fn get_logs_digest(&self, id: &Uuid) -> Result<Option<u64>> {
+        let (count, last_timestamp): (i64, Option<DateTime<Utc>>) =
+            sqlx::query_as("SELECT COUNT(*), MAX(timestamp) FROM logs WHERE id = ?")
+                .bind(id)
+                .fetch_one(&self.pool)
+                .await?;
+
+        if count == 0 {
+            return Ok(None);
+        }
+
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(&count.to_be_bytes());
+        if let Some(last_timestamp) = last_timestamp {
+            context.update(
+                last_timestamp
+                    .to_rfc3339_opts(SecondsFormat::Nanos, true)
+                    .as_bytes(),
+            );
+        }
+
+        let mut prefix = [0; 8];
+        prefix.copy_from_slice(&context.finish().as_ref()[..8]);
+
+        Ok(Some(u64::from_be_bytes(prefix)))
+    }
+
+    pub fn get_log_subscriber(&self) -> Receiver<deploy_layer::Log> {
+        self.stream_log_send.subscribe()
+    }
+
+    /// Subscribe to the logs stream to follow the logs of deployment `id`. The subscriber is counted towards
+    /// [Persistence::subscriber_count_for] for as long as it is alive.
+    pub fn subscribe_to_deployment(&self, id: &Uuid) -> DeploymentLogSubscriber {
+        DeploymentLogSubscriber::new(
+            *id,
+            self.stream_log_send.subscribe(),
+            self.subscriber_counts.clone(),
+        )
+    }
+
+    /// Store a [deploy_layer::Log::dropped_marker] with the logs of its deployment, so that the gap in the stream can
+    /// still be seen later. It is not broadcast, since the other subscribers did not miss those logs.
+    pub async fn record_dropped_marker(&self, marker: &deploy_layer::Log) -> Result<()> {
+        insert_log(&self.pool, marker.clone()).await
+    }
+
+    /// Get the current state of a deployment along with a stream of the states it moves to from then on. The stream
+    /// ends once the deployment reaches a terminal state, so it is empty for a deployment which is already in one.
+    /// States which change faster than the stream is read are skipped in favour of the latest one.
+    pub async fn watch_deployment_state(
+        &self,
+        id: &Uuid,
+    ) -> Result<(State, impl Stream<Item = State>)> {
+        // Subscribing before reading the current state makes sure no transition is missed in between
+        let receiver = self.state_watchers.subscribe(*id);
+        let current = self
+            .get_deployment(id)
+            .await?
+            .ok_or(Error::DeploymentNotFound)?
+            .state;
+
+        let transitions =
+            futures::stream::unfold((receiver, current), |(mut receiver, previous)| async move {
+                if previous.is_terminal() {
+                    return None;
+                }
+
+                loop {
+                    receiver.changed().await.ok()?;
+                    let state = *receiver.borrow();
+
+                    if state != previous {
+                        return Some((state, (receiver, state)));
+                    }
+                }
+            });
+
+        Ok((current, transitions))
+    }
+
+    /// Number of subscribers currently following the logs of deployment `id`
+    pub fn subscriber_count_for(&self, id: &Uuid) -> usize {
+        self.subscriber_counts.get(id)
+    }
+
+    pub fn get_log_sender(&self) -> crossbeam_channel::Sender<deploy_layer::Log> {
+        self.log_send.clone()
+    }
+
+    /// Set whether a log subscriber that falls behind the broadcast stream should get a marker log saying how many
+    /// logs it missed (the default) or should have its stream closed instead.
+    pub fn with_stream_lag_markers(mut self, enabled: bool) -> Self {
+        self.stream_lag_markers = enabled;
+
+        self
+    }
+
+    /// Keep up to `capacity` services in memory so that looking them up by name (like the proxy does on every
+    /// request) does not always hit the state database. There is no cache by default.
+    pub fn with_service_cache(mut self, capacity: usize) -> Self {
+        self.service_cache = Some(ServiceCache::new(capacity));
+
+        self
+    }
+
+    /// Reject new deployments of a service with [Error::TooManyActiveDeployments] once it has `limit` deployments
+    /// which have not finished yet. There is no limit by default.
+    pub fn with_max_active_deployments(mut self, limit: u32) -> Self {
+        self.max_active_deployments = Some(limit);
+
+        self
+    }
+
+    pub fn stream_lag_markers(&self) -> bool {
+        self.stream_lag_markers
+    }
+
+    /// Keep secrets in `secret_store` rather than in the state database
+    pub fn with_secret_store(mut self, secret_store: impl SecretStore) -> Self {
+        self.secret_store = Arc::new(secret_store);
+        self.custom_secret_store = true;
+
+        self
+    }
+
+    /// Take the timestamps written to the state database from `clock`. A custom secret store keeps its own time, so it
+    /// is left alone whichever order the two are set in.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        if !self.custom_secret_store {
+            self.secret_store =
+                Arc::new(SqliteSecretStore::new(self.pool.clone()).with_clock(clock.clone()));
+        }
+        self.clock = clock;
+
+        self
+    }
+
+    pub async fn delete_secret(&self, service_id: &Uuid, key: &str) -> Result<()> {
+        self.secret_store.delete_secret(service_id, key).await
+    }
+
+    /// Hard-delete the secrets which have expired, returning how many were deleted
+    #[instrument(skip(self))]
+    pub async fn delete_expired_secrets(&self) -> Result<u64> {
+        self.secret_store.delete_expired_secrets().await
+    }
+
+    /// Run [Persistence::delete_expired_secrets] on every tick of `period`
+    pub fn spawn_expired_secrets_sweeper(&self, period: std::time::Duration) -> JoinHandle<()> {
+        let persistence = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+
+            loop {
+                interval.tick().await;
+
+                if let Err(error) = persistence.delete_expired_secrets().await {
+                    error!(
+                        error = &error as &dyn std::error::Error,
+                        "failed to delete expired secrets"
+                    );
+                }
+            }
+        })
+    }
+}
+
+async fn applied_migrations(pool: &SqlitePool) -> Result<Vec<(i64, String)>> {
+    let table: Option<(String,)> = sqlx::query_as(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if table.is_none() {
+        return Ok(Vec::new());
+    }
+
+    sqlx::query_as("SELECT version, description FROM _sqlx_migrations ORDER BY version")
+        .fetch_all(pool)
+        .await
+        .map_err(Error::from)
+}
+
+/// Refuse a database with migrations newer than the ones this binary knows about, like after rolling back to an older
+/// deployer, since running against a schema it does not understand could lose data
+async fn check_schema_not_ahead(pool: &SqlitePool) -> Result<()> {
+    let known = MIGRATIONS
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or_default();
+    let applied = applied_migrations(pool)
+        .await?
+        .into_iter()
+        .map(|(version, _)| version)
+        .max();
+
+    match applied {
+        Some(applied) if applied > known => Err(Error::SchemaAhead { applied, known }),
+        _ => Ok(()),
+    }
+}
+
+/// Get all the states which pass `filter`
+fn states_where(filter: impl Fn(&State) -> bool) -> Vec<State> {
+    State::iter().filter(filter).collect()
+}
+
+/// Get a list of `count` bind placeholders to use in an `IN` clause
+fn placeholders(count: usize) -> String {
+    vec!["?"; count].join(", ")
+}
+
+/// Get the query which moves all the in progress deployments to [State::Stopped]
+fn cleanup_invalid_states_sql() -> String {
+    format!(
+        "UPDATE deployments SET state = ?, last_update = ? WHERE {}",
+        invalid_states_condition()
+    )
+}
+
+/// Get the query which selects the deployments [cleanup_invalid_states_sql] would change, along with their new state
+fn cleanup_invalid_states_dry_run_sql() -> String {
+    format!(
+        "SELECT id, ? AS state, ? AS last_update FROM deployments WHERE {}",
+        invalid_states_condition()
+    )
+}
+
+fn invalid_states_condition() -> String {
+    format!(
+        "state IN({})",
+        placeholders(states_where(State::is_in_progress).len())
+    )
+}
+
+/// Binds the states used by [cleanup_invalid_states_sql] and [cleanup_invalid_states_dry_run_sql] so that any extra
+/// conditions can be added to the end of `sql`. The reset deployments are marked as updated at `now`.
+fn cleanup_invalid_states_query(
+    sql: &str,
+    now: DateTime<Utc>,
+) -> Query<'_, Sqlite, SqliteArguments<'_>> {
+    let mut query = sqlx::query(sql).bind(State::Stopped).bind(now);
+    for state in states_where(State::is_in_progress) {
+        query = query.bind(state);
+    }
+
+    query
+}
+
+/// Store the log of a state change and update the deployment to that state together, so that neither is kept when the
+/// other fails. Returns the number of deployments updated.
+/// The whole transaction is retried while the database is busy, since losing a state change is worse than losing an
+/// event.
+async fn store_state_log(pool: &SqlitePool, log: deploy_layer::Log) -> Result<u64> {
+    retry_when_busy("state log", || store_state_log_once(pool, log.clone())).await
+}
+
+async fn store_state_log_once(pool: &SqlitePool, log: deploy_layer::Log) -> Result<u64> {
+    let mut transaction = pool.begin().await?;
+
+    insert_state_log(
+        &mut transaction,
+        Log {
+            id: log.id,
+            timestamp: log.timestamp,
+            state: log.state,
+            level: log.level.clone(),
+            file: log.file.clone(),
+            line: log.line,
+            target: String::new(),
+            fields: json!(STATE_MESSAGE),
+            trace_id: log.trace_id.clone(),
+            span_id: log.span_id.clone(),
+            origin: LogOrigin::Deployer,
+            span_fields: Some(log.fields.clone()),
+            duration_ms: None,
+            repeat_count: 1,
+        },
+    )
+    .await?;
+    let updated = update_deployment(&mut transaction, log, None, None).await?;
+
+    transaction.commit().await?;
+
+    Ok(updated)
+}
+
+/// Update the state of a deployment. When `expected_last_update` is set, the update is only applied if the deployment
+/// was last updated at exactly that time. When `from_states` is set, the update is only applied if the deployment is
+/// currently in one of those states, so that the check and the update cannot race.
+#[instrument(skip_all, fields(deployment_id = field::Empty, state = field::Empty))]
+async fn update_deployment<'c>(
+    executor: impl sqlx::Executor<'c, Database = Sqlite>,
+    state: impl Into<DeploymentState>,
+    expected_last_update: Option<DateTime<Utc>>,
+    from_states: Option<&[State]>,
+) -> Result<u64> {
+    let state = state.into();
+    let span = Span::current();
+    span.record("deployment_id", &field::display(state.id));
+    span.record("state", &field::display(state.state));
+
+    // TODO: Handle moving to 'active_deployments' table for State::Running.
+
+    // A deployment that is already running keeps the time it started running
+    let mut sql = "UPDATE deployments SET state = ?, last_update = ?, address = ?, error_message = ?, running_since = CASE WHEN ? THEN COALESCE(running_since, ?) ELSE NULL END WHERE id = ? AND (? IS NULL OR last_update = ?)".to_string();
+    if let Some(from_states) = from_states {
+        sql.push_str(&format!(
+            " AND state IN ({})",
+            placeholders(from_states.len())
+        ));
+    }
+
+    let mut query = sqlx::query(&sql)
+        .bind(state.state)
+        .bind(state.last_update)
+        .bind(state.address.map(|socket| socket.to_string()))
+        .bind(state.error_message)
+        .bind(state.state == State::Running)
+        .bind(state.last_update)
+        .bind(state.id)
+        .bind(expected_last_update)
+        .bind(expected_last_update);
+    for from in from_states.unwrap_or_default() {
+        query = query.bind(*from);
+    }
+
+    query
+        .execute(executor)
+        .await
+        .map(|result| {
+            metrics::state_updated();
+            result.rows_affected()
+        })
+        .map_err(|error| {
+            metrics::query_error("update_deployment");
+            Error::from(error)
+        })
+}
+
+/// Finds the runs of identical consecutive event logs of a deployment as `runs`, with every log's row id and the row id
+/// of the first log of its run as `head`. Takes the state message and the deployment id as its parameters.
+const COMPACT_LOGS_RUNS: &str = r#"WITH marked AS (
+        SELECT rowid AS row_id, timestamp, repeat_count,
+            fields IS NOT ?
+                AND level IS LAG(level) OVER w
+                AND origin IS LAG(origin) OVER w
+                AND target IS LAG(target) OVER w
+                AND fields IS LAG(fields) OVER w
+                AND fields_compressed IS LAG(fields_compressed) OVER w AS continues_run
+        FROM logs WHERE id = ?
+        WINDOW w AS (ORDER BY timestamp)
+    ),
+    numbered AS (
+        SELECT row_id, timestamp, repeat_count, SUM(NOT continues_run) OVER (ORDER BY timestamp) AS run FROM marked
+    ),
+    runs AS (
+        SELECT row_id, repeat_count, FIRST_VALUE(row_id) OVER (PARTITION BY run ORDER BY timestamp) AS head
+        FROM numbered
+    )"#;
+
+/// Store how long a state took on the latest log of the deployment entering that state
+async fn set_state_duration(pool: &SqlitePool, log: &deploy_layer::Log) -> Result<u64> {
+    let duration_ms = log
+        .duration_ms
+        .map(|duration_ms| i64::try_from(duration_ms).unwrap_or(i64::MAX));
+
+    retry_when_busy("state duration update", || async {
+        sqlx::query(
+            "UPDATE logs SET duration_ms = ? WHERE rowid = (SELECT rowid FROM logs WHERE id = ? AND state = ? AND fields = ? ORDER BY timestamp DESC LIMIT 1)",
+        )
+        .bind(duration_ms)
+        .bind(log.id)
+        .bind(log.state)
+        .bind(json!(STATE_MESSAGE))
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(Error::from)
+    })
+    .await
+}
+
+/// Record why a deployment crashed without touching the rest of its state
+async fn set_error_message(pool: &SqlitePool, id: &Uuid, error_message: &str) -> Result<u64> {
+    sqlx::query("UPDATE deployments SET error_message = ? WHERE id = ?")
+        .bind(error_message)
+        .bind(id)
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(Error::from)
+}
+
+#[instrument(skip_all, fields(deployment_id = %id))]
+async fn get_deployment(pool: &SqlitePool, id: &Uuid) -> Result<Option<Deployment>> {
+    sqlx::query_as("SELECT * FROM deployments WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .map_err(Error::from)
+}
+
+async fn insert_deployment<'c>(
+    executor: impl sqlx::Executor<'c, Database = Sqlite>,
+    deployment: Deployment,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO deployments (id, service_id, state, last_update, address, error_message, running_since) VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(deployment.id)
+    .bind(deployment.service_id)
+    .bind(deployment.state)
+    .bind(deployment.last_update)
+    .bind(deployment.address.map(|socket| socket.to_string()))
+    .bind(deployment.error_message)
+    .bind(deployment.running_since)
+    .execute(executor)
+    .await
+    .map(|_| metrics::deployment_inserted())
+    .map_err(|error| {
+        metrics::query_error("insert_deployment");
+        Error::from(error)
+    })
+}
+
+/// How many times a write is tried while the database is busy, and how long to wait before the first retry. The wait
+/// doubles with each retry.
+///
+/// Every attempt can itself wait up to [PersistenceConfig::busy_timeout] for the lock. So a write on a db which stays
+/// locked stalls the log drain for about `WRITE_ATTEMPTS` times the busy timeout (25s with the default config) before
+/// giving up. The log channel is unbuffered, so every thread recording a log waits along with it.
+const WRITE_ATTEMPTS: u32 = 5;
+const WRITE_BACKOFF: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Try a write again for a moment while the database is busy, so that a burst of writes does not lose it
+async fn retry_when_busy<T, F, Fut>(what: &str, mut write: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut backoff = WRITE_BACKOFF;
+
+    for _ in 1..WRITE_ATTEMPTS {
+        match write().await {
+            Err(error) if error.is_transient() => {
+                warn!(
+                    error = &error as &dyn std::error::Error,
+                    ?backoff,
+                    "state db is busy, retrying {what}"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+
+    write().await
+}
+
+/// Insert a log, retrying while the database is busy
+async fn insert_log(pool: &SqlitePool, log: impl Into<Log>) -> Result<()> {
+    let log = log.into();
+
+    retry_when_busy("log insert", || insert_log_with(pool, log.clone(), "")).await
+}
+
+/// Insert a state log, ignoring it when a log of the deployment was already stored at the same time. This happens when
+/// a state transition is retried.
+async fn insert_state_log<'c>(
+    executor: impl sqlx::Executor<'c, Database = Sqlite>,
+    log: impl Into<Log>,
+) -> Result<()> {
+    insert_log_with(
+        executor,
+        log.into(),
+        " ON CONFLICT (id, timestamp) DO NOTHING",
+    )
+    .await
+}
+
+// Every event a deployment emits is inserted, so keep these spans out of the default traces
+#[instrument(level = "trace", skip_all, fields(deployment_id = %log.id))]
+async fn insert_log_with<'c>(
+    executor: impl sqlx::Executor<'c, Database = Sqlite>,
+    log: Log,
+    on_conflict: &str,
+) -> Result<()> {
+    let start = Instant::now();
+    let sql = format!("INSERT INTO logs (id, timestamp, state, level, file, line, target, fields, fields_compressed, trace_id, span_id, origin, span_fields, duration_ms, repeat_count, message) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?){on_conflict}");
+    let message = log::search_message(&log.fields);
+
+    let query = sqlx::query(&sql)
+        .bind(log.id)
+        .bind(log.timestamp)
+        .bind(log.state)
+        .bind(log.level)
+        .bind(log.file)
+        .bind(log.line)
+        .bind(log.target);
+
+    let query = match log::compress_fields(&log.fields) {
+        Some(compressed) => query.bind(compressed).bind(true),
+        None => query.bind(log.fields).bind(false),
+    };
+
+    query
+        .bind(log.trace_id)
+        .bind(log.span_id)
+        .bind(log.origin)
+        .bind(log.span_fields)
+        .bind(
+            log.duration_ms
+                .map(|duration_ms| i64::try_from(duration_ms).unwrap_or(i64::MAX)),
+        )
+        .bind(log.repeat_count)
+        .bind(message)
+        .execute(executor)
+        .await
+        .map(|_| metrics::log_inserted(start.elapsed()))
+        .map_err(|error| {
+            metrics::query_error("insert_log");
+            Error::from(error)
+        })
+}
+
+#[instrument(skip_all, fields(deployment_id = %id, rows = field::Empty))]
+async fn get_deployment_logs(pool: &SqlitePool, id: &Uuid) -> Result<Vec<Log>> {
+    sqlx::query_as("SELECT * FROM logs WHERE id = ? ORDER BY timestamp")
+        .bind(id)
+        .fetch_all(pool)
+        .await
+        .map(record_rows)
+        .map_err(Error::from)
+}
+
+/// Record how many rows a query returned on the `rows` field of the current span
+fn record_rows<T>(rows: Vec<T>) -> Vec<T> {
+    Span::current().record("rows", rows.len());
+
+    rows
+}
+
+impl LogRecorder for Persistence {
+    fn record(&self, log: deploy_layer::Log) {
+        if let Err(error) = self.log_send.send(log) {
+            // Nothing receives logs anymore once the drain was shut down
+            if !self.log_drain_cancel.is_cancelled() {
+                panic!("failed to move log to async thread: {error}");
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ResourceManager for Persistence {
+    type Err = Error;
+
+    #[instrument(skip_all, fields(service_id = %resource.service_id))]
+    async fn insert_resource(&self, resource: &Resource) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO resources (service_id, type, data) VALUES (?, ?, ?)")
+            .bind(resource.service_id)
+            .bind(resource.r#type)
+            .bind(&resource.data)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    #[instrument(skip_all, fields(service_id = %service_id, rows = field::Empty))]
+    async fn get_resources(&self, service_id: &Uuid) -> Result<Vec<Resource>> {
+        sqlx::query_as(r#"SELECT * FROM resources WHERE service_id = ?"#)
+            .bind(service_id)
+            .fetch_all(&self.pool)
+            .await
+            .map(record_rows)
+            .map_err(Error::from)
+    }
+
+    #[instrument(skip(self), fields(rows = field::Empty))]
+    async fn get_all_resources(&self) -> Result<Vec<Resource>> {
+        sqlx::query_as("SELECT * FROM resources")
+            .fetch_all(&self.pool)
+            .await
+            .map(record_rows)
+            .map_err(Error::from)
+    }
+}
+
+#[async_trait::async_trait]
+impl SecretRecorder for Persistence {
+    type Err = Error;
+
+    #[instrument(skip(self, service_id, value), fields(service_id = %service_id))]
+    async fn insert_secret(&self, service_id: &Uuid, key: &str, value: &str) -> Result<()> {
+        validate_secret_key(key)?;
+
+        self.secret_store
+            .insert_secret(service_id, key, value)
+            .await
+    }
+
+    #[instrument(skip(self, service_id, value), fields(service_id = %service_id))]
+    async fn insert_secret_with_ttl(
+        &self,
+        service_id: &Uuid,
+        key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<()> {
+        validate_secret_key(key)?;
+
+        self.secret_store
+            .insert_secret_with_ttl(service_id, key, value, ttl)
+            .await
+    }
+
+    #[instrument(skip_all, fields(service_id = %service_id, count = secrets.len()))]
+    async fn insert_secrets(
+        &self,
+        service_id: &Uuid,
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        secrets
+            .keys()
+            .try_for_each(|key| validate_secret_key(key))?;
+
+        self.secret_store.insert_secrets(service_id, secrets).await
+    }
+
+    #[instrument(skip_all, fields(service_id = %service_id, count = desired.len()))]
+    async fn sync_secrets(
+        &self,
+        service_id: &Uuid,
+        desired: &BTreeMap<String, String>,
+    ) -> Result<SecretSyncReport> {
+        desired
+            .keys()
+            .try_for_each(|key| validate_secret_key(key))?;
+
+        self.secret_store.sync_secrets(service_id, desired).await
+    }
+}
+
+#[async_trait::async_trait]
+impl SecretGetter for Persistence {
+    type Err = Error;
+
+    #[instrument(skip_all, fields(service_id = %service_id))]
+    async fn get_secrets(&self, service_id: &Uuid) -> Result<Vec<Secret>> {
+        self.secret_store.get_secrets(service_id).await
+    }
+}
+
+#[async_trait::async_trait]
+impl AddressGetter for Persistence {
+    #[instrument(skip(self))]
+    async fn get_address_for_service(
+        &self,
+        service_name: &str,
+    ) -> crate::handlers::Result<Option<std::net::SocketAddr>> {
+        // Every proxied request looks up its service, so this goes through the service cache
+        let service = match self
+            .get_service_by_name(service_name)
+            .await
+            .map_err(crate::handlers::Error::Persistence)?
+        {
+            Some(service) => service,
+            None => return Ok(None),
+        };
+
+        let address_str = sqlx::query_as::<_, (String,)>(
+            "SELECT address FROM deployments WHERE service_id = ? AND state = ? ORDER BY last_update",
+        )
+        .bind(service.id)
+        .bind(State::Running)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(Error::from)
+        .map_err(crate::handlers::Error::Persistence)?;
+
+        if let Some((address_str,)) = address_str {
+            parse_address(&address_str)
+                .map(Some)
+                .map_err(crate::handlers::Error::from)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ActiveDeploymentsGetter for Persistence {
+    type Err = Error;
+
+    async fn get_active_deployments(
+        &self,
+        service_id: &Uuid,
+    ) -> std::result::Result<Vec<Uuid>, Self::Err> {
+        let ids: Vec<_> = sqlx::query_as::<_, Deployment>(
+            "SELECT * FROM deployments WHERE service_id = ? AND state = ?",
+        )
+        .bind(service_id)
+        .bind(State::Running)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)?
+        .into_iter()
+        .map(|deployment| deployment.id)
+        .collect();
+
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use chrono::{TimeZone, Utc};
+    use futures::StreamExt;
+    use rand::Rng;
+    use serde_json::json;
+    use sqlx::Connection;
+
+    use super::*;
+    use crate::persistence::{
+        deployment::{Deployment, DeploymentRunnable, DeploymentState},
+        log::{Level, Log, Origin},
+        state::State,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn incremental_vacuum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deployer.sqlite");
+        let (p, _) = Persistence::new(path.to_str().unwrap(), Default::default()).await;
+
+        let (auto_vacuum,): (i64,) = sqlx::query_as("PRAGMA auto_vacuum")
+            .fetch_one(&p.pool)
+            .await
+            .unwrap();
+        assert_eq!(auto_vacuum, 2, "auto_vacuum should be INCREMENTAL");
+
+        let service_id = add_service(&p.pool).await.unwrap();
+        for i in 0..200 {
+            p.insert_secret(&service_id, &format!("key{i}"), &"value".repeat(100))
+                .await
+                .unwrap();
+        }
+        sqlx::query("DELETE FROM secrets")
+            .execute(&p.pool)
+            .await
+            .unwrap();
+
+        p.incremental_vacuum(10).await.unwrap();
+        p.incremental_vacuum(0).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pool_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deployer.sqlite");
+        let config = PersistenceConfig {
+            max_connections: 1,
+            acquire_timeout: std::time::Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        let (p, _) = Persistence::new(path.to_str().unwrap(), config).await;
+        let held = p.pool.acquire().await.unwrap();
+
+        assert!(matches!(
+            p.get_deployment(&Uuid::new_v4()).await,
+            Err(Error::PoolTimeout)
+        ));
+
+        drop(held);
+        assert!(p.get_deployment(&Uuid::new_v4()).await.unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pragmas_from_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deployer.sqlite");
+        let config = PersistenceConfig {
+            page_size: 16384,
+            mmap_size: 64 * 1024 * 1024,
+            ..Default::default()
+        };
+
+        let (p, _) = Persistence::new(path.to_str().unwrap(), config).await;
+
+        let pragma = |name: &'static str| {
+            let pool = p.pool.clone();
+            async move {
+                let (value,): (i64,) = sqlx::query_as(&format!("PRAGMA {name}"))
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+                value
+            }
+        };
+        assert_eq!(pragma("page_size").await, 16384);
+        assert_eq!(pragma("mmap_size").await, 64 * 1024 * 1024);
+        assert_eq!(
+            pragma("synchronous").await,
+            2,
+            "synchronous should stay FULL"
+        );
+
+        let (journal_mode,): (String,) = sqlx::query_as("PRAGMA journal_mode")
+            .fetch_one(&p.pool)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_writes_with_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deployer.sqlite");
+        let config = PersistenceConfig {
+            max_connections: 2,
+            busy_timeout: std::time::Duration::from_secs(10),
+            ..Default::default()
+        };
+
+        let (p, _) = Persistence::new(path.to_str().unwrap(), config).await;
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        let writes: Vec<_> = (0..20)
+            .map(|i| {
+                let p = p.clone();
+                tokio::spawn(async move {
+                    p.insert_secret(&service_id, &format!("key{i}"), "value")
+                        .await
+                })
+            })
+            .collect();
+
+        for write in futures::future::join_all(writes).await {
+            write.unwrap().unwrap();
+        }
+
+        assert_eq!(p.get_secrets(&service_id).await.unwrap().len(), 20);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn in_memory_survives_idle_pool() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service = p.get_or_create_service("idle").await.unwrap();
+
+        // Close every pooled connection, like the pool does once they have been idle for too long
+        let open: Vec<_> = futures::future::join_all((0..p.pool.size()).map(|_| p.pool.acquire()))
+            .await
+            .into_iter()
+            .map(|connection| connection.unwrap().detach())
+            .collect();
+        for connection in open {
+            connection.close().await.unwrap();
+        }
+
+        assert_eq!(
+            p.get_service_by_name("idle").await.unwrap(),
+            Some(service),
+            "the db should not be lost with the pooled connections"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn schema_ahead() {
+        let (p, _) = Persistence::new_in_memory().await;
+        check_schema_not_ahead(&p.pool).await.unwrap();
+
+        let known = MIGRATIONS
+            .iter()
+            .map(|migration| migration.version)
+            .max()
+            .unwrap();
+        add_future_migration(&p.pool, known + 1).await;
+
+        assert!(matches!(
+            check_schema_not_ahead(&p.pool).await,
+            Err(Error::SchemaAhead { applied, known: latest }) if applied == known + 1 && latest == known
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[should_panic(expected = "newer than")]
+    async fn schema_ahead_refuses_to_start() {
+        let (p, _) = Persistence::new_in_memory().await;
+        add_future_migration(&p.pool, i64::MAX).await;
+
+        Persistence::from_pool(p.pool.clone()).await;
+    }
+
+    /// Pretend a newer deployer applied migration `version` to the db
+    async fn add_future_migration(pool: &SqlitePool, version: i64) {
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (?, 'from the future', TRUE, x'00', 0)",
+        )
+        .bind(version)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn database_stats() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let empty = p.database_size().await.unwrap();
+        assert!(empty.size_bytes > 0);
+        assert_eq!(
+            empty,
+            DatabaseStats {
+                size_bytes: empty.size_bytes,
+                ..Default::default()
+            }
+        );
+
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+        let service_id = p
+            .get_deployment(&deployment_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .service_id;
+        add_service(&p.pool).await.unwrap();
+        for message in ["first", "second", "third"] {
+            insert_log(
+                &p.pool,
+                Log {
+                    id: deployment_id,
+                    timestamp: Utc::now(),
+                    state: State::Running,
+                    level: Level::Info,
+                    file: None,
+                    line: None,
+                    target: "tests::database_stats".to_string(),
+                    fields: json!({ "message": message }),
+                    trace_id: None,
+                    span_id: None,
+                    origin: Origin::Deployer,
+                    span_fields: None,
+                    duration_ms: None,
+                    repeat_count: 1,
+                },
+            )
+            .await
+            .unwrap();
+        }
+        p.insert_secret(&service_id, "KEY", "value").await.unwrap();
+        p.insert_resource(&Resource {
+            service_id,
+            r#type: ResourceType::Database(resource::DatabaseType::Shared(
+                resource::database::SharedType::Postgres,
+            )),
+            data: json!({"username": "foo"}),
+        })
+        .await
+        .unwrap();
+
+        let stats = p.database_size().await.unwrap();
+        assert!(stats.size_bytes >= empty.size_bytes);
+        assert_eq!(
+            stats,
+            DatabaseStats {
+                size_bytes: stats.size_bytes,
+                deployments: 1,
+                logs: 3,
+                secrets: 1,
+                resources: 1,
+                services: 2,
+            }
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn applied_migrations() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let expected: Vec<_> = MIGRATIONS
+            .iter()
+            .map(|migration| (migration.version, migration.description.to_string()))
+            .collect();
+
+        assert!(!expected.is_empty());
+        assert_eq!(p.applied_migrations().await.unwrap(), expected);
+
+        sqlx::query("DROP TABLE _sqlx_migrations")
+            .execute(&p.pool)
+            .await
+            .unwrap();
+
+        assert!(
+            p.applied_migrations().await.unwrap().is_empty(),
+            "a missing migrations table should mean no migrations were applied"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_updates() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        let id = Uuid::new_v4();
+        let deployment = Deployment {
+            id,
+            service_id,
+            state: State::Queued,
+            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+
+        p.insert_deployment(deployment.clone()).await.unwrap();
+        assert_eq!(p.get_deployment(&id).await.unwrap().unwrap(), deployment);
+
+        update_deployment(
+            &p.pool,
+            DeploymentState {
+                id,
+                state: State::Built,
+                last_update: Utc::now(),
+                address: None,
+                error_message: None,
+            },
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let update = p.get_deployment(&id).await.unwrap().unwrap();
+        assert_eq!(update.state, State::Built);
+        assert_ne!(
+            update.last_update,
+            Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap()
+        );
+
+        let address = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 8000);
+        update_deployment(
+            &p.pool,
+            DeploymentState {
+                id,
+                state: State::Running,
+                last_update: Utc::now(),
+                address: Some(address),
+                error_message: None,
+            },
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let update = p.get_deployment(&id).await.unwrap().unwrap();
+        assert_eq!(update.address, Some(address));
+
+        let (stored,): (String,) = sqlx::query_as("SELECT address FROM deployments WHERE id = ?")
+            .bind(id)
+            .fetch_one(&p.pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, "[::1]:8000");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn insert_log_retries_when_busy() {
+        use sqlx::{sqlite::SqliteConnection, Connection};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deployer.sqlite");
+        let path = path.to_str().unwrap();
+        let config = PersistenceConfig {
+            busy_timeout: std::time::Duration::ZERO,
+            ..Default::default()
+        };
+        let (p, _) = Persistence::new(path, config).await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+
+        // Hold the write lock from another connection for a moment
+        let mut locker = SqliteConnection::connect(path).await.unwrap();
+        sqlx::query("BEGIN IMMEDIATE")
+            .execute(&mut locker)
+            .await
+            .unwrap();
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            sqlx::query("COMMIT").execute(&mut locker).await.unwrap();
+        });
+
+        let log = Log {
+            id: deployment_id,
+            timestamp: Utc.with_ymd_and_hms(2022, 12, 1, 9, 0, 0).unwrap(),
+            state: State::Running,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: "tests::insert_log_retries_when_busy".to_string(),
+            fields: json!({ "message": "written after the lock is released" }),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+        insert_log(&p.pool, log.clone()).await.unwrap();
+        release.await.unwrap();
+
+        assert_eq!(
+            get_deployment_logs(&p.pool, &deployment_id).await.unwrap(),
+            vec![log]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn store_state_log_retries_when_busy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deployer.sqlite");
+        let path = path.to_str().unwrap();
+        let config = PersistenceConfig {
+            busy_timeout: std::time::Duration::ZERO,
+            ..Default::default()
+        };
+        let (p, _) = Persistence::new(path, config).await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+
+        // Hold the write lock from another connection for a moment
+        let mut locker = SqliteConnection::connect(path).await.unwrap();
+        sqlx::query("BEGIN IMMEDIATE")
+            .execute(&mut locker)
+            .await
+            .unwrap();
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            sqlx::query("COMMIT").execute(&mut locker).await.unwrap();
+        });
+
+        let stopped_at = Utc.with_ymd_and_hms(2022, 12, 1, 9, 0, 0).unwrap();
+        let updated = store_state_log(
+            &p.pool,
+            deploy_layer::Log {
+                id: deployment_id,
+                timestamp: stopped_at,
+                state: State::Stopped,
+                level: Level::Info,
+                file: None,
+                line: None,
+                target: String::new(),
+                fields: json!({}),
+                r#type: deploy_layer::LogType::State,
+                address: None,
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                duration_ms: None,
+            },
+        )
+        .await
+        .unwrap();
+        release.await.unwrap();
+
+        assert_eq!(updated, 1);
+        let deployment = p.get_deployment(&deployment_id).await.unwrap().unwrap();
+        assert_eq!(deployment.state, State::Stopped);
+        assert_eq!(deployment.last_update, stopped_at);
+        assert_eq!(
+            get_deployment_logs(&p.pool, &deployment_id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn foreign_keys() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let result = p
+            .insert_deployment(Deployment::builder(Uuid::new_v4()))
+            .await;
+        assert!(
+            matches!(result, Err(Error::Database(_))),
+            "a deployment of a missing service should be rejected: {result:?}"
+        );
+
+        let result = insert_log(
+            &p.pool,
+            Log {
+                id: Uuid::new_v4(),
+                timestamp: Utc::now(),
+                state: State::Queued,
+                level: Level::Info,
+                file: None,
+                line: None,
+                target: "tests::foreign_keys".to_string(),
+                fields: json!({ "message": "orphaned" }),
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                span_fields: None,
+                duration_ms: None,
+                repeat_count: 1,
+            },
+        )
+        .await;
+        assert!(
+            matches!(result, Err(Error::Database(_))),
+            "a log of a missing deployment should be rejected: {result:?}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn used_ports() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        for (state, port) in [
+            (State::Running, 9000),
+            (State::Running, 8001),
+            (State::Stopped, 8002),
+            (State::Crashed, 8003),
+        ] {
+            p.insert_deployment(
+                Deployment::builder(service_id)
+                    .state(state)
+                    .address(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)),
+            )
+            .await
+            .unwrap();
+        }
+        p.insert_deployment(Deployment::builder(service_id).state(State::Running))
+            .await
+            .unwrap();
+
+        assert_eq!(p.get_used_ports().await.unwrap(), vec![8001, 9000]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_builder() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        let before = Utc::now();
+        let deployment = Deployment::builder(service_id).build();
+        assert_eq!(deployment.state, State::Queued);
+        assert_eq!(deployment.address, None);
+        assert_eq!(deployment.error_message, None);
+        assert_eq!(deployment.running_since, None);
+        assert!(deployment.last_update >= before);
+        assert_eq!(deployment.service_id, service_id);
+        assert_ne!(deployment.id, Deployment::builder(service_id).build().id);
+
+        let id = Uuid::new_v4();
+        let last_update = Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap();
+        let address = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9876);
+        let expected = Deployment {
+            id,
+            service_id,
+            state: State::Crashed,
+            last_update,
+            address: Some(address),
+            error_message: Some("panicked".to_string()),
+            running_since: None,
+        };
+
+        p.insert_deployment(
+            Deployment::builder(service_id)
+                .id(id)
+                .state(State::Crashed)
+                .last_update(last_update)
+                .address(address)
+                .error_message("panicked"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(p.get_deployment(&id).await.unwrap(), Some(expected));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_crashes_since() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let cutoff = Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap();
+
+        let deployments: Vec<_> = [
+            (State::Crashed, cutoff - Duration::minutes(5)),
+            (State::Crashed, cutoff),
+            (State::Crashed, cutoff + Duration::minutes(10)),
+            (State::Stopped, cutoff + Duration::minutes(1)),
+            (State::Crashed, cutoff + Duration::minutes(2)),
+        ]
+        .into_iter()
+        .map(|(state, last_update)| {
+            Deployment::builder(service_id)
+                .state(state)
+                .last_update(last_update)
+                .build()
+        })
+        .collect();
+        for deployment in deployments.iter() {
+            p.insert_deployment(deployment.clone()).await.unwrap();
+        }
+
+        assert_eq!(
+            p.get_crashed_deployments_since(cutoff).await.unwrap(),
+            vec![deployments[4].clone(), deployments[2].clone()]
+        );
+        assert!(p
+            .get_crashed_deployments_since(cutoff + Duration::minutes(10))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployments_all_paged() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let start = Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap();
+
+        // Spread over a few services, with the most recent first
+        let mut deployments = Vec::new();
+        for i in 0..7 {
+            let service_id = add_service(&p.pool).await.unwrap();
+            let deployment = Deployment::builder(service_id)
+                .state(State::Stopped)
+                .last_update(start + Duration::seconds(i))
+                .build();
+            p.insert_deployment(deployment.clone()).await.unwrap();
+            deployments.insert(0, deployment);
+        }
+
+        assert_eq!(
+            p.get_all_deployments_paged(3, 0).await.unwrap(),
+            deployments[0..3]
+        );
+        assert_eq!(
+            p.get_all_deployments_paged(3, 3).await.unwrap(),
+            deployments[3..6]
+        );
+        assert_eq!(
+            p.get_all_deployments_paged(3, 6).await.unwrap(),
+            deployments[6..]
+        );
+        assert!(p.get_all_deployments_paged(3, 7).await.unwrap().is_empty());
+
+        let first = p.get_all_deployments_after(None, 3).await.unwrap();
+        assert_eq!(first, deployments[0..3]);
+
+        // A newer deployment should not shift the next page
+        let newer = Deployment::builder(deployments[0].service_id)
+            .state(State::Queued)
+            .last_update(start + Duration::minutes(1))
+            .build();
+        p.insert_deployment(newer).await.unwrap();
+
+        let cursor = first.last().map(|last| (last.last_update, last.id));
+        assert_eq!(
+            p.get_all_deployments_after(cursor, 3).await.unwrap(),
+            deployments[3..6]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_active_limit() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let p = p.with_max_active_deployments(2);
+        let service_id = add_service(&p.pool).await.unwrap();
+        let other_service_id = add_service(&p.pool).await.unwrap();
+
+        let deployment = |service_id, state| {
+            Deployment::builder(service_id)
+                .state(state)
+                .last_update(Utc::now())
+                .build()
+        };
+
+        for state in [State::Queued, State::Running, State::Stopped] {
+            p.insert_deployment(deployment(service_id, state))
+                .await
+                .unwrap();
+        }
+
+        assert!(matches!(
+            p.insert_deployment(deployment(service_id, State::Queued))
+                .await,
+            Err(Error::TooManyActiveDeployments(2))
+        ));
+        assert_eq!(p.get_deployments(&service_id).await.unwrap().len(), 3);
+
+        // Finished deployments do not count towards the limit
+        p.insert_deployment(deployment(service_id, State::Crashed))
+            .await
+            .unwrap();
+        p.insert_deployment(deployment(other_service_id, State::Queued))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_summaries() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let other_service_id = add_service(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap();
+
+        let running = Deployment::builder(service_id)
+            .state(State::Running)
+            .last_update(start + Duration::seconds(1))
+            .address(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345))
+            .build();
+        let stopped = Deployment::builder(service_id)
+            .state(State::Stopped)
+            .last_update(start)
+            .build();
+        for deployment in [&running, &stopped] {
+            p.insert_deployment(deployment.clone()).await.unwrap();
+        }
+        p.insert_deployment(Deployment::builder(other_service_id))
+            .await
+            .unwrap();
+
+        let summaries = p.get_deployment_summaries(&service_id).await.unwrap();
+        let expected: Vec<_> = [&stopped, &running]
+            .into_iter()
+            .map(|deployment| DeploymentSummary {
+                id: deployment.id,
+                state: deployment.state,
+                last_update: deployment.last_update,
+                has_address: deployment.address.is_some(),
+            })
+            .collect();
+        assert_eq!(summaries, expected);
+        assert_eq!(
+            serde_json::to_value(&summaries[1]).unwrap(),
+            json!({
+                "id": running.id,
+                "state": "Running",
+                "last_update": "2022-04-25T04:43:34Z",
+                "has_address": true,
+            })
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployments_double_running() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let healthy_service_id = add_service(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap();
+
+        let deployments: Vec<_> = [
+            (service_id, State::Running),
+            (service_id, State::Running),
+            (service_id, State::Stopped),
+            (service_id, State::Running),
+            (healthy_service_id, State::Running),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (service_id, state))| {
+            Deployment::builder(service_id)
+                .state(state)
+                .last_update(start + Duration::seconds(i as i64))
+                .build()
+        })
+        .collect();
+        for deployment in deployments.iter() {
+            p.insert_deployment(deployment.clone()).await.unwrap();
+        }
+
+        assert_eq!(
+            p.get_services_with_multiple_running().await.unwrap(),
+            vec![(
+                service_id,
+                vec![deployments[3].id, deployments[1].id, deployments[0].id]
+            )]
+        );
+
+        assert_eq!(p.resolve_double_running(&service_id).await.unwrap(), 2);
+        assert!(p
+            .get_services_with_multiple_running()
+            .await
+            .unwrap()
+            .is_empty());
+
+        let running: Vec<_> = p
+            .get_deployments(&service_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|deployment| deployment.state == State::Running)
+            .map(|deployment| deployment.id)
+            .collect();
+        assert_eq!(running, vec![deployments[3].id]);
+
+        assert_eq!(
+            p.resolve_double_running(&healthy_service_id).await.unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployments_stop_all() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let other_service_id = add_service(&p.pool).await.unwrap();
+        let address = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345);
+
+        let deployments: Vec<_> = [
+            (service_id, State::Running),
+            (service_id, State::Running),
+            (service_id, State::Crashed),
+            (other_service_id, State::Running),
+        ]
+        .into_iter()
+        .map(|(service_id, state)| {
+            Deployment::builder(service_id)
+                .state(state)
+                .address(address)
+                .build()
+        })
+        .collect();
+        for deployment in deployments.iter() {
+            p.insert_deployment(deployment.clone()).await.unwrap();
+        }
+
+        assert_eq!(p.stop_all_deployments(&service_id).await.unwrap(), 2);
+
+        let mut stored = Vec::new();
+        for deployment in deployments.iter() {
+            let deployment = p.get_deployment(&deployment.id).await.unwrap().unwrap();
+            stored.push((deployment.state, deployment.address));
+        }
+        assert_eq!(
+            stored,
+            vec![
+                (State::Stopped, None),
+                (State::Stopped, None),
+                (State::Crashed, Some(address)),
+                (State::Running, Some(address)),
+            ]
+        );
+
+        assert_eq!(p.stop_all_deployments(&service_id).await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_stale_addresses() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap();
+        let address = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345);
+
+        let deployments: Vec<_> = [
+            (State::Crashed, Some(address)),
+            (State::Running, Some(address)),
+            (State::Stopped, None),
+            (State::Stopped, Some(address)),
+            (State::Completed, Some(address)),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (state, address))| {
+            let mut builder = Deployment::builder(service_id)
+                .state(state)
+                .last_update(start + Duration::seconds(i as i64));
+            if let Some(address) = address {
+                builder = builder.address(address);
+            }
+            builder.build()
+        })
+        .collect();
+        for deployment in deployments.iter() {
+            p.insert_deployment(deployment.clone()).await.unwrap();
+        }
+
+        assert_eq!(
+            p.get_deployments_with_stale_address().await.unwrap(),
+            vec![
+                deployments[0].clone(),
+                deployments[3].clone(),
+                deployments[4].clone()
+            ]
+        );
+
+        assert_eq!(p.clear_stale_addresses().await.unwrap(), 3);
+        assert!(p
+            .get_deployments_with_stale_address()
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            p.get_deployment(&deployments[0].id)
+                .await
+                .unwrap()
+                .unwrap()
+                .address,
+            None
+        );
+        assert_eq!(
+            p.get_deployment(&deployments[1].id)
+                .await
+                .unwrap()
+                .unwrap()
+                .address,
+            Some(address),
+            "running deployments should keep their address"
+        );
+        assert_eq!(p.clear_stale_addresses().await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_labels() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap();
+
+        let deployments: Vec<_> = (0..3)
+            .map(|i| {
+                Deployment::builder(service_id)
+                    .last_update(start + Duration::minutes(i))
+                    .build()
+            })
+            .collect();
+        for deployment in deployments.iter() {
+            p.insert_deployment(deployment.clone()).await.unwrap();
+        }
+
+        for (deployment, branch) in deployments.iter().zip(["main", "feature", "main"]) {
+            p.set_deployment_label(&deployment.id, "branch", branch)
+                .await
+                .unwrap();
+        }
+        p.set_deployment_label(&deployments[0].id, "git_sha", "1234abc")
+            .await
+            .unwrap();
+        p.set_deployment_label(&deployments[0].id, "git_sha", "5678def")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            p.get_deployment_labels(&deployments[0].id).await.unwrap(),
+            BTreeMap::from([
+                ("branch".to_string(), "main".to_string()),
+                ("git_sha".to_string(), "5678def".to_string()),
+            ])
+        );
+        assert!(p
+            .get_deployment_labels(&Uuid::new_v4())
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            p.find_deployments_by_label("branch", "main").await.unwrap(),
+            vec![deployments[0].clone(), deployments[2].clone()]
+        );
+        assert!(p
+            .find_deployments_by_label("git_sha", "1234abc")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_reconcile_state() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap();
+        let deployment = Deployment::builder(service_id)
+            .state(State::Loading)
+            .last_update(start)
+            .build();
+        p.insert_deployment(deployment.clone()).await.unwrap();
+
+        assert_eq!(
+            p.reconcile_deployment_state(&deployment.id).await.unwrap(),
+            State::Loading,
+            "nothing to reconcile without state logs"
+        );
+
+        let state_log = |i, state| Log {
+            id: deployment.id,
+            timestamp: start + Duration::seconds(i),
+            state,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields: json!(STATE_MESSAGE),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+        insert_log(&p.pool, state_log(0, State::Loading))
+            .await
+            .unwrap();
+        insert_log(&p.pool, state_log(5, State::Running))
+            .await
+            .unwrap();
+        insert_log(
+            &p.pool,
+            Log {
+                fields: json!({ "message": "listening" }),
+                ..state_log(6, State::Running)
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            p.reconcile_deployment_state(&deployment.id).await.unwrap(),
+            State::Running
+        );
+        let reconciled = p.get_deployment(&deployment.id).await.unwrap().unwrap();
+        assert_eq!(reconciled.state, State::Running);
+        assert_eq!(reconciled.last_update, start + Duration::seconds(5));
+        assert_eq!(reconciled.running_since, Some(start + Duration::seconds(5)));
+
+        // Changes stored without a state log should not be undone
+        p.stop_all_deployments(&service_id).await.unwrap();
+        assert_eq!(
+            p.reconcile_deployment_state(&deployment.id).await.unwrap(),
+            State::Stopped
+        );
+        assert_eq!(
+            p.get_deployment(&deployment.id)
+                .await
+                .unwrap()
+                .unwrap()
+                .state,
+            State::Stopped
+        );
+
+        let building = Deployment::builder(service_id)
+            .state(State::Building)
+            .last_update(start)
+            .build();
+        p.insert_deployment(building.clone()).await.unwrap();
+        insert_log(
+            &p.pool,
+            Log {
+                id: building.id,
+                ..state_log(1, State::Building)
+            },
+        )
+        .await
+        .unwrap();
+        p.cleanup_invalid_states().await.unwrap();
+        assert_eq!(
+            p.reconcile_deployment_state(&building.id).await.unwrap(),
+            State::Stopped
+        );
+
+        assert!(matches!(
+            p.reconcile_deployment_state(&Uuid::new_v4()).await,
+            Err(Error::DeploymentNotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn startup_recovery() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap();
+
+        let deployment = |state| {
+            Deployment::builder(service_id)
+                .state(state)
+                .last_update(start)
+                .build()
+        };
+        let state_log = |id, state| Log {
+            id,
+            timestamp: start + Duration::seconds(1),
+            state,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields: json!(STATE_MESSAGE),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+
+        // Left building when the deployer died
+        let building = deployment(State::Building);
+        // Logged as running, but the deployer died before storing it
+        let unstored_running = deployment(State::Loading);
+        // Logged as building, but stored as built
+        let unstored_building = deployment(State::Built);
+        // Nothing wrong with it
+        let running = deployment(State::Running);
+        let stopped = deployment(State::Stopped);
+
+        for deployment in [
+            &building,
+            &unstored_running,
+            &unstored_building,
+            &running,
+            &stopped,
+        ] {
+            p.insert_deployment(deployment.clone()).await.unwrap();
+        }
+        for (id, state) in [
+            (unstored_running.id, State::Running),
+            (unstored_building.id, State::Building),
+            (running.id, State::Running),
+        ] {
+            insert_log(&p.pool, state_log(id, state)).await.unwrap();
+        }
+
+        let report = p.recover_on_startup().await.unwrap();
+        assert_eq!(report.reconciled, 2);
+        assert_eq!(report.reset, 2, "building deployments should be reset");
+        assert_eq!(
+            report
+                .runnable
+                .iter()
+                .map(|runnable| runnable.id)
+                .collect::<HashSet<_>>(),
+            HashSet::from([unstored_running.id, running.id])
+        );
+
+        let state = |id| {
+            let p = &p;
+            async move { p.get_deployment(&id).await.unwrap().unwrap().state }
+        };
+        assert_eq!(state(building.id).await, State::Stopped);
+        assert_eq!(state(unstored_running.id).await, State::Running);
+        assert_eq!(state(unstored_building.id).await, State::Stopped);
+        assert_eq!(state(running.id).await, State::Running);
+        assert_eq!(state(stopped.id).await, State::Stopped);
+
+        let report = p.recover_on_startup().await.unwrap();
+        assert_eq!(
+            (report.reconciled, report.reset),
+            (0, 0),
+            "recovering again should not change anything"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_uptime() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let id = Uuid::new_v4();
+        p.insert_deployment(Deployment {
+            id,
+            service_id: add_service(&p.pool).await.unwrap(),
+            state: State::Built,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        })
+        .await
+        .unwrap();
+        let state = |state| DeploymentState {
+            id,
+            state,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+        };
+
+        p.update_deployment_state(state(State::Loading))
+            .await
+            .unwrap();
+        assert_eq!(p.deployment_uptime(&id).await.unwrap(), None);
+
+        p.update_deployment_state(state(State::Running))
+            .await
+            .unwrap();
+        let running_since = p.get_deployment(&id).await.unwrap().unwrap().running_since;
+        assert!(running_since.is_some());
+
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(p.deployment_uptime(&id).await.unwrap().unwrap() > Duration::zero());
+
+        // Staying in the running state should not restart the uptime
+        p.update_deployment_state(state(State::Running))
+            .await
+            .unwrap();
+        assert_eq!(
+            p.get_deployment(&id).await.unwrap().unwrap().running_since,
+            running_since
+        );
+
+        p.update_deployment_state(state(State::Stopped))
+            .await
+            .unwrap();
+        assert_eq!(p.deployment_uptime(&id).await.unwrap(), None);
+        assert!(matches!(
+            p.deployment_uptime(&Uuid::new_v4()).await,
+            Err(Error::DeploymentNotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_update_conflict() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        let read_at = Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap();
+        let deployment = Deployment {
+            id: Uuid::new_v4(),
+            service_id,
+            state: State::Running,
+            last_update: read_at,
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+        p.insert_deployment(deployment.clone()).await.unwrap();
+
+        let stopped_at = Utc.with_ymd_and_hms(2022, 4, 25, 4, 44, 0).unwrap();
+        p.update_deployment_state_if_unchanged(
+            DeploymentState {
+                id: deployment.id,
+                state: State::Stopped,
+                last_update: stopped_at,
+                address: None,
+                error_message: None,
+            },
+            read_at,
+        )
+        .await
+        .unwrap();
+
+        // This crash was based on the deployment as it was before it stopped
+        let result = p
+            .update_deployment_state_if_unchanged(
+                DeploymentState {
+                    id: deployment.id,
+                    state: State::Crashed,
+                    last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 45, 0).unwrap(),
+                    address: None,
+                    error_message: Some("panicked".to_string()),
+                },
+                read_at,
+            )
+            .await;
+
+        assert!(matches!(result, Err(Error::UpdateConflict)));
+
+        let stored = p.get_deployment(&deployment.id).await.unwrap().unwrap();
+        assert_eq!(stored.state, State::Stopped);
+        assert_eq!(stored.last_update, stopped_at);
+
+        let result = p
+            .update_deployment_state_if_unchanged(
+                DeploymentState {
+                    id: Uuid::new_v4(),
+                    state: State::Crashed,
+                    last_update: Utc::now(),
+                    address: None,
+                    error_message: None,
+                },
+                read_at,
+            )
+            .await;
+
+        assert!(matches!(result, Err(Error::DeploymentNotFound)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_update_missing() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let id = add_deployment(&p.pool).await.unwrap();
+
+        let state = |id| DeploymentState {
+            id,
+            state: State::Stopped,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+        };
+
+        assert_eq!(p.update_deployment_state(state(id)).await.unwrap(), 1);
+        assert!(matches!(
+            p.update_deployment_state(state(Uuid::new_v4())).await,
+            Err(Error::DeploymentNotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_update_invalid_transition() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let id = add_deployment(&p.pool).await.unwrap();
+
+        let state = |state| DeploymentState {
+            id,
+            state,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+        };
+
+        p.update_deployment_state(state(State::Stopped))
+            .await
+            .unwrap();
+        assert!(matches!(
+            p.update_deployment_state(state(State::Building)).await,
+            Err(Error::InvalidTransition {
+                from: State::Stopped,
+                to: State::Building
+            })
+        ));
+        assert_eq!(
+            p.get_deployment(&id).await.unwrap().unwrap().state,
+            State::Stopped
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_with_service() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service_named(&p.pool, "joined-service").await.unwrap();
+        let deployment = Deployment {
+            id: Uuid::new_v4(),
+            service_id,
+            state: State::Running,
+            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap(),
+            address: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9876)),
+            error_message: None,
+            running_since: None,
+        };
+        p.insert_deployment(deployment.clone()).await.unwrap();
+
+        assert_eq!(
+            p.get_deployment_with_service(&deployment.id).await.unwrap(),
+            Some((
+                deployment,
+                Service {
+                    id: service_id,
+                    name: "joined-service".to_string()
+                }
+            ))
+        );
+        assert_eq!(
+            p.get_deployment_with_service(&Uuid::new_v4())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn service_for_deployment() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service_named(&p.pool, "owning-service").await.unwrap();
+        add_service_named(&p.pool, "other-service").await.unwrap();
+        let id = Uuid::new_v4();
+        p.insert_deployment(Deployment {
+            id,
+            service_id,
+            state: State::Queued,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            p.get_service_for_deployment(&id).await.unwrap(),
+            Some(Service {
+                id: service_id,
+                name: "owning-service".to_string()
+            })
+        );
+        assert_eq!(
+            p.get_service_for_deployment(&Uuid::new_v4()).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn service_rename() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let id = add_deployment(&p.pool).await.unwrap();
+        let service_id = p.get_deployment(&id).await.unwrap().unwrap().service_id;
+
+        let renamed = p.rename_service(&service_id, "renamed").await.unwrap();
+        assert_eq!(
+            renamed,
+            Service {
+                id: service_id,
+                name: "renamed".to_string()
+            }
+        );
+        assert_eq!(
+            p.get_service_by_name("renamed").await.unwrap(),
+            Some(renamed.clone())
+        );
+        assert_eq!(
+            p.get_deployment(&id).await.unwrap().unwrap().service_id,
+            service_id,
+            "deployments should move with the service"
+        );
+
+        // Renaming to the current name is a no-op
+        assert_eq!(
+            p.rename_service(&service_id, "renamed").await.unwrap(),
+            renamed
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn service_rename_collision() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service_named(&p.pool, "first").await.unwrap();
+        add_service_named(&p.pool, "second").await.unwrap();
+
+        assert!(matches!(
+            p.rename_service(&service_id, "second").await,
+            Err(Error::ServiceNameTaken(name)) if name == "second"
+        ));
+        assert_eq!(
+            p.get_service_by_id(&service_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .name,
+            "first"
+        );
+        assert!(matches!(
+            p.rename_service(&Uuid::new_v4(), "third").await,
+            Err(Error::ServiceNotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn service_cache() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let p = p.with_service_cache(10);
+        let service = p.get_or_create_service("cached").await.unwrap();
+
+        // Removing the row behind the cache's back shows whether reads still go to the db
+        sqlx::query("DELETE FROM services WHERE id = ?")
+            .bind(service.id)
+            .execute(&p.pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            p.get_service_by_name("cached").await.unwrap(),
+            Some(service.clone()),
+            "reads should come from the cache"
+        );
+
+        p.delete_service(&service.id).await.unwrap();
+        assert_eq!(p.get_service_by_name("cached").await.unwrap(), None);
+
+        // Renaming should not leave the old name behind
+        let service = p.get_or_create_service("cached").await.unwrap();
+        p.rename_service(&service.id, "renamed").await.unwrap();
+        assert_eq!(p.get_service_by_name("cached").await.unwrap(), None);
+        assert_eq!(
+            p.get_service_by_name("renamed").await.unwrap().unwrap().id,
+            service.id
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_for_user() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let owner = Uuid::new_v4();
+        let other = Uuid::new_v4();
+
+        let id = add_deployment(&p.pool).await.unwrap();
+        let deployment = p.get_deployment(&id).await.unwrap().unwrap();
+        p.set_service_owner(&deployment.service_id, &owner)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            p.get_deployment_for_user(&id, &owner).await.unwrap(),
+            Some(deployment.clone())
+        );
+        assert_eq!(p.get_deployment_for_user(&id, &other).await.unwrap(), None);
+
+        assert_eq!(
+            p.get_deployments_for_user(&deployment.service_id, &owner)
+                .await
+                .unwrap(),
+            vec![deployment.clone()]
+        );
+        assert!(p
+            .get_deployments_for_user(&deployment.service_id, &other)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Services without an owner are not visible to any user
+        let unowned = add_deployment(&p.pool).await.unwrap();
+        assert_eq!(
+            p.get_deployment_for_user(&unowned, &owner).await.unwrap(),
+            None
+        );
+
+        assert!(matches!(
+            p.set_service_owner(&Uuid::new_v4(), &owner).await,
+            Err(Error::ServiceNotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_active() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let xyz_id = add_service(&p.pool).await.unwrap();
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        let deployment_crashed = Deployment {
+            id: Uuid::new_v4(),
+            service_id: xyz_id,
+            state: State::Crashed,
+            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 7, 29, 35).unwrap(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+        let deployment_stopped = Deployment {
+            id: Uuid::new_v4(),
+            service_id: xyz_id,
+            state: State::Stopped,
+            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 7, 49, 35).unwrap(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+        let deployment_other = Deployment {
+            id: Uuid::new_v4(),
+            service_id,
+            state: State::Running,
+            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 7, 39, 39).unwrap(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+        let deployment_running = Deployment {
+            id: Uuid::new_v4(),
+            service_id: xyz_id,
+            state: State::Running,
+            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 7, 48, 29).unwrap(),
+            address: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9876)),
+            error_message: None,
+            running_since: None,
+        };
+
+        for deployment in [
+            &deployment_crashed,
+            &deployment_stopped,
+            &deployment_other,
+            &deployment_running,
+        ] {
+            p.insert_deployment(deployment.clone()).await.unwrap();
+        }
+
+        assert_eq!(
+            p.get_active_deployment(&xyz_id).await.unwrap().unwrap(),
+            deployment_running
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_promotion() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let other_service_id = add_service(&p.pool).await.unwrap();
+
+        let deployments: Vec<_> = [
+            (service_id, State::Stopped),
+            (service_id, State::Running),
+            (service_id, State::Completed),
+            (other_service_id, State::Running),
+        ]
+        .into_iter()
+        .map(|(service_id, state)| Deployment::builder(service_id).state(state).build())
+        .collect();
+        for deployment in deployments.iter() {
+            p.insert_deployment(deployment.clone()).await.unwrap();
+        }
+
+        assert_eq!(p.get_promoted_deployment(&service_id).await.unwrap(), None);
+
+        p.promote_deployment(&deployments[0].id).await.unwrap();
+        p.promote_deployment(&deployments[3].id).await.unwrap();
+        assert_eq!(
+            p.get_promoted_deployment(&service_id).await.unwrap(),
+            Some(deployments[0].clone())
+        );
+
+        // Promoting another deployment takes the promotion away from the first
+        p.promote_deployment(&deployments[2].id).await.unwrap();
+        assert_eq!(
+            p.get_promoted_deployment(&service_id).await.unwrap(),
+            Some(deployments[2].clone())
+        );
+        let (promoted,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM deployments WHERE service_id = ? AND is_promoted")
+                .bind(service_id)
+                .fetch_one(&p.pool)
+                .await
+                .unwrap();
+        assert_eq!(promoted, 1);
+
+        assert_eq!(
+            p.get_active_deployment(&service_id).await.unwrap(),
+            Some(deployments[1].clone())
+        );
+        assert_eq!(
+            p.get_promoted_deployment(&other_service_id).await.unwrap(),
+            Some(deployments[3].clone())
+        );
+        assert!(matches!(
+            p.promote_deployment(&Uuid::new_v4()).await,
+            Err(Error::DeploymentNotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn services_with_running_deployments() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let running_id = add_service_named(&p.pool, "running").await.unwrap();
+        let crashed_id = add_service_named(&p.pool, "crashed").await.unwrap();
+        let mixed_id = add_service_named(&p.pool, "mixed").await.unwrap();
+        add_service_named(&p.pool, "empty").await.unwrap();
+
+        for (service_id, state) in [
+            (running_id, State::Running),
+            (running_id, State::Running),
+            (crashed_id, State::Crashed),
+            (crashed_id, State::Stopped),
+            (mixed_id, State::Stopped),
+            (mixed_id, State::Running),
+        ] {
+            p.insert_deployment(Deployment {
+                id: Uuid::new_v4(),
+                service_id,
+                state,
+                last_update: Utc::now(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        let mut services: Vec<_> = p
+            .get_services_with_running_deployments()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|service| service.name)
+            .collect();
+        services.sort();
+
+        assert_eq!(services, vec!["mixed", "running"]);
+    }
+
+    // Test that we are correctly cleaning up any stale / unexpected states for a deployment
+    // The reason this does not clean up two (or more) running states for a single deployment is because
+    // it should theoretically be impossible for a service to have two deployments in the running state.
+    // And even if a service where to have this, then the start ups of these deployments (more specifically
+    // the last deployment that is starting up) will stop all the deployments correctly.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cleanup_invalid_states() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        let queued_id = Uuid::new_v4();
+        let building_id = Uuid::new_v4();
+        let built_id = Uuid::new_v4();
+        let loading_id = Uuid::new_v4();
+        let stopping_id = Uuid::new_v4();
+
+        let deployment_crashed = Deployment {
+            id: Uuid::new_v4(),
+            service_id,
+            state: State::Crashed,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+        let deployment_stopped = Deployment {
+            id: Uuid::new_v4(),
+            service_id,
+            state: State::Stopped,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+        let deployment_running = Deployment {
+            id: Uuid::new_v4(),
+            service_id,
+            state: State::Running,
+            last_update: Utc::now(),
+            address: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9876)),
+            error_message: None,
+            running_since: None,
+        };
+        let deployment_queued = Deployment {
+            id: queued_id,
+            service_id,
+            state: State::Queued,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+        let deployment_building = Deployment {
+            id: building_id,
+            service_id,
+            state: State::Building,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+        let deployment_built = Deployment {
+            id: built_id,
+            service_id,
+            state: State::Built,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+        let deployment_loading = Deployment {
+            id: loading_id,
+            service_id,
+            state: State::Loading,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+        let deployment_stopping = Deployment {
+            id: stopping_id,
+            service_id,
+            state: State::Stopping,
+            last_update: Utc::now(),
+            address: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9877)),
+            error_message: None,
+            running_since: None,
+        };
+
+        for deployment in [
+            &deployment_crashed,
+            &deployment_stopped,
+            &deployment_running,
+            &deployment_queued,
+            &deployment_built,
+            &deployment_building,
+            &deployment_loading,
+            &deployment_stopping,
+        ] {
+            p.insert_deployment(deployment.clone()).await.unwrap();
+        }
+
+        p.cleanup_invalid_states().await.unwrap();
+
+        let actual: Vec<_> = p
+            .get_deployments(&service_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|deployment| (deployment.id, deployment.state))
+            .collect();
+        let expected = vec![
+            (deployment_crashed.id, State::Crashed),
+            (deployment_stopped.id, State::Stopped),
+            (deployment_running.id, State::Running),
+            (queued_id, State::Stopped),
+            (built_id, State::Stopped),
+            (building_id, State::Stopped),
+            (loading_id, State::Stopped),
+            (stopping_id, State::Stopped),
+        ];
+
+        assert_eq!(
+            actual, expected,
+            "invalid states should be moved to the stopped state"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cleanup_invalid_states_dry_run() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        for state in State::iter() {
+            p.insert_deployment(Deployment {
+                id: Uuid::new_v4(),
+                service_id,
+                state,
+                last_update: Utc::now(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        let states = |deployments: Vec<Deployment>| -> Vec<_> {
+            deployments
+                .into_iter()
+                .map(|deployment| (deployment.id, deployment.state))
+                .collect()
+        };
+        let before = states(p.get_deployments(&service_id).await.unwrap());
+
+        let mut dry_run = p.cleanup_invalid_states_dry_run().await.unwrap();
+        assert_eq!(
+            states(p.get_deployments(&service_id).await.unwrap()),
+            before,
+            "a dry run should not change anything"
+        );
+
+        p.cleanup_invalid_states().await.unwrap();
+        let mut changed: Vec<_> = states(p.get_deployments(&service_id).await.unwrap())
+            .into_iter()
+            .filter(|after| !before.contains(after))
+            .collect();
+
+        dry_run.sort_by_key(|(id, _)| *id);
+        changed.sort_by_key(|(id, _)| *id);
+        assert_eq!(dry_run.len(), 5);
+        assert_eq!(dry_run, changed);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cleanup_invalid_states_for_service() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let service_id = add_service(&p.pool).await.unwrap();
+        let other_service_id = add_service(&p.pool).await.unwrap();
+
+        let wedged_id = Uuid::new_v4();
+        let other_wedged_id = Uuid::new_v4();
+
+        for (id, service_id) in [(wedged_id, service_id), (other_wedged_id, other_service_id)] {
+            p.insert_deployment(Deployment {
+                id,
+                service_id,
+                state: State::Building,
+                last_update: Utc::now(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        p.cleanup_invalid_states_for_service(&service_id)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            p.get_deployment(&wedged_id).await.unwrap().unwrap().state,
+            State::Stopped,
+            "invalid state of the cleaned service should be moved to the stopped state"
+        );
+        assert_eq!(
+            p.get_deployment(&other_wedged_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .state,
+            State::Building,
+            "other services should not be touched"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn prune_deployments() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let other_service_id = add_service(&p.pool).await.unwrap();
+
+        let deployment = |service_id, state, hour| Deployment {
+            id: Uuid::new_v4(),
+            service_id,
+            state,
+            last_update: Utc.with_ymd_and_hms(2022, 4, 25, hour, 0, 0).unwrap(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+
+        let old_crashed = deployment(service_id, State::Crashed, 1);
+        let old_stopped = deployment(service_id, State::Stopped, 2);
+        let old_running = deployment(service_id, State::Running, 3);
+        let kept_completed = deployment(service_id, State::Completed, 4);
+        let new_stopped = deployment(service_id, State::Stopped, 10);
+        let new_crashed = deployment(service_id, State::Crashed, 11);
+        let other_old_stopped = deployment(other_service_id, State::Stopped, 1);
+
+        for deployment in [
+            &old_crashed,
+            &old_stopped,
+            &old_running,
+            &kept_completed,
+            &new_stopped,
+            &new_crashed,
+            &other_old_stopped,
+        ] {
+            p.insert_deployment(deployment.clone()).await.unwrap();
+        }
+
+        insert_log(
+            &p.pool,
+            Log {
+                id: old_crashed.id,
+                timestamp: old_crashed.last_update,
+                state: State::Crashed,
+                level: Level::Error,
+                file: None,
+                line: None,
+                target: String::new(),
+                fields: json!({"message": "panicked"}),
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                span_fields: None,
+                duration_ms: None,
+                repeat_count: 1,
+            },
+        )
+        .await
+        .unwrap();
+
+        let cutoff = Utc.with_ymd_and_hms(2022, 4, 25, 8, 0, 0).unwrap();
+
+        // The three most recent deployments of each service are kept
+        assert_eq!(p.prune_deployments(3, cutoff).await.unwrap(), 2);
+
+        let mut remaining = p.get_deployments(&service_id).await.unwrap();
+        remaining.sort_by_key(|deployment| deployment.last_update);
+        assert_eq!(
+            remaining,
+            vec![
+                old_running,
+                kept_completed.clone(),
+                new_stopped,
+                new_crashed
+            ]
+        );
+        assert_eq!(
+            p.get_deployments(&other_service_id).await.unwrap(),
+            vec![other_old_stopped]
+        );
+        assert!(p
+            .get_deployment_logs(&old_crashed.id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Nothing newer than the cutoff is pruned even when it is not being kept
+        assert_eq!(p.prune_deployments(0, cutoff).await.unwrap(), 2);
+        assert_eq!(p.get_deployments(&service_id).await.unwrap().len(), 3);
+        assert!(p
+            .get_deployment(&kept_completed.id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fetching_runnable_deployments() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let bar_id = add_service_named(&p.pool, "bar").await.unwrap();
+        let foo_id = add_service_named(&p.pool, "foo").await.unwrap();
+        let service_id = add_service(&p.pool).await.unwrap();
+        let service_id2 = add_service(&p.pool).await.unwrap();
+
+        let id_1 = Uuid::new_v4();
+        let id_2 = Uuid::new_v4();
+        let id_3 = Uuid::new_v4();
+
+        for deployment in [
+            Deployment {
+                id: Uuid::new_v4(),
+                service_id,
+                state: State::Built,
+                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 29, 33).unwrap(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            },
+            Deployment {
+                id: id_1,
+                service_id: foo_id,
+                state: State::Running,
+                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 29, 44).unwrap(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            },
+            Deployment {
+                id: id_2,
+                service_id: bar_id,
+                state: State::Running,
+                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 33, 48).unwrap(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            },
+            Deployment {
+                id: Uuid::new_v4(),
+                service_id: service_id2,
+                state: State::Crashed,
+                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 38, 52).unwrap(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            },
+            Deployment {
+                id: id_3,
+                service_id: foo_id,
+                state: State::Running,
+                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 42, 32).unwrap(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            },
+        ] {
+            p.insert_deployment(deployment).await.unwrap();
+        }
+
+        let runnable = p.get_all_runnable_deployments().await.unwrap();
+        assert_eq!(
+            runnable,
+            [
+                DeploymentRunnable {
+                    id: id_1,
+                    service_name: "foo".to_string(),
+                    service_id: foo_id,
+                },
+                DeploymentRunnable {
+                    id: id_2,
+                    service_name: "bar".to_string(),
+                    service_id: bar_id,
+                },
+                DeploymentRunnable {
+                    id: id_3,
+                    service_name: "foo".to_string(),
+                    service_id: foo_id,
+                },
+            ]
+        );
+
+        let grouped = p.get_runnable_deployments_grouped().await.unwrap();
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(
+            grouped[&foo_id],
+            [
+                DeploymentRunnable {
+                    id: id_1,
+                    service_name: "foo".to_string(),
+                    service_id: foo_id,
+                },
+                DeploymentRunnable {
+                    id: id_3,
+                    service_name: "foo".to_string(),
+                    service_id: foo_id,
+                },
+            ]
+        );
+        assert_eq!(
+            grouped[&bar_id],
+            [DeploymentRunnable {
+                id: id_2,
+                service_name: "bar".to_string(),
+                service_id: bar_id,
+            }]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_ids() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let other_service_id = add_service(&p.pool).await.unwrap();
+
+        for (service_id, minute) in [
+            (service_id, 10),
+            (service_id, 30),
+            (other_service_id, 40),
+            (service_id, 20),
+        ] {
+            p.insert_deployment(Deployment {
+                id: Uuid::new_v4(),
+                service_id,
+                state: State::Stopped,
+                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, minute, 0).unwrap(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        let mut deployments = p.get_deployments(&service_id).await.unwrap();
+        deployments.sort_by_key(|deployment| std::cmp::Reverse(deployment.last_update));
+        let expected: Vec<_> = deployments
+            .into_iter()
+            .map(|deployment| deployment.id)
+            .collect();
+
+        assert_eq!(expected.len(), 3);
+        assert_eq!(p.get_deployment_ids(&service_id).await.unwrap(), expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fetching_runnable_deployments_for_service() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let bar_id = add_service_named(&p.pool, "bar").await.unwrap();
+        let foo_id = add_service_named(&p.pool, "foo").await.unwrap();
+
+        let foo_running_id = Uuid::new_v4();
+        let foo_later_running_id = Uuid::new_v4();
+
+        for deployment in [
+            Deployment {
+                id: foo_later_running_id,
+                service_id: foo_id,
+                state: State::Running,
+                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 42, 32).unwrap(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            },
+            Deployment {
+                id: Uuid::new_v4(),
+                service_id: bar_id,
+                state: State::Running,
+                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 33, 48).unwrap(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            },
+            Deployment {
+                id: foo_running_id,
+                service_id: foo_id,
+                state: State::Running,
+                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 29, 44).unwrap(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            },
+            Deployment {
+                id: Uuid::new_v4(),
+                service_id: foo_id,
+                state: State::Stopped,
+                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 30, 1).unwrap(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            },
+        ] {
+            p.insert_deployment(deployment).await.unwrap();
+        }
+
+        let runnable = p.get_runnable_deployments_for_service("foo").await.unwrap();
+        assert_eq!(
+            runnable,
+            [
+                DeploymentRunnable {
+                    id: foo_running_id,
+                    service_name: "foo".to_string(),
+                    service_id: foo_id,
+                },
+                DeploymentRunnable {
+                    id: foo_later_running_id,
+                    service_name: "foo".to_string(),
+                    service_id: foo_id,
+                },
+            ]
+        );
+
+        assert!(p
+            .get_runnable_deployments_for_service("baz")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn log_insert() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+
+        let log = Log {
+            id: deployment_id,
+            timestamp: Utc::now(),
+            state: State::Queued,
+            level: Level::Info,
+            file: Some("queue.rs".to_string()),
+            line: Some(12),
+            target: "tests::log_insert".to_string(),
+            fields: json!({"message": "job queued"}),
+            trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".to_string()),
+            span_id: Some("00f067aa0ba902b7".to_string()),
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+
+        insert_log(&p.pool, log.clone()).await.unwrap();
+
+        let logs = p.get_deployment_logs(&deployment_id).await.unwrap();
+        assert!(!logs.is_empty(), "there should be one log");
+
+        assert_eq!(logs.first().unwrap(), &log);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn metrics_counters() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        recorder.install().unwrap();
+
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let deployment = Deployment {
+            id: Uuid::new_v4(),
+            service_id,
+            state: State::Queued,
+            last_update: Utc::now(),
+            address: None,
+            error_message: None,
+            running_since: None,
+        };
+
+        p.insert_deployment(deployment.clone()).await.unwrap();
+        p.update_deployment_state(DeploymentState {
+            id: deployment.id,
+            state: State::Building,
+            last_update: Utc::now(),
             address: None,
+            error_message: None,
+        })
+        .await
+        .unwrap();
+        insert_log(
+            &p.pool,
+            Log {
+                id: deployment.id,
+                timestamp: Utc::now(),
+                state: State::Building,
+                level: Level::Info,
+                file: None,
+                line: None,
+                target: "tests::metrics_counters".to_string(),
+                fields: json!({"message": "building"}),
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                span_fields: None,
+                duration_ms: None,
+                repeat_count: 1,
+            },
+        )
+        .await
+        .unwrap();
+        assert!(p.insert_deployment(deployment).await.is_err());
+
+        let snapshot = snapshotter.snapshot().into_vec();
+        let value = |name: &str| {
+            snapshot
+                .iter()
+                .filter(|(key, _, _, _)| key.key().name() == name)
+                .map(|(_, _, _, value)| match value {
+                    DebugValue::Counter(count) => *count,
+                    DebugValue::Histogram(values) => values.len() as u64,
+                    DebugValue::Gauge(_) => 0,
+                })
+                .sum::<u64>()
         };
-        let deployment_stopped = Deployment {
-            id: Uuid::new_v4(),
-            service_id,
-            state: State::Stopped,
-            last_update: Utc::now(),
-            address: None,
+
+        assert!(value(super::metrics::DEPLOYMENTS_INSERTED) >= 1);
+        assert!(value(super::metrics::STATE_UPDATES) >= 1);
+        assert!(value(super::metrics::LOGS_INSERTED) >= 1);
+        assert!(value(super::metrics::INSERT_LOG_SECONDS) >= 1);
+        assert!(value(super::metrics::QUERY_ERRORS) >= 1);
+    }
+
+    #[tokio::test]
+    async fn query_spans() {
+        use std::sync::Mutex;
+
+        use tracing::{
+            field::{Field, Visit},
+            instrument::WithSubscriber,
+            span, Subscriber,
+        };
+        use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+        /// Keeps the fields of each span by the span's name
+        #[derive(Clone, Default)]
+        struct SpanRecorder(Arc<Mutex<HashMap<String, BTreeMap<String, String>>>>);
+
+        struct FieldVisitor<'a>(&'a mut BTreeMap<String, String>);
+
+        impl Visit for FieldVisitor<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0
+                    .insert(field.name().to_string(), format!("{value:?}"));
+            }
+        }
+
+        impl<S> Layer<S> for SpanRecorder
+        where
+            S: Subscriber + for<'a> LookupSpan<'a>,
+        {
+            fn on_new_span(
+                &self,
+                attrs: &span::Attributes<'_>,
+                _id: &span::Id,
+                _ctx: Context<'_, S>,
+            ) {
+                let mut fields = BTreeMap::new();
+                attrs.record(&mut FieldVisitor(&mut fields));
+
+                self.0
+                    .lock()
+                    .unwrap()
+                    .insert(attrs.metadata().name().to_string(), fields);
+            }
+
+            fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+                let name = ctx.span(id).unwrap().name();
+                let mut spans = self.0.lock().unwrap();
+
+                values.record(&mut FieldVisitor(
+                    spans.entry(name.to_string()).or_default(),
+                ));
+            }
+        }
+
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        for _ in 0..2 {
+            p.insert_deployment(Deployment {
+                id: Uuid::new_v4(),
+                service_id,
+                state: State::Running,
+                last_update: Utc::now(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        let recorder = SpanRecorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+
+        p.get_deployments(&service_id)
+            .with_subscriber(subscriber)
+            .await
+            .unwrap();
+
+        let spans = recorder.0.lock().unwrap();
+        assert_eq!(
+            spans["get_deployments"],
+            BTreeMap::from([
+                ("service_id".to_string(), service_id.to_string()),
+                ("rows".to_string(), "2".to_string()),
+            ])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logs_stream_all() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_a = add_deployment(&p.pool).await.unwrap();
+        let deployment_b = add_deployment(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 12, 1, 9, 0, 0).unwrap();
+
+        let logs: Vec<_> = (0..200)
+            .map(|i| Log {
+                id: if i % 3 == 0 {
+                    deployment_a
+                } else {
+                    deployment_b
+                },
+                timestamp: start + Duration::milliseconds(i),
+                state: State::Building,
+                level: Level::Info,
+                file: None,
+                line: None,
+                target: "tests::logs_stream_all".to_string(),
+                fields: json!({ "build_line": format!("line {i}") }),
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                span_fields: None,
+                duration_ms: None,
+                repeat_count: 1,
+            })
+            .collect();
+
+        // Insert out of order to make sure the stream is sorted
+        for log in logs.iter().rev() {
+            insert_log(&p.pool, log.clone()).await.unwrap();
+        }
+
+        let streamed: Vec<_> = p.stream_all_logs().try_collect().await.unwrap();
+        assert_eq!(streamed, logs);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logs_for_service() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let other_deployment = add_deployment(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 12, 1, 9, 0, 0).unwrap();
+
+        let deployments: Vec<_> = (0..2).map(|_| Uuid::new_v4()).collect();
+        for id in &deployments {
+            p.insert_deployment(Deployment {
+                id: *id,
+                service_id,
+                state: State::Running,
+                last_update: start,
+                address: None,
+                error_message: None,
+                running_since: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        let log = |id, i| Log {
+            id,
+            timestamp: start + Duration::seconds(i),
+            state: State::Running,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: "tests::logs_for_service".to_string(),
+            fields: json!({ "message": format!("line {i}") }),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+        let logs: Vec<_> = (0..6)
+            .map(|i| log(deployments[i as usize % 2], i))
+            .collect();
+
+        for log in logs.iter() {
+            insert_log(&p.pool, log.clone()).await.unwrap();
+        }
+        insert_log(&p.pool, log(other_deployment, 3)).await.unwrap();
+
+        assert_eq!(p.get_service_logs(&service_id, 10).await.unwrap(), logs);
+        assert_eq!(
+            p.get_service_logs(&service_id, 3).await.unwrap(),
+            logs[3..],
+            "only the most recent logs should be returned"
+        );
+        assert!(p
+            .get_service_logs(&Uuid::new_v4(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logs_search_by_message() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+        let other_deployment_id = add_deployment(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 12, 1, 9, 0, 0).unwrap();
+
+        let log = |id, i, fields| Log {
+            id,
+            timestamp: start + Duration::seconds(i),
+            state: State::Running,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: "tests::logs_search_by_message".to_string(),
+            fields,
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+        let logs = [
+            log(deployment_id, 0, json!({ "message": "starting up" })),
+            log(
+                deployment_id,
+                1,
+                json!({ "message": "Error: failed to connect" }),
+            ),
+            log(
+                deployment_id,
+                2,
+                json!({ "build_line": "error: not a message" }),
+            ),
+            log(deployment_id, 3, json!(STATE_MESSAGE)),
+            log(deployment_id, 4, json!({ "message": "error: timed out" })),
+            log(other_deployment_id, 5, json!({ "message": "error: other" })),
+        ];
+        for log in logs.iter() {
+            insert_log(&p.pool, log.clone()).await.unwrap();
+        }
+
+        assert_eq!(
+            p.search_logs_by_message(&deployment_id, "error%")
+                .await
+                .unwrap(),
+            vec![logs[1].clone(), logs[4].clone()]
+        );
+        assert_eq!(
+            p.search_logs_by_message(&deployment_id, "%connect%")
+                .await
+                .unwrap(),
+            vec![logs[1].clone()]
+        );
+        assert!(p
+            .search_logs_by_message(&deployment_id, "missing%")
+            .await
+            .unwrap()
+            .is_empty());
+
+        let plan: Vec<(i64, i64, i64, String)> = sqlx::query_as(
+            "EXPLAIN QUERY PLAN SELECT * FROM logs WHERE id = ? AND message LIKE ? ORDER BY timestamp",
+        )
+        .bind(deployment_id)
+        .bind("error%")
+        .fetch_all(&p.pool)
+        .await
+        .unwrap();
+        assert!(
+            plan.iter().any(|(_, _, _, detail)| detail
+                .contains("USING INDEX logs_id_message (id=? AND message>? AND message<?)")),
+            "the message pattern should be looked up in the index, not only the id: {plan:?}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logs_compaction() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 12, 1, 9, 0, 0).unwrap();
+
+        let log = |i, fields| Log {
+            id: deployment_id,
+            timestamp: start + Duration::seconds(i),
+            state: State::Running,
+            level: Level::Warn,
+            file: None,
+            line: None,
+            target: "tests::logs_compaction".to_string(),
+            fields,
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+        let retry = || json!({ "message": "retrying connection" });
+        let logs = [
+            log(0, retry()),
+            log(1, retry()),
+            log(2, retry()),
+            log(3, json!({ "message": "connected" })),
+            log(4, retry()),
+            log(5, json!(STATE_MESSAGE)),
+            log(6, json!(STATE_MESSAGE)),
+            log(7, retry()),
+            log(8, retry()),
+        ];
+        for log in logs.iter() {
+            insert_log(&p.pool, log.clone()).await.unwrap();
+        }
+
+        assert_eq!(p.compact_logs(&deployment_id).await.unwrap(), 3);
+
+        let rows: Vec<(DateTime<Utc>, i64)> = sqlx::query_as(
+            "SELECT timestamp, repeat_count FROM logs WHERE id = ? ORDER BY timestamp",
+        )
+        .bind(deployment_id)
+        .fetch_all(&p.pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            rows,
+            [0, 3, 4, 5, 6, 7]
+                .into_iter()
+                .zip([3, 1, 1, 1, 1, 2])
+                .map(|(i, count)| (start + Duration::seconds(i), count))
+                .collect::<Vec<_>>(),
+            "state logs should be kept and break up runs"
+        );
+        let repeat_counts: Vec<_> = p
+            .get_deployment_logs(&deployment_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|log| log.repeat_count)
+            .collect();
+        assert_eq!(repeat_counts, [3, 1, 1, 1, 1, 2]);
+
+        assert_eq!(
+            p.compact_logs(&deployment_id).await.unwrap(),
+            0,
+            "compacting again should not change anything"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logs_targets() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+        let other_deployment_id = add_deployment(&p.pool).await.unwrap();
+
+        let log = |id, target: &str| Log {
+            id,
+            timestamp: Utc::now(),
+            state: State::Running,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: target.to_string(),
+            fields: json!({ "message": "hello" }),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+        for log in [
+            log(deployment_id, "hyper::client"),
+            log(deployment_id, ""),
+            log(deployment_id, "app::db"),
+            log(deployment_id, "hyper::client"),
+            log(other_deployment_id, "other"),
+        ] {
+            insert_log(&p.pool, log).await.unwrap();
+        }
+
+        assert_eq!(
+            p.get_log_targets(&deployment_id).await.unwrap(),
+            ["app::db", "hyper::client"]
+        );
+        assert!(p.get_log_targets(&Uuid::new_v4()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logs_last_time() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+        let other_deployment_id = add_deployment(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 12, 1, 9, 0, 0).unwrap();
+
+        assert_eq!(p.get_last_log_time(&deployment_id).await.unwrap(), None);
+
+        let log = |id, timestamp| Log {
+            id,
+            timestamp,
+            state: State::Running,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: "tests::logs_last_time".to_string(),
+            fields: json!({ "message": "alive" }),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+        for offset in [
+            Duration::seconds(30),
+            Duration::milliseconds(90_500),
+            Duration::zero(),
+        ] {
+            insert_log(&p.pool, log(deployment_id, start + offset))
+                .await
+                .unwrap();
+        }
+        insert_log(
+            &p.pool,
+            log(other_deployment_id, start + Duration::hours(1)),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            p.get_last_log_time(&deployment_id).await.unwrap(),
+            Some(start + Duration::milliseconds(90_500))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logs_by_origin() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 12, 1, 9, 0, 0).unwrap();
+
+        let deployer_log = Log {
+            id: deployment_id,
+            timestamp: start,
+            state: State::Loading,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: "shuttle_deployer::deployment".to_string(),
+            fields: json!({ "message": "loading" }),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+        insert_log(&p.pool, deployer_log.clone()).await.unwrap();
+
+        // As sent by the logger of a running service
+        let runtime_log = deploy_layer::Log::from(shuttle_common::LogItem {
+            id: deployment_id,
+            timestamp: start + Duration::seconds(1),
+            state: shuttle_common::deployment::State::Running,
+            level: shuttle_common::log::Level::Info,
+            file: None,
+            line: None,
+            target: "app".to_string(),
+            fields: serde_json::to_vec(&json!({ "message": "hello" })).unwrap(),
+        });
+        insert_log(&p.pool, runtime_log).await.unwrap();
+
+        let runtime_logs = p
+            .get_deployment_logs_by_origin(&deployment_id, Origin::Runtime)
+            .await
+            .unwrap();
+        assert_eq!(runtime_logs.len(), 1);
+        assert_eq!(runtime_logs[0].origin, Origin::Runtime);
+        assert_eq!(runtime_logs[0].fields, json!({ "message": "hello" }));
+
+        assert_eq!(
+            p.get_deployment_logs_by_origin(&deployment_id, Origin::Deployer)
+                .await
+                .unwrap(),
+            vec![deployer_log]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logs_level_severity() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 12, 1, 9, 0, 0).unwrap();
+
+        let levels = [
+            Level::Error,
+            Level::Trace,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+        ];
+        let logs: Vec<_> = levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| Log {
+                id: deployment_id,
+                timestamp: start + Duration::seconds(i as i64),
+                state: State::Running,
+                level: level.clone(),
+                file: None,
+                line: None,
+                target: "tests::logs_level_severity".to_string(),
+                fields: json!({ "message": format!("{level:?}") }),
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                span_fields: None,
+                duration_ms: None,
+                repeat_count: 1,
+            })
+            .collect();
+        for log in logs.iter() {
+            insert_log(&p.pool, log.clone()).await.unwrap();
+        }
+
+        let stored: Vec<(Level, i64)> =
+            sqlx::query_as("SELECT level, level_severity FROM logs ORDER BY level_severity")
+                .fetch_all(&p.pool)
+                .await
+                .unwrap();
+        let mut expected = levels.to_vec();
+        expected.sort();
+        assert_eq!(
+            stored,
+            expected
+                .into_iter()
+                .map(|level| {
+                    let severity = level.severity();
+                    (level, severity)
+                })
+                .collect::<Vec<_>>(),
+            "stored severities should order levels the same way as Level"
+        );
+
+        assert_eq!(
+            p.get_deployment_logs_at_least(&deployment_id, Level::Warn)
+                .await
+                .unwrap(),
+            vec![logs[0].clone(), logs[2].clone()]
+        );
+        assert_eq!(
+            p.get_deployment_logs_at_least(&deployment_id, Level::Trace)
+                .await
+                .unwrap(),
+            logs
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn log_insert_state_dedupe() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+
+        let log = Log {
+            id: deployment_id,
+            timestamp: Utc::now(),
+            state: State::Built,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields: json!(STATE_MESSAGE),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+
+        insert_state_log(&p.pool, log.clone()).await.unwrap();
+        insert_state_log(&p.pool, log.clone()).await.unwrap();
+
+        assert_eq!(
+            p.get_deployment_logs(&deployment_id).await.unwrap(),
+            vec![log.clone()]
+        );
+
+        // Events are not deduplicated
+        assert!(insert_log(&p.pool, log).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn log_insert_compressed() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+
+        let build_line = "   Compiling serde v1.0.148\n".repeat(1_000);
+        let log = Log {
+            id: deployment_id,
+            timestamp: Utc::now(),
+            state: State::Building,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: "tests::log_insert_compressed".to_string(),
+            fields: json!({ "message": "Compiling dependencies", "build_line": build_line }),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
         };
-        let deployment_running = Deployment {
-            id: Uuid::new_v4(),
-            service_id,
-            state: State::Running,
-            last_update: Utc::now(),
-            address: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9876)),
+
+        insert_log(&p.pool, log.clone()).await.unwrap();
+
+        let (stored_len, compressed): (i64, bool) =
+            sqlx::query_as("SELECT length(fields), fields_compressed FROM logs WHERE id = ?")
+                .bind(deployment_id)
+                .fetch_one(&p.pool)
+                .await
+                .unwrap();
+
+        assert!(compressed);
+        assert!((stored_len as usize) < serde_json::to_vec(&log.fields).unwrap().len());
+
+        let logs = p.get_deployment_logs(&deployment_id).await.unwrap();
+        assert_eq!(logs, vec![log.clone()]);
+
+        let found = p
+            .search_logs_by_message(&deployment_id, "compiling%")
+            .await
+            .unwrap();
+        assert_eq!(
+            found,
+            vec![log],
+            "compressed logs should still be searchable"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logs_for_deployment() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_a = add_deployment(&p.pool).await.unwrap();
+        let deployment_b = add_deployment(&p.pool).await.unwrap();
+
+        let log_a1 = Log {
+            id: deployment_a,
+            timestamp: Utc::now(),
+            state: State::Queued,
+            level: Level::Info,
+            file: Some("file.rs".to_string()),
+            line: Some(5),
+            target: "tests::logs_for_deployment".to_string(),
+            fields: json!({"message": "job queued"}),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
         };
-        let deployment_queued = Deployment {
-            id: queued_id,
-            service_id,
+        let log_b = Log {
+            id: deployment_b,
+            timestamp: Utc::now(),
             state: State::Queued,
-            last_update: Utc::now(),
-            address: None,
+            level: Level::Info,
+            file: Some("file.rs".to_string()),
+            line: Some(5),
+            target: "tests::logs_for_deployment".to_string(),
+            fields: json!({"message": "job queued"}),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
         };
-        let deployment_building = Deployment {
-            id: building_id,
-            service_id,
+        let log_a2 = Log {
+            id: deployment_a,
+            timestamp: Utc::now(),
             state: State::Building,
-            last_update: Utc::now(),
-            address: None,
-        };
-        let deployment_built = Deployment {
-            id: built_id,
-            service_id,
-            state: State::Built,
-            last_update: Utc::now(),
-            address: None,
+            level: Level::Warn,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields: json!({"message": "unused Result"}),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
         };
-        let deployment_loading = Deployment {
-            id: loading_id,
-            service_id,
-            state: State::Loading,
-            last_update: Utc::now(),
-            address: None,
+
+        for log in [log_a1.clone(), log_b, log_a2.clone()] {
+            insert_log(&p.pool, log).await.unwrap();
+        }
+
+        let logs = p.get_deployment_logs(&deployment_a).await.unwrap();
+        assert!(!logs.is_empty(), "there should be two logs");
+
+        assert_eq!(logs, vec![log_a1, log_a2]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn export_deployment() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service = p.get_or_create_service("export-service").await.unwrap();
+        let id = Uuid::new_v4();
+
+        let deployment = Deployment {
+            id,
+            service_id: service.id,
+            state: State::Running,
+            last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap(),
+            address: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9876)),
+            error_message: None,
+            running_since: None,
         };
+        let logs = vec![
+            Log {
+                id,
+                timestamp: Utc.with_ymd_and_hms(2022, 4, 25, 4, 40, 0).unwrap(),
+                state: State::Queued,
+                level: Level::Info,
+                file: None,
+                line: None,
+                target: String::new(),
+                fields: json!(STATE_MESSAGE),
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                span_fields: None,
+                duration_ms: None,
+                repeat_count: 1,
+            },
+            Log {
+                id,
+                timestamp: Utc.with_ymd_and_hms(2022, 4, 25, 4, 41, 0).unwrap(),
+                state: State::Building,
+                level: Level::Warn,
+                file: Some("main.rs".to_string()),
+                line: Some(20),
+                target: "export::main".to_string(),
+                fields: json!({"message": "unused import"}),
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                span_fields: None,
+                duration_ms: None,
+                repeat_count: 1,
+            },
+        ];
 
-        for deployment in [
-            &deployment_crashed,
-            &deployment_stopped,
-            &deployment_running,
-            &deployment_queued,
-            &deployment_built,
-            &deployment_building,
-            &deployment_loading,
-        ] {
-            p.insert_deployment(deployment.clone()).await.unwrap();
+        p.insert_deployment(deployment.clone()).await.unwrap();
+        for log in logs.clone() {
+            insert_log(&p.pool, log).await.unwrap();
         }
 
-        p.cleanup_invalid_states().await.unwrap();
+        let export = p.export_deployment(&id).await.unwrap();
+        let json = serde_json::to_string(&export).unwrap();
+        let actual: DeploymentExport = serde_json::from_str(&json).unwrap();
 
-        let actual: Vec<_> = p
-            .get_deployments(&service_id)
+        assert_eq!(
+            actual,
+            DeploymentExport {
+                deployment,
+                service,
+                logs,
+            }
+        );
+        assert!(matches!(
+            p.export_deployment(&Uuid::new_v4()).await,
+            Err(Error::DeploymentNotFound)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_import() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service_named(&p.pool, "imported").await.unwrap();
+        let id = Uuid::new_v4();
+        p.insert_deployment(
+            Deployment::builder(service_id)
+                .id(id)
+                .state(State::Crashed)
+                .last_update(Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap())
+                .error_message("panicked"),
+        )
+        .await
+        .unwrap();
+        for (i, message) in ["building", "panicked"].into_iter().enumerate() {
+            insert_log(
+                &p.pool,
+                Log {
+                    id,
+                    timestamp: Utc.with_ymd_and_hms(2022, 4, 25, 4, 40, i as u32).unwrap(),
+                    state: State::Building,
+                    level: Level::Info,
+                    file: None,
+                    line: None,
+                    target: "tests::deployment_import".to_string(),
+                    fields: json!({ "message": message }),
+                    trace_id: None,
+                    span_id: None,
+                    origin: Origin::Deployer,
+                    span_fields: None,
+                    duration_ms: None,
+                    repeat_count: 1,
+                },
+            )
             .await
-            .unwrap()
-            .into_iter()
-            .map(|deployment| (deployment.id, deployment.state))
-            .collect();
-        let expected = vec![
-            (deployment_crashed.id, State::Crashed),
-            (deployment_stopped.id, State::Stopped),
-            (deployment_running.id, State::Running),
-            (queued_id, State::Stopped),
-            (built_id, State::Stopped),
-            (building_id, State::Stopped),
-            (loading_id, State::Stopped),
+            .unwrap();
+        }
+        let export = p.export_deployment(&id).await.unwrap();
+
+        let (fresh, _) = Persistence::new_in_memory().await;
+        fresh.import_deployment(&export).await.unwrap();
+        assert_eq!(fresh.export_deployment(&id).await.unwrap(), export);
+
+        assert!(matches!(
+            fresh.import_deployment(&export).await,
+            Err(Error::DeploymentExists(existing)) if existing == id
+        ));
+
+        // A collision should not leave part of the export behind
+        let (other, _) = Persistence::new_in_memory().await;
+        add_service_named(&other.pool, "imported").await.unwrap();
+        assert!(matches!(
+            other.import_deployment(&export).await,
+            Err(Error::ServiceNameTaken(_))
+        ));
+        assert_eq!(other.get_deployment(&id).await.unwrap(), None);
+        assert!(other.get_deployment_logs(&id).await.unwrap().is_empty());
+
+        let (renamed, _) = Persistence::new_in_memory().await;
+        sqlx::query("INSERT INTO services (id, name) VALUES (?, ?)")
+            .bind(export.service.id)
+            .bind("renamed")
+            .execute(&renamed.pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            renamed.import_deployment(&export).await,
+            Err(Error::ServiceIdTaken(taken)) if taken == export.service.id
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logs_build() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 12, 1, 9, 0, 0).unwrap();
+
+        let log = |i, state, fields| Log {
+            id: deployment_id,
+            timestamp: start + Duration::seconds(i),
+            state,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: "tests::logs_build".to_string(),
+            fields,
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
+        };
+        let build_logs = vec![
+            log(0, State::Queued, json!(STATE_MESSAGE)),
+            log(1, State::Building, json!(STATE_MESSAGE)),
+            log(2, State::Building, json!({ "build_line": "Compiling app" })),
+            log(3, State::Building, json!({ "build_line": "Finished" })),
+        ];
+        let built = log(4, State::Built, json!(STATE_MESSAGE));
+        let runtime_logs = vec![
+            log(5, State::Loading, json!(STATE_MESSAGE)),
+            log(6, State::Running, json!(STATE_MESSAGE)),
+            log(7, State::Running, json!({ "message": "listening" })),
         ];
 
+        for log in build_logs.iter() {
+            insert_log(&p.pool, log.clone()).await.unwrap();
+        }
         assert_eq!(
-            actual, expected,
-            "invalid states should be moved to the stopped state"
+            p.get_build_logs(&deployment_id).await.unwrap(),
+            build_logs,
+            "all the logs are build logs while building"
         );
+
+        for log in std::iter::once(&built).chain(runtime_logs.iter()) {
+            insert_log(&p.pool, log.clone()).await.unwrap();
+        }
+        let mut expected = build_logs;
+        expected.push(built);
+        assert_eq!(p.get_build_logs(&deployment_id).await.unwrap(), expected);
     }
+
     #[tokio::test(flavor = "multi_thread")]
-    async fn fetching_runnable_deployments() {
+    async fn logs_between() {
         let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
 
-        let bar_id = add_service_named(&p.pool, "bar").await.unwrap();
-        let foo_id = add_service_named(&p.pool, "foo").await.unwrap();
-        let service_id = add_service(&p.pool).await.unwrap();
-        let service_id2 = add_service(&p.pool).await.unwrap();
-
-        let id_1 = Uuid::new_v4();
-        let id_2 = Uuid::new_v4();
-        let id_3 = Uuid::new_v4();
+        let logs: Vec<_> = (0..5)
+            .map(|minute| Log {
+                id: deployment_id,
+                timestamp: Utc.with_ymd_and_hms(2022, 4, 25, 4, minute, 0).unwrap(),
+                state: State::Building,
+                level: Level::Info,
+                file: None,
+                line: None,
+                target: String::new(),
+                fields: json!({ "message": format!("minute {minute}") }),
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                span_fields: None,
+                duration_ms: None,
+                repeat_count: 1,
+            })
+            .collect();
 
-        for deployment in [
-            Deployment {
-                id: Uuid::new_v4(),
-                service_id,
-                state: State::Built,
-                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 29, 33).unwrap(),
-                address: None,
-            },
-            Deployment {
-                id: id_1,
-                service_id: foo_id,
-                state: State::Running,
-                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 29, 44).unwrap(),
-                address: None,
-            },
-            Deployment {
-                id: id_2,
-                service_id: bar_id,
-                state: State::Running,
-                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 33, 48).unwrap(),
-                address: None,
-            },
-            Deployment {
-                id: Uuid::new_v4(),
-                service_id: service_id2,
-                state: State::Crashed,
-                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 38, 52).unwrap(),
-                address: None,
-            },
-            Deployment {
-                id: id_3,
-                service_id: foo_id,
-                state: State::Running,
-                last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 42, 32).unwrap(),
-                address: None,
-            },
-        ] {
-            p.insert_deployment(deployment).await.unwrap();
+        for log in logs.iter().rev() {
+            insert_log(&p.pool, log.clone()).await.unwrap();
         }
 
-        let runnable = p.get_all_runnable_deployments().await.unwrap();
+        let start = Utc.with_ymd_and_hms(2022, 4, 25, 4, 1, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2022, 4, 25, 4, 3, 0).unwrap();
+
         assert_eq!(
-            runnable,
-            [
-                DeploymentRunnable {
-                    id: id_1,
-                    service_name: "foo".to_string(),
-                    service_id: foo_id,
-                },
-                DeploymentRunnable {
-                    id: id_2,
-                    service_name: "bar".to_string(),
-                    service_id: bar_id,
-                },
-                DeploymentRunnable {
-                    id: id_3,
-                    service_name: "foo".to_string(),
-                    service_id: foo_id,
-                },
-            ]
+            p.get_deployment_logs_between(&deployment_id, start, end)
+                .await
+                .unwrap(),
+            logs[1..=3].to_vec()
+        );
+        assert!(matches!(
+            p.get_deployment_logs_between(&deployment_id, end, start)
+                .await,
+            Err(Error::InvalidTimeRange)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logs_tail() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+
+        let logs: Vec<_> = (0..10)
+            .map(|minute| Log {
+                id: deployment_id,
+                timestamp: Utc.with_ymd_and_hms(2022, 4, 25, 4, minute, 0).unwrap(),
+                state: State::Building,
+                level: Level::Info,
+                file: None,
+                line: None,
+                target: String::new(),
+                fields: json!({ "message": format!("minute {minute}") }),
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                span_fields: None,
+                duration_ms: None,
+                repeat_count: 1,
+            })
+            .collect();
+
+        for log in logs.iter() {
+            insert_log(&p.pool, log.clone()).await.unwrap();
+        }
+
+        assert_eq!(
+            p.tail_deployment_logs(&deployment_id, 3).await.unwrap(),
+            logs[7..].to_vec()
+        );
+        assert_eq!(
+            p.tail_deployment_logs(&deployment_id, 20).await.unwrap(),
+            logs
         );
+        assert!(p
+            .tail_deployment_logs(&deployment_id, 0)
+            .await
+            .unwrap()
+            .is_empty());
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn log_insert() {
+    async fn logs_digest() {
         let (p, _) = Persistence::new_in_memory().await;
         let deployment_id = add_deployment(&p.pool).await.unwrap();
 
+        assert_eq!(p.get_logs_digest(&deployment_id).await.unwrap(), None);
+
         let log = Log {
             id: deployment_id,
-            timestamp: Utc::now(),
+            timestamp: Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 33).unwrap(),
             state: State::Queued,
             level: Level::Info,
-            file: Some("queue.rs".to_string()),
-            line: Some(12),
-            target: "tests::log_insert".to_string(),
+            file: None,
+            line: None,
+            target: String::new(),
             fields: json!({"message": "job queued"}),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
         };
-
         insert_log(&p.pool, log.clone()).await.unwrap();
 
-        let logs = p.get_deployment_logs(&deployment_id).await.unwrap();
-        assert!(!logs.is_empty(), "there should be one log");
+        let digest = p.get_logs_digest(&deployment_id).await.unwrap();
+        assert_eq!(
+            digest,
+            Some(13925713549559826714),
+            "digest should not change between releases"
+        );
+        assert_eq!(
+            p.get_logs_digest(&deployment_id).await.unwrap(),
+            digest,
+            "digest should be stable while logs are unchanged"
+        );
 
-        assert_eq!(logs.first().unwrap(), &log);
+        insert_log(
+            &p.pool,
+            Log {
+                timestamp: Utc.with_ymd_and_hms(2022, 4, 25, 4, 43, 34).unwrap(),
+                ..log
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_ne!(p.get_logs_digest(&deployment_id).await.unwrap(), digest);
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn logs_for_deployment() {
-        let (p, _) = Persistence::new_in_memory().await;
-        let deployment_a = add_deployment(&p.pool).await.unwrap();
-        let deployment_b = add_deployment(&p.pool).await.unwrap();
+    async fn handle_join() {
+        let (p, handle) = Persistence::new_in_memory().await;
+        let sender = p.get_log_sender();
 
-        let log_a1 = Log {
-            id: deployment_a,
-            timestamp: Utc::now(),
-            state: State::Queued,
-            level: Level::Info,
-            file: Some("file.rs".to_string()),
-            line: Some(5),
-            target: "tests::logs_for_deployment".to_string(),
-            fields: json!({"message": "job queued"}),
-        };
-        let log_b = Log {
-            id: deployment_b,
+        drop(p);
+        drop(sender);
+
+        assert!(handle.join().await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handle_shutdown() {
+        let (p, handle) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+        let _sender = p.get_log_sender();
+
+        p.record(deploy_layer::Log {
+            id: deployment_id,
             timestamp: Utc::now(),
-            state: State::Queued,
+            state: State::Running,
             level: Level::Info,
-            file: Some("file.rs".to_string()),
-            line: Some(5),
-            target: "tests::logs_for_deployment".to_string(),
-            fields: json!({"message": "job queued"}),
-        };
-        let log_a2 = Log {
-            id: deployment_a,
-            timestamp: Utc::now(),
-            state: State::Building,
-            level: Level::Warn,
             file: None,
             line: None,
-            target: String::new(),
-            fields: json!({"message": "unused Result"}),
-        };
-
-        for log in [log_a1.clone(), log_b, log_a2.clone()] {
-            insert_log(&p.pool, log).await.unwrap();
-        }
+            target: "tests::handle_shutdown".to_string(),
+            fields: json!({"message": "before shutdown"}),
+            r#type: deploy_layer::LogType::Event,
+            address: None,
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            duration_ms: None,
+        });
 
-        let logs = p.get_deployment_logs(&deployment_a).await.unwrap();
-        assert!(!logs.is_empty(), "there should be two logs");
+        tokio::time::timeout(std::time::Duration::from_secs(1), p.shutdown(handle))
+            .await
+            .expect("shutdown should not wait for the senders to be dropped")
+            .unwrap();
 
-        assert_eq!(logs, vec![log_a1, log_a2]);
+        let logs = get_deployment_logs(&p.pool, &deployment_id).await.unwrap();
+        assert_eq!(logs.len(), 1, "the received log should be stored");
+        assert_eq!(logs[0].fields, json!({"message": "before shutdown"}));
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -831,13 +5760,17 @@ mod tests {
             fields: json!({"message": "job queued"}),
             r#type: deploy_layer::LogType::Event,
             address: None,
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            duration_ms: None,
         };
 
         p.record(event);
 
         // Drop channel and wait for it to finish
         drop(p.log_send);
-        assert!(handle.await.is_ok());
+        assert!(handle.join().await.is_ok());
 
         let logs = get_deployment_logs(&p.pool, &deployment_id).await.unwrap();
 
@@ -852,6 +5785,221 @@ mod tests {
         assert_eq!(log.fields, json!({"message": "job queued"}));
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn log_recorder_state_duration() {
+        let (p, handle) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+        let mut subscriber = p.subscribe_to_deployment(&deployment_id);
+
+        let log = |r#type, duration_ms| deploy_layer::Log {
+            id: deployment_id,
+            timestamp: Utc::now(),
+            state: State::Building,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields: json!({}),
+            r#type,
+            address: None,
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            duration_ms,
+        };
+        p.record(log(deploy_layer::LogType::State, None));
+        p.record(log(deploy_layer::LogType::StateEnd, Some(1234)));
+
+        drop(p.log_send);
+        assert!(handle.join().await.is_ok());
+
+        let logs = get_deployment_logs(&p.pool, &deployment_id).await.unwrap();
+        assert_eq!(logs.len(), 1, "the end of a state is not a log of its own");
+        assert_eq!(logs[0].fields, json!(STATE_MESSAGE));
+        assert_eq!(logs[0].duration_ms, Some(1234));
+
+        assert_eq!(
+            subscriber.recv().await.unwrap().r#type,
+            deploy_layer::LogType::State
+        );
+        assert!(
+            subscriber.try_recv().is_err(),
+            "the end of a state should not be streamed"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stream_lag_marker_recorded() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+        let mut subscriber = p.subscribe_to_deployment(&deployment_id);
+
+        // The broadcast only holds one log, so the subscriber falls behind once the drain moves on to the third log
+        for message in ["one", "two", "three"] {
+            p.record(deploy_layer::Log {
+                id: deployment_id,
+                timestamp: Utc::now(),
+                state: State::Running,
+                level: Level::Info,
+                file: None,
+                line: None,
+                target: String::new(),
+                fields: json!({ "message": message }),
+                r#type: deploy_layer::LogType::Event,
+                address: None,
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                duration_ms: None,
+            });
+        }
+
+        let skipped = match subscriber.recv().await {
+            Err(broadcast::error::RecvError::Lagged(skipped)) => skipped,
+            other => panic!("the subscriber should have lagged: {other:?}"),
+        };
+
+        let marker = deploy_layer::Log::dropped_marker(deployment_id, State::Running, skipped);
+        p.record_dropped_marker(&marker).await.unwrap();
+
+        let logs = p.get_deployment_logs(&deployment_id).await.unwrap();
+        assert!(
+            logs.iter().any(|log| log.fields == marker.fields),
+            "the marker should be stored with the logs: {logs:?}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn log_recorder_state_span_fields() {
+        let (p, handle) = Persistence::new_in_memory().await;
+        let deployment_id = add_deployment(&p.pool).await.unwrap();
+
+        p.record(deploy_layer::Log {
+            id: deployment_id,
+            timestamp: Utc::now(),
+            state: State::Building,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: "tests::log_recorder_state_span_fields".to_string(),
+            fields: json!({"builder": "cargo", "attempt": 2}),
+            r#type: deploy_layer::LogType::State,
+            address: None,
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            duration_ms: None,
+        });
+
+        drop(p.log_send);
+        assert!(handle.join().await.is_ok());
+
+        let logs = get_deployment_logs(&p.pool, &deployment_id).await.unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].fields, json!(STATE_MESSAGE));
+        assert_eq!(
+            logs[0].span_fields,
+            Some(json!({"builder": "cargo", "attempt": 2}))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn log_recorder_state_insert_failed() {
+        let (p, handle) = Persistence::new_in_memory().await;
+        let id = add_deployment(&p.pool).await.unwrap();
+        let before = p.get_deployment(&id).await.unwrap().unwrap();
+
+        sqlx::query(
+            "CREATE TRIGGER fail_log_insert BEFORE INSERT ON logs BEGIN SELECT RAISE(ABORT, 'forced failure'); END",
+        )
+        .execute(&p.pool)
+        .await
+        .unwrap();
+
+        p.record(deploy_layer::Log {
+            id,
+            timestamp: Utc::now(),
+            state: State::Stopped,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields: serde_json::Value::Null,
+            r#type: deploy_layer::LogType::State,
+            address: None,
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            duration_ms: None,
+        });
+        p.shutdown(handle).await.unwrap();
+
+        assert_eq!(
+            p.get_deployment(&id).await.unwrap().unwrap(),
+            before,
+            "the state should not change without its log"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deployment_state_watch() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let id = add_deployment(&p.pool).await.unwrap();
+        let initial = p.get_deployment(&id).await.unwrap().unwrap().state;
+
+        let (current, transitions) = p.watch_deployment_state(&id).await.unwrap();
+        assert_eq!(current, initial);
+        futures::pin_mut!(transitions);
+
+        for state in [
+            State::Building,
+            State::Built,
+            State::Loading,
+            State::Stopped,
+        ] {
+            p.record(deploy_layer::Log {
+                id,
+                timestamp: Utc::now(),
+                state,
+                level: Level::Info,
+                file: None,
+                line: None,
+                target: String::new(),
+                fields: serde_json::Value::Null,
+                r#type: deploy_layer::LogType::State,
+                address: None,
+                trace_id: None,
+                span_id: None,
+                origin: Origin::Deployer,
+                duration_ms: None,
+            });
+
+            let next = tokio::time::timeout(std::time::Duration::from_secs(5), transitions.next())
+                .await
+                .expect("transition should arrive");
+            assert_eq!(next, Some(state));
+        }
+
+        assert_eq!(
+            transitions.next().await,
+            None,
+            "the stream should end on a terminal state"
+        );
+        assert!(matches!(
+            p.watch_deployment_state(&Uuid::new_v4()).await,
+            Err(Error::DeploymentNotFound)
+        ));
+
+        // Watching a deployment which already finished
+        let (current, transitions) = p.watch_deployment_state(&id).await.unwrap();
+        assert_eq!(current, State::Stopped);
+        futures::pin_mut!(transitions);
+        let next = tokio::time::timeout(std::time::Duration::from_secs(5), transitions.next())
+            .await
+            .expect("the stream should end rather than wait for a transition");
+        assert_eq!(next, None);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn log_recorder_state() {
         let (p, handle) = Persistence::new_in_memory().await;
@@ -865,6 +6013,8 @@ mod tests {
             state: State::Queued, // Should be different from the state recorded below
             last_update: Utc.with_ymd_and_hms(2022, 4, 29, 2, 39, 39).unwrap(),
             address: None,
+            error_message: None,
+            running_since: None,
         })
         .await
         .unwrap();
@@ -878,35 +6028,213 @@ mod tests {
             target: String::new(),
             fields: serde_json::Value::Null,
             r#type: deploy_layer::LogType::State,
-            address: Some("127.0.0.1:12345".to_string()),
+            address: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345)),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            duration_ms: None,
+        };
+
+        p.record(state);
+
+        // Drop channel and wait for it to finish
+        drop(p.log_send);
+        assert!(handle.join().await.is_ok());
+
+        let logs = get_deployment_logs(&p.pool, &id).await.unwrap();
+
+        assert!(!logs.is_empty(), "state change should be logged");
+
+        let log = logs.first().unwrap();
+        assert_eq!(log.id, id);
+        assert_eq!(log.state, State::Running);
+        assert_eq!(log.level, Level::Info);
+        assert_eq!(log.fields, json!("NEW STATE"));
+
+        assert_eq!(
+            get_deployment(&p.pool, &id).await.unwrap().unwrap(),
+            Deployment {
+                id,
+                service_id,
+                state: State::Running,
+                last_update: Utc.with_ymd_and_hms(2022, 4, 29, 2, 39, 59).unwrap(),
+                address: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345)),
+                error_message: None,
+                running_since: Some(Utc.with_ymd_and_hms(2022, 4, 29, 2, 39, 59).unwrap()),
+            }
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn log_recorder_crash_reason() {
+        let (p, handle) = Persistence::new_in_memory().await;
+        let id = add_deployment(&p.pool).await.unwrap();
+
+        let log = |r#type, fields| deploy_layer::Log {
+            id,
+            timestamp: Utc::now(),
+            state: State::Crashed,
+            level: Level::Error,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields,
+            r#type,
+            address: None,
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            duration_ms: None,
+        };
+
+        let crashed_at = Utc.with_ymd_and_hms(2022, 4, 29, 2, 39, 59).unwrap();
+        let address = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345);
+        p.record(deploy_layer::Log {
+            timestamp: crashed_at,
+            address: Some(address),
+            ..log(deploy_layer::LogType::State, serde_json::Value::Null)
+        });
+        p.record(log(
+            deploy_layer::LogType::Event,
+            json!({"message": "service encountered an error", "error": "panicked at 'boom'"}),
+        ));
+
+        // Drop channel and wait for it to finish
+        drop(p.log_send);
+        assert!(handle.join().await.is_ok());
+
+        let deployment = get_deployment(&p.pool, &id).await.unwrap().unwrap();
+        assert_eq!(deployment.state, State::Crashed);
+        assert_eq!(
+            deployment.error_message,
+            Some("panicked at 'boom'".to_string())
+        );
+
+        // Recording the reason should leave the rest of the state transition alone
+        assert_eq!(deployment.last_update, crashed_at);
+        assert_eq!(deployment.address, Some(address));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn flakiest_deployments() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+        let id_once = Uuid::new_v4();
+        let id_thrice = Uuid::new_v4();
+        let id_never = Uuid::new_v4();
+        let id_other = add_deployment(&p.pool).await.unwrap();
+
+        for id in [id_once, id_thrice, id_never] {
+            p.insert_deployment(Deployment {
+                id,
+                service_id,
+                state: State::Crashed,
+                last_update: Utc::now(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        let crash = |id, minute| Log {
+            id,
+            timestamp: Utc.with_ymd_and_hms(2022, 4, 25, 4, minute, 0).unwrap(),
+            state: State::Crashed,
+            level: Level::Info,
+            file: None,
+            line: None,
+            target: String::new(),
+            fields: json!(STATE_MESSAGE),
+            trace_id: None,
+            span_id: None,
+            origin: Origin::Deployer,
+            span_fields: None,
+            duration_ms: None,
+            repeat_count: 1,
         };
 
-        p.record(state);
+        for log in [
+            crash(id_once, 1),
+            crash(id_thrice, 2),
+            crash(id_thrice, 3),
+            crash(id_thrice, 4),
+            crash(id_other, 5),
+            crash(id_other, 6),
+            crash(id_other, 7),
+            crash(id_other, 8),
+            // Events while crashed should not count
+            Log {
+                fields: json!({"message": "panicked"}),
+                ..crash(id_never, 9)
+            },
+            // Neither should other states
+            Log {
+                state: State::Running,
+                ..crash(id_never, 10)
+            },
+        ] {
+            insert_log(&p.pool, log).await.unwrap();
+        }
+
+        assert_eq!(
+            p.get_flakiest_deployments(&service_id, 10).await.unwrap(),
+            vec![(id_thrice, 3), (id_once, 1)]
+        );
+        assert_eq!(
+            p.get_flakiest_deployments(&service_id, 1).await.unwrap(),
+            vec![(id_thrice, 3)]
+        );
+    }
 
-        // Drop channel and wait for it to finish
-        drop(p.log_send);
-        assert!(handle.await.is_ok());
+    #[tokio::test(flavor = "multi_thread")]
+    async fn subscriber_counts() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let id = Uuid::new_v4();
+        let other_id = Uuid::new_v4();
 
-        let logs = get_deployment_logs(&p.pool, &id).await.unwrap();
+        assert_eq!(p.subscriber_count_for(&id), 0);
 
-        assert!(!logs.is_empty(), "state change should be logged");
+        let subscriber1 = p.subscribe_to_deployment(&id);
+        let subscriber2 = p.clone().subscribe_to_deployment(&id);
+        let other_subscriber = p.subscribe_to_deployment(&other_id);
 
-        let log = logs.first().unwrap();
-        assert_eq!(log.id, id);
-        assert_eq!(log.state, State::Running);
-        assert_eq!(log.level, Level::Info);
-        assert_eq!(log.fields, json!("NEW STATE"));
+        assert_eq!(p.subscriber_count_for(&id), 2);
+        assert_eq!(p.subscriber_count_for(&other_id), 1);
 
-        assert_eq!(
-            get_deployment(&p.pool, &id).await.unwrap().unwrap(),
-            Deployment {
-                id,
-                service_id,
-                state: State::Running,
-                last_update: Utc.with_ymd_and_hms(2022, 4, 29, 2, 39, 59).unwrap(),
-                address: Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 12345)),
+        drop(subscriber1);
+        assert_eq!(p.subscriber_count_for(&id), 1);
+
+        drop(subscriber2);
+        drop(other_subscriber);
+        assert_eq!(p.subscriber_count_for(&id), 0);
+        assert_eq!(p.subscriber_count_for(&other_id), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn address_for_service_malformed() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service_named(&p.pool, "foo").await.unwrap();
+
+        sqlx::query(
+            "INSERT INTO deployments (id, service_id, state, last_update, address) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(service_id)
+        .bind(State::Running)
+        .bind(Utc::now())
+        .bind("localhost")
+        .execute(&p.pool)
+        .await
+        .unwrap();
+
+        match p.get_address_for_service("foo").await {
+            Err(crate::handlers::Error::AddressParse(error)) => {
+                assert_eq!(error.address, "localhost")
             }
-        );
+            other => panic!("expected an address parse error, got {other:?}"),
+        }
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -949,11 +6277,45 @@ mod tests {
             p.insert_resource(resource).await.unwrap();
         }
 
+        let resources = p.get_all_resources().await.unwrap();
+
+        assert_eq!(
+            resources.iter().collect::<Vec<_>>(),
+            vec![&resource2, &resource3, &resource4]
+        );
+
         let resources = p.get_resources(&service_id).await.unwrap();
 
         assert_eq!(resources, vec![resource2, resource4]);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bucket_resources() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        let database = Resource {
+            service_id,
+            r#type: ResourceType::Database(resource::DatabaseType::Shared(
+                resource::database::SharedType::Postgres,
+            )),
+            data: json!({"username": "root"}),
+        };
+        let bucket = Resource {
+            service_id,
+            r#type: ResourceType::Bucket(resource::BucketType::AwsS3),
+            data: json!({"bucket_name": "assets", "endpoint": "https://s3.amazonaws.com"}),
+        };
+
+        for resource in [&database, &bucket] {
+            p.insert_resource(resource).await.unwrap();
+        }
+
+        let resources = p.get_resources(&service_id).await.unwrap();
+
+        assert_eq!(resources, vec![database, bucket]);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn secrets() {
         let (p, _) = Persistence::new_in_memory().await;
@@ -1003,6 +6365,268 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn secret_keys() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        p.insert_secret(&service_id, "_DATABASE_url2", "value")
+            .await
+            .unwrap();
+
+        for key in ["", " ", "api-key", "2fa", "key with space"] {
+            assert!(
+                matches!(
+                    p.insert_secret(&service_id, key, "value").await,
+                    Err(Error::InvalidSecretKey(invalid)) if invalid == key
+                ),
+                "'{key}' should be rejected"
+            );
+        }
+
+        let secrets = BTreeMap::from([
+            ("valid".to_string(), "value".to_string()),
+            ("in-valid".to_string(), "value".to_string()),
+        ]);
+        assert!(matches!(
+            p.insert_secrets(&service_id, &secrets).await,
+            Err(Error::InvalidSecretKey(_))
+        ));
+
+        let keys: Vec<_> = p
+            .get_secrets(&service_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|secret| secret.key)
+            .collect();
+        assert_eq!(keys, vec!["_DATABASE_url2"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bulk_secrets() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        let secrets = BTreeMap::from([
+            ("key1".to_string(), "value1".to_string()),
+            ("key2".to_string(), "value2".to_string()),
+            ("key3".to_string(), "value3".to_string()),
+        ]);
+
+        p.insert_secrets(&service_id, &secrets).await.unwrap();
+
+        let actual = p.get_secrets(&service_id).await.unwrap();
+        assert_eq!(
+            actual
+                .iter()
+                .map(|secret| (secret.key.clone(), secret.value.clone()))
+                .collect::<BTreeMap<_, _>>(),
+            secrets
+        );
+        assert!(actual
+            .iter()
+            .all(|secret| secret.last_update == actual[0].last_update));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sync_secrets() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        for (key, value) in [("kept", "same"), ("changed", "old"), ("removed", "gone")] {
+            p.insert_secret(&service_id, key, value).await.unwrap();
+        }
+
+        let desired = BTreeMap::from([
+            ("kept".to_string(), "same".to_string()),
+            ("changed".to_string(), "new".to_string()),
+            ("added".to_string(), "fresh".to_string()),
+        ]);
+
+        let report = p.sync_secrets(&service_id, &desired).await.unwrap();
+        assert_eq!(
+            report,
+            SecretSyncReport {
+                added: vec!["added".to_string()],
+                updated: vec!["changed".to_string()],
+                removed: vec!["removed".to_string()],
+            }
+        );
+
+        let actual: BTreeMap<_, _> = p
+            .get_secrets(&service_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|secret| (secret.key, secret.value))
+            .collect();
+        assert_eq!(actual, desired);
+
+        assert_eq!(
+            p.sync_secrets(&service_id, &desired).await.unwrap(),
+            SecretSyncReport::default()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn secrets_with_ttl() {
+        let start = Utc.with_ymd_and_hms(2022, 11, 30, 14, 21, 5).unwrap();
+        let now = Arc::new(std::sync::Mutex::new(start));
+        let clock = {
+            let now = now.clone();
+            Clock::new(move || *now.lock().unwrap())
+        };
+        let (p, _) = Persistence::new_in_memory().await;
+        let p = p.with_clock(clock);
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        p.insert_secret(&service_id, "KEEP", "forever")
+            .await
+            .unwrap();
+        p.insert_secret_with_ttl(&service_id, "SHORT", "token", Duration::minutes(5))
+            .await
+            .unwrap();
+        p.insert_secret_with_ttl(&service_id, "LONG", "token", Duration::hours(1))
+            .await
+            .unwrap();
+
+        let keys = |p: &Persistence| {
+            let p = p.clone();
+            async move {
+                p.get_secrets(&service_id)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|secret| secret.key)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(keys(&p).await, ["KEEP", "LONG", "SHORT"]);
+
+        *now.lock().unwrap() = start + Duration::minutes(5);
+        assert_eq!(
+            keys(&p).await,
+            ["KEEP", "LONG"],
+            "expired secrets should not be returned"
+        );
+
+        assert_eq!(p.delete_expired_secrets().await.unwrap(), 1);
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM secrets")
+            .fetch_one(&p.pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+
+        // Setting an expired secret again without a ttl should keep it
+        *now.lock().unwrap() = start + Duration::hours(2);
+        p.insert_secret(&service_id, "LONG", "forever")
+            .await
+            .unwrap();
+        assert_eq!(keys(&p).await, ["KEEP", "LONG"]);
+        assert_eq!(p.delete_expired_secrets().await.unwrap(), 0);
+
+        assert!(matches!(
+            p.insert_secret_with_ttl(&service_id, "not valid", "token", Duration::hours(1))
+                .await,
+            Err(Error::InvalidSecretKey(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn secrets_with_fixed_clock() {
+        let now = Utc.with_ymd_and_hms(2022, 11, 30, 14, 21, 5).unwrap();
+        let (p, _) = Persistence::new_in_memory().await;
+        let p = p.with_clock(Clock::fixed(now));
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        p.insert_secret(&service_id, "key", "value").await.unwrap();
+
+        assert_eq!(
+            p.get_secrets(&service_id).await.unwrap(),
+            vec![Secret {
+                service_id,
+                key: "key".to_string(),
+                value: "value".to_string(),
+                last_update: now,
+            }]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn custom_secret_store() {
+        #[derive(Default)]
+        struct MemorySecretStore(std::sync::Mutex<Vec<Secret>>);
+
+        #[async_trait::async_trait]
+        impl SecretStore for MemorySecretStore {
+            async fn insert_secret(&self, service_id: &Uuid, key: &str, value: &str) -> Result<()> {
+                self.0.lock().unwrap().push(Secret {
+                    service_id: *service_id,
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    last_update: Default::default(),
+                });
+
+                Ok(())
+            }
+
+            async fn get_secrets(&self, service_id: &Uuid) -> Result<Vec<Secret>> {
+                Ok(self
+                    .0
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|secret| secret.service_id == *service_id)
+                    .map(|secret| Secret {
+                        service_id: secret.service_id,
+                        key: secret.key.clone(),
+                        value: secret.value.clone(),
+                        last_update: secret.last_update,
+                    })
+                    .collect())
+            }
+
+            async fn delete_secret(&self, service_id: &Uuid, key: &str) -> Result<()> {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .retain(|secret| secret.service_id != *service_id || secret.key != key);
+
+                Ok(())
+            }
+        }
+
+        let (p, _) = Persistence::new_in_memory().await;
+        let p = p
+            .with_secret_store(MemorySecretStore::default())
+            .with_clock(Clock::default());
+        let service_id = add_service(&p.pool).await.unwrap();
+
+        p.insert_secret(&service_id, "key1", "value1")
+            .await
+            .unwrap();
+        p.insert_secret(&service_id, "key2", "value2")
+            .await
+            .unwrap();
+        p.delete_secret(&service_id, "key1").await.unwrap();
+
+        let keys: Vec<_> = p
+            .get_secrets(&service_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|secret| secret.key)
+            .collect();
+        assert_eq!(keys, vec!["key2".to_string()]);
+
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM secrets")
+            .fetch_one(&p.pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 0, "the state db should not be used for secrets");
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn service() {
         let (p, _) = Persistence::new_in_memory().await;
@@ -1027,6 +6651,108 @@ mod tests {
             .is_none());
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn purge_soft_deleted_services() {
+        let (p, _) = Persistence::new_in_memory().await;
+
+        let old_id = add_service(&p.pool).await.unwrap();
+        let recent_id = add_service(&p.pool).await.unwrap();
+        let live_id = add_service(&p.pool).await.unwrap();
+
+        for (service_id, deleted_at) in [
+            (old_id, Utc.with_ymd_and_hms(2022, 4, 1, 0, 0, 0).unwrap()),
+            (
+                recent_id,
+                Utc.with_ymd_and_hms(2022, 4, 20, 0, 0, 0).unwrap(),
+            ),
+        ] {
+            sqlx::query("UPDATE services SET deleted_at = ? WHERE id = ?")
+                .bind(deleted_at)
+                .bind(service_id)
+                .execute(&p.pool)
+                .await
+                .unwrap();
+        }
+
+        let mut deployment_ids = Vec::new();
+        for service_id in [old_id, recent_id, live_id] {
+            let id = Uuid::new_v4();
+            p.insert_deployment(Deployment {
+                id,
+                service_id,
+                state: State::Stopped,
+                last_update: Utc::now(),
+                address: None,
+                error_message: None,
+                running_since: None,
+            })
+            .await
+            .unwrap();
+            insert_log(
+                &p.pool,
+                Log {
+                    id,
+                    timestamp: Utc::now(),
+                    state: State::Stopped,
+                    level: Level::Info,
+                    file: None,
+                    line: None,
+                    target: String::new(),
+                    fields: json!(STATE_MESSAGE),
+                    trace_id: None,
+                    span_id: None,
+                    origin: Origin::Deployer,
+                    span_fields: None,
+                    duration_ms: None,
+                    repeat_count: 1,
+                },
+            )
+            .await
+            .unwrap();
+            p.insert_secret(&service_id, "key", "value").await.unwrap();
+            p.insert_resource(&Resource {
+                service_id,
+                r#type: ResourceType::Database(resource::DatabaseType::Shared(
+                    resource::database::SharedType::Postgres,
+                )),
+                data: json!({"username": "root"}),
+            })
+            .await
+            .unwrap();
+
+            deployment_ids.push(id);
+        }
+
+        let purged = p
+            .purge_soft_deleted_services(Utc.with_ymd_and_hms(2022, 4, 10, 0, 0, 0).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(purged, 1);
+
+        assert!(p.get_service_by_id(&old_id).await.unwrap().is_none());
+        assert!(p.get_deployments(&old_id).await.unwrap().is_empty());
+        assert!(p
+            .get_deployment_logs(&deployment_ids[0])
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(p.get_secrets(&old_id).await.unwrap().is_empty());
+        assert!(p.get_resources(&old_id).await.unwrap().is_empty());
+
+        for (service_id, deployment_id) in
+            [(recent_id, deployment_ids[1]), (live_id, deployment_ids[2])]
+        {
+            assert!(p.get_service_by_id(&service_id).await.unwrap().is_some());
+            assert_eq!(p.get_deployments(&service_id).await.unwrap().len(), 1);
+            assert_eq!(
+                p.get_deployment_logs(&deployment_id).await.unwrap().len(),
+                1
+            );
+            assert_eq!(p.get_secrets(&service_id).await.unwrap().len(), 1);
+            assert_eq!(p.get_resources(&service_id).await.unwrap().len(), 1);
+        }
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn address_getter() {
         let (p, _) = Persistence::new_in_memory().await;
@@ -1065,6 +6791,64 @@ mod tests {
                 .unwrap()
                 .unwrap(),
         );
+
+        let service_ipv6_id = add_service_named(&p.pool, "ipv6-name").await.unwrap();
+        let address = SocketAddr::new(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x5).into(), 12356);
+        p.insert_deployment(Deployment {
+            id: Uuid::new_v4(),
+            service_id: service_ipv6_id,
+            state: State::Running,
+            last_update: Utc::now(),
+            address: Some(address),
+            error_message: None,
+            running_since: None,
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            p.get_address_for_service("ipv6-name")
+                .await
+                .unwrap()
+                .unwrap(),
+            address,
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn address_getter_uses_service_cache() {
+        let (p, _) = Persistence::new_in_memory().await;
+        let p = p.with_service_cache(10);
+        let service_id = add_service_named(&p.pool, "cached").await.unwrap();
+        sqlx::query(
+            "INSERT INTO deployments (id, service_id, state, last_update, address) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(service_id)
+        .bind(State::Running)
+        .bind(Utc::now())
+        .bind("10.0.0.5:12356")
+        .execute(&p.pool)
+        .await
+        .unwrap();
+        let address = SocketAddr::from(([10, 0, 0, 5], 12356));
+
+        assert_eq!(
+            p.get_address_for_service("cached").await.unwrap(),
+            Some(address)
+        );
+
+        // Renaming the service behind the cache's back shows whether the name is still looked up in the db
+        sqlx::query("UPDATE services SET name = 'renamed' WHERE id = ?")
+            .bind(service_id)
+            .execute(&p.pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            p.get_address_for_service("cached").await.unwrap(),
+            Some(address),
+            "the service should come from the cache"
+        );
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -1081,6 +6865,8 @@ mod tests {
                 state: State::Built,
                 last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 29, 33).unwrap(),
                 address: None,
+                error_message: None,
+                running_since: None,
             },
             Deployment {
                 id: Uuid::new_v4(),
@@ -1088,6 +6874,8 @@ mod tests {
                 state: State::Stopped,
                 last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 29, 44).unwrap(),
                 address: None,
+                error_message: None,
+                running_since: None,
             },
             Deployment {
                 id: id_1,
@@ -1095,6 +6883,8 @@ mod tests {
                 state: State::Running,
                 last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 33, 48).unwrap(),
                 address: None,
+                error_message: None,
+                running_since: None,
             },
             Deployment {
                 id: Uuid::new_v4(),
@@ -1102,6 +6892,8 @@ mod tests {
                 state: State::Crashed,
                 last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 38, 52).unwrap(),
                 address: None,
+                error_message: None,
+                running_since: None,
             },
             Deployment {
                 id: id_2,
@@ -1109,6 +6901,8 @@ mod tests {
                 state: State::Running,
                 last_update: Utc.with_ymd_and_hms(2022, 4, 25, 4, 42, 32).unwrap(),
                 address: None,
+                error_message: None,
+                running_since: None,
             },
         ] {
             p.insert_deployment(deployment).await.unwrap();
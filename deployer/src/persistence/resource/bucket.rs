@@ -0,0 +1,26 @@
+use strum::{Display, EnumString};
+
+#[derive(Clone, Copy, Debug, Display, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum Type {
+    Shared,
+    AwsS3,
+}
+
+impl From<Type> for shuttle_common::bucket::Type {
+    fn from(r#type: Type) -> Self {
+        match r#type {
+            Type::Shared => Self::Shared,
+            Type::AwsS3 => Self::AwsS3,
+        }
+    }
+}
+
+impl From<shuttle_common::bucket::Type> for Type {
+    fn from(r#type: shuttle_common::bucket::Type) -> Self {
+        match r#type {
+            shuttle_common::bucket::Type::Shared => Self::Shared,
+            shuttle_common::bucket::Type::AwsS3 => Self::AwsS3,
+        }
+    }
+}
@@ -1,3 +1,4 @@
+pub mod bucket;
 pub mod database;
 
 use sqlx::{
@@ -7,6 +8,7 @@ use sqlx::{
 use std::{borrow::Cow, fmt::Display, str::FromStr};
 use uuid::Uuid;
 
+pub use self::bucket::Type as BucketType;
 pub use self::database::Type as DatabaseType;
 
 /// Types that can record and retrieve resource allocations
@@ -16,6 +18,9 @@ pub trait ResourceManager: Clone + Send + Sync + 'static {
 
     async fn insert_resource(&self, resource: &Resource) -> Result<(), Self::Err>;
     async fn get_resources(&self, service_id: &Uuid) -> Result<Vec<Resource>, Self::Err>;
+
+    /// Get the resources of every service
+    async fn get_all_resources(&self) -> Result<Vec<Resource>, Self::Err>;
 }
 
 #[derive(sqlx::FromRow, Debug, Eq, PartialEq)]
@@ -38,12 +43,14 @@ impl From<Resource> for shuttle_common::models::resource::Response {
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Type {
     Database(DatabaseType),
+    Bucket(BucketType),
 }
 
 impl From<Type> for shuttle_common::models::resource::Type {
     fn from(r#type: Type) -> Self {
         match r#type {
             Type::Database(r#type) => Self::Database(r#type.into()),
+            Type::Bucket(r#type) => Self::Bucket(r#type.into()),
         }
     }
 }
@@ -52,6 +59,7 @@ impl Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Type::Database(db_type) => write!(f, "database::{db_type}"),
+            Type::Bucket(bucket_type) => write!(f, "bucket::{bucket_type}"),
         }
     }
 }
@@ -63,6 +71,9 @@ impl FromStr for Type {
         if let Some((prefix, rest)) = s.split_once("::") {
             match prefix {
                 "database" => Ok(Self::Database(DatabaseType::from_str(rest)?)),
+                "bucket" => Ok(Self::Bucket(
+                    BucketType::from_str(rest).map_err(|e| e.to_string())?,
+                )),
                 _ => Err(format!("'{prefix}' is an unknown resource type")),
             }
         } else {
@@ -100,7 +111,7 @@ impl<'r> sqlx::Decode<'r, Sqlite> for Type {
 mod tests {
     use std::str::FromStr;
 
-    use super::{database, Type};
+    use super::{bucket, database, Type};
 
     #[test]
     fn to_string_and_back() {
@@ -110,6 +121,8 @@ mod tests {
             Type::Database(database::Type::AwsRds(database::AwsRdsType::MariaDB)),
             Type::Database(database::Type::Shared(database::SharedType::Postgres)),
             Type::Database(database::Type::Shared(database::SharedType::MongoDb)),
+            Type::Bucket(bucket::Type::Shared),
+            Type::Bucket(bucket::Type::AwsS3),
         ];
 
         for input in inputs {
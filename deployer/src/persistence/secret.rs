@@ -1,6 +1,12 @@
-use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use sqlx::SqlitePool;
 use uuid::Uuid;
 
+use super::clock::Clock;
+use super::error::{Error, Result};
+
 #[async_trait::async_trait]
 /// Record a secret value for a service with name
 pub trait SecretRecorder: Clone + Send + Sync + 'static {
@@ -12,6 +18,35 @@ pub trait SecretRecorder: Clone + Send + Sync + 'static {
         key: &str,
         value: &str,
     ) -> Result<(), Self::Err>;
+
+    /// Record a secret which is no longer returned once `ttl` has passed, like a short-lived token
+    async fn insert_secret_with_ttl(
+        &self,
+        service_id: &Uuid,
+        key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<(), Self::Err>;
+
+    /// Record many secrets at once. Recorders that can should write them all together with the same `last_update`.
+    async fn insert_secrets(
+        &self,
+        service_id: &Uuid,
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<(), Self::Err> {
+        for (key, value) in secrets {
+            self.insert_secret(service_id, key, value).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Make the secrets of a service exactly match `desired`, reporting which keys changed
+    async fn sync_secrets(
+        &self,
+        service_id: &Uuid,
+        desired: &BTreeMap<String, String>,
+    ) -> Result<SecretSyncReport, Self::Err>;
 }
 
 #[async_trait::async_trait]
@@ -22,6 +57,276 @@ pub trait SecretGetter: Clone + Send + Sync + 'static {
     async fn get_secrets(&self, service_id: &Uuid) -> Result<Vec<Secret>, Self::Err>;
 }
 
+/// Backend where the secrets of services are kept. The state database is used by default, but any other store (like
+/// Vault) can be swapped in with [super::Persistence::with_secret_store].
+#[async_trait::async_trait]
+pub trait SecretStore: Send + Sync + 'static {
+    async fn insert_secret(&self, service_id: &Uuid, key: &str, value: &str) -> Result<()>;
+
+    /// Insert a secret which should stop being returned by [SecretStore::get_secrets] once `ttl` has passed. Stores
+    /// which cannot expire secrets reject them.
+    async fn insert_secret_with_ttl(
+        &self,
+        _service_id: &Uuid,
+        _key: &str,
+        _value: &str,
+        _ttl: Duration,
+    ) -> Result<()> {
+        Err(Error::SecretExpiryUnsupported)
+    }
+
+    /// Hard-delete the secrets which have expired, returning how many were deleted
+    async fn delete_expired_secrets(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Insert many secrets at once, which is not atomic unless the store overrides it
+    async fn insert_secrets(
+        &self,
+        service_id: &Uuid,
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        for (key, value) in secrets {
+            self.insert_secret(service_id, key, value).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_secrets(&self, service_id: &Uuid) -> Result<Vec<Secret>>;
+    async fn delete_secret(&self, service_id: &Uuid, key: &str) -> Result<()>;
+
+    /// Make the secrets of a service exactly match `desired`, which is not atomic unless the store overrides it
+    async fn sync_secrets(
+        &self,
+        service_id: &Uuid,
+        desired: &BTreeMap<String, String>,
+    ) -> Result<SecretSyncReport> {
+        let current = self
+            .get_secrets(service_id)
+            .await?
+            .into_iter()
+            .map(|secret| (secret.key, secret.value))
+            .collect();
+        let report = SecretSyncReport::new(&current, desired);
+
+        for key in report.added.iter().chain(report.updated.iter()) {
+            self.insert_secret(service_id, key, &desired[key]).await?;
+        }
+
+        for key in report.removed.iter() {
+            self.delete_secret(service_id, key).await?;
+        }
+
+        Ok(report)
+    }
+}
+
+/// Keys which had to change for the secrets of a service to match a desired set
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct SecretSyncReport {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl SecretSyncReport {
+    fn new(current: &BTreeMap<String, String>, desired: &BTreeMap<String, String>) -> Self {
+        let mut report = Self::default();
+
+        for (key, value) in desired {
+            match current.get(key) {
+                None => report.added.push(key.clone()),
+                Some(current_value) if current_value != value => report.updated.push(key.clone()),
+                Some(_) => {}
+            }
+        }
+
+        report.removed = current
+            .keys()
+            .filter(|key| !desired.contains_key(*key))
+            .cloned()
+            .collect();
+
+        report
+    }
+}
+
+/// Check that `key` can be used as the name of an environment variable, so it has to match `[A-Za-z_][A-Za-z0-9_]*`
+pub fn validate_secret_key(key: &str) -> Result<()> {
+    let mut chars = key.chars();
+    let valid = chars
+        .next()
+        .map_or(false, |first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidSecretKey(key.to_string()))
+    }
+}
+
+/// Condition for the secrets which have not expired, with the current time bound to it
+const NOT_EXPIRED: &str = "(expires_at IS NULL OR expires_at > ?)";
+
+/// Default store keeping secrets in the `secrets` table of the state database
+pub struct SqliteSecretStore {
+    pool: SqlitePool,
+    clock: Clock,
+}
+
+impl SqliteSecretStore {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self {
+            pool,
+            clock: Default::default(),
+        }
+    }
+
+    /// Take the `last_update` of secrets from `clock`
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl SecretStore for SqliteSecretStore {
+    async fn insert_secret(&self, service_id: &Uuid, key: &str, value: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO secrets (service_id, key, value, last_update) VALUES (?, ?, ?, ?)",
+        )
+        .bind(service_id)
+        .bind(key)
+        .bind(value)
+        .bind(self.clock.now())
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(Error::from)
+    }
+
+    async fn insert_secret_with_ttl(
+        &self,
+        service_id: &Uuid,
+        key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<()> {
+        let now = self.clock.now();
+
+        sqlx::query(
+            "INSERT OR REPLACE INTO secrets (service_id, key, value, last_update, expires_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(service_id)
+        .bind(key)
+        .bind(value)
+        .bind(now)
+        .bind(now + ttl)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(Error::from)
+    }
+
+    async fn delete_expired_secrets(&self) -> Result<u64> {
+        sqlx::query("DELETE FROM secrets WHERE expires_at <= ?")
+            .bind(self.clock.now())
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected())
+            .map_err(Error::from)
+    }
+
+    async fn insert_secrets(
+        &self,
+        service_id: &Uuid,
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+        let last_update = self.clock.now();
+
+        for (key, value) in secrets {
+            sqlx::query(
+                "INSERT OR REPLACE INTO secrets (service_id, key, value, last_update) VALUES (?, ?, ?, ?)",
+            )
+            .bind(service_id)
+            .bind(key)
+            .bind(value)
+            .bind(last_update)
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await.map_err(Error::from)
+    }
+
+    async fn sync_secrets(
+        &self,
+        service_id: &Uuid,
+        desired: &BTreeMap<String, String>,
+    ) -> Result<SecretSyncReport> {
+        let mut transaction = self.pool.begin().await?;
+        let last_update = self.clock.now();
+
+        let current: Vec<(String, String)> = sqlx::query_as(&format!(
+            "SELECT key, value FROM secrets WHERE service_id = ? AND {NOT_EXPIRED}"
+        ))
+        .bind(service_id)
+        .bind(last_update)
+        .fetch_all(&mut transaction)
+        .await?;
+        let report = SecretSyncReport::new(&current.into_iter().collect(), desired);
+
+        for key in report.added.iter().chain(report.updated.iter()) {
+            sqlx::query(
+                "INSERT OR REPLACE INTO secrets (service_id, key, value, last_update) VALUES (?, ?, ?, ?)",
+            )
+            .bind(service_id)
+            .bind(key)
+            .bind(&desired[key])
+            .bind(last_update)
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        for key in report.removed.iter() {
+            sqlx::query("DELETE FROM secrets WHERE service_id = ? AND key = ?")
+                .bind(service_id)
+                .bind(key)
+                .execute(&mut transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(report)
+    }
+
+    async fn get_secrets(&self, service_id: &Uuid) -> Result<Vec<Secret>> {
+        sqlx::query_as(&format!(
+            "SELECT * FROM secrets WHERE service_id = ? AND {NOT_EXPIRED} ORDER BY key"
+        ))
+        .bind(service_id)
+        .bind(self.clock.now())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Error::from)
+    }
+
+    async fn delete_secret(&self, service_id: &Uuid, key: &str) -> Result<()> {
+        sqlx::query("DELETE FROM secrets WHERE service_id = ? AND key = ?")
+            .bind(service_id)
+            .bind(key)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+}
+
 #[derive(sqlx::FromRow, Debug, Eq, PartialEq)]
 pub struct Secret {
     pub service_id: Uuid,
@@ -1,7 +1,13 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
 use shuttle_common::models::service;
 use uuid::Uuid;
 
-#[derive(Clone, Debug, Eq, PartialEq, sqlx::FromRow)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, sqlx::FromRow)]
 pub struct Service {
     pub id: Uuid,
     pub name: String,
@@ -15,3 +21,135 @@ impl From<Service> for service::Response {
         }
     }
 }
+
+/// Bounded cache of services by their name which evicts the least recently used service once it is full.
+///
+/// Every invalidation bumps a generation. A service read from the db is only cached if no invalidation happened since
+/// the read started, so that a slow read cannot put back a service which was changed in the meantime.
+#[derive(Clone)]
+pub struct ServiceCache {
+    capacity: usize,
+    inner: Arc<Mutex<ServiceCacheInner>>,
+}
+
+#[derive(Default)]
+struct ServiceCacheInner {
+    /// Services with the tick at which they were last used
+    entries: HashMap<String, (Service, u64)>,
+    tick: u64,
+    generation: u64,
+}
+
+impl ServiceCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Default::default(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Service> {
+        let mut inner = self.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        inner.entries.get_mut(name).map(|(service, last_used)| {
+            *last_used = tick;
+            service.clone()
+        })
+    }
+
+    /// The generation to pass to [ServiceCache::insert] for a read which is about to start
+    pub fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    /// Cache a service which was read at `generation`, unless it might have been invalidated since
+    pub fn insert(&self, service: Service, generation: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut inner = self.lock();
+        if inner.generation != generation {
+            return;
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if inner.entries.len() >= self.capacity && !inner.entries.contains_key(&service.name) {
+            let least_recent = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(name, _)| name.clone());
+
+            if let Some(name) = least_recent {
+                inner.entries.remove(&name);
+            }
+        }
+
+        inner.entries.insert(service.name.clone(), (service, tick));
+    }
+
+    /// Forget the service with this `id`, whatever name it is cached under
+    pub fn invalidate(&self, id: &Uuid) {
+        let mut inner = self.lock();
+        inner.generation += 1;
+        inner.entries.retain(|_, (service, _)| &service.id != id);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ServiceCacheInner> {
+        self.inner
+            .lock()
+            .expect("service cache lock should not be poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{Service, ServiceCache};
+
+    fn service(name: &str) -> Service {
+        Service {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = ServiceCache::new(2);
+        let (foo, bar, baz) = (service("foo"), service("bar"), service("baz"));
+
+        cache.insert(foo.clone(), cache.generation());
+        cache.insert(bar.clone(), cache.generation());
+        assert_eq!(cache.get("foo"), Some(foo.clone()));
+
+        cache.insert(baz.clone(), cache.generation());
+        assert_eq!(cache.get("bar"), None, "bar was used the longest ago");
+        assert_eq!(cache.get("foo"), Some(foo.clone()));
+        assert_eq!(cache.get("baz"), Some(baz));
+
+        cache.invalidate(&foo.id);
+        assert_eq!(cache.get("foo"), None);
+    }
+
+    #[test]
+    fn stale_read_is_not_cached() {
+        let cache = ServiceCache::new(2);
+        let foo = service("foo");
+
+        // A read which started before the service was invalidated
+        let generation = cache.generation();
+        cache.invalidate(&foo.id);
+        cache.insert(foo.clone(), generation);
+        assert_eq!(cache.get("foo"), None);
+
+        cache.insert(foo.clone(), cache.generation());
+        assert_eq!(cache.get("foo"), Some(foo));
+    }
+}
@@ -1,7 +1,20 @@
-use strum::{Display, EnumString};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter, EnumString};
 
 /// States a deployment can be in
-#[derive(sqlx::Type, Debug, Display, Clone, Copy, EnumString, PartialEq, Eq)]
+#[derive(
+    sqlx::Type,
+    Debug,
+    Deserialize,
+    Display,
+    Clone,
+    Copy,
+    EnumIter,
+    EnumString,
+    PartialEq,
+    Eq,
+    Serialize,
+)]
 pub enum State {
     /// Deployment is queued to be build
     Queued,
@@ -21,6 +34,9 @@ pub enum State {
     /// Deployment was running, but stopped running all by itself. This is expected for things like background workers
     Completed,
 
+    /// Deployment is running, but the user asked for it to stop and it is shutting down
+    Stopping,
+
     /// Deployment was running, but has been stopped by the user.
     Stopped,
 
@@ -31,6 +47,49 @@ pub enum State {
     Unknown,
 }
 
+impl State {
+    /// Whether a deployment in this state will never change state again
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Completed | Self::Stopped | Self::Crashed)
+    }
+
+    /// Whether a deployment in this state should be running
+    pub fn is_active(&self) -> bool {
+        matches!(self, Self::Running)
+    }
+
+    /// Whether a deployment in this state is still on its way to running or stopping. The work for these states is
+    /// lost when the deployer restarts
+    pub fn is_in_progress(&self) -> bool {
+        matches!(
+            self,
+            Self::Queued | Self::Building | Self::Built | Self::Loading | Self::Stopping
+        )
+    }
+
+    /// Whether a deployment is allowed to move from this state to `next`. Staying in the same state is always allowed.
+    /// A deployment in the [State::Unknown] state can be recovered to any other state.
+    pub fn can_transition_to(&self, next: State) -> bool {
+        if *self == next {
+            return true;
+        }
+
+        match self {
+            Self::Queued => matches!(next, Self::Building | Self::Stopped | Self::Crashed),
+            Self::Building => matches!(next, Self::Built | Self::Stopped | Self::Crashed),
+            Self::Built => matches!(next, Self::Loading | Self::Stopped | Self::Crashed),
+            Self::Loading => matches!(next, Self::Running | Self::Stopped | Self::Crashed),
+            Self::Running => matches!(
+                next,
+                Self::Completed | Self::Stopping | Self::Stopped | Self::Crashed
+            ),
+            Self::Stopping => matches!(next, Self::Stopped | Self::Crashed),
+            Self::Completed | Self::Stopped | Self::Crashed => false,
+            Self::Unknown => next != Self::Unknown,
+        }
+    }
+}
+
 impl Default for State {
     fn default() -> Self {
         Self::Unknown
@@ -46,6 +105,7 @@ impl From<State> for shuttle_common::deployment::State {
             State::Loading => Self::Loading,
             State::Running => Self::Running,
             State::Completed => Self::Completed,
+            State::Stopping => Self::Stopping,
             State::Stopped => Self::Stopped,
             State::Crashed => Self::Crashed,
             State::Unknown => Self::Unknown,
@@ -62,9 +122,77 @@ impl From<shuttle_common::deployment::State> for State {
             shuttle_common::deployment::State::Loading => Self::Loading,
             shuttle_common::deployment::State::Running => Self::Running,
             shuttle_common::deployment::State::Completed => Self::Completed,
+            shuttle_common::deployment::State::Stopping => Self::Stopping,
             shuttle_common::deployment::State::Stopped => Self::Stopped,
             shuttle_common::deployment::State::Crashed => Self::Crashed,
             shuttle_common::deployment::State::Unknown => Self::Unknown,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use strum::IntoEnumIterator;
+
+    use super::State;
+
+    #[test]
+    fn classification() {
+        for state in State::iter() {
+            let (terminal, active, in_progress) = match state {
+                State::Queued
+                | State::Building
+                | State::Built
+                | State::Loading
+                | State::Stopping => (false, false, true),
+                State::Running => (false, true, false),
+                State::Completed | State::Stopped | State::Crashed => (true, false, false),
+                State::Unknown => (false, false, false),
+            };
+
+            assert_eq!(state.is_terminal(), terminal, "{state} terminal");
+            assert_eq!(state.is_active(), active, "{state} active");
+            assert_eq!(state.is_in_progress(), in_progress, "{state} in progress");
+        }
+    }
+
+    #[test]
+    fn transitions() {
+        use State::*;
+
+        #[rustfmt::skip]
+        let legal = [
+            (Queued, Building), (Queued, Stopped), (Queued, Crashed),
+            (Building, Built), (Building, Stopped), (Building, Crashed),
+            (Built, Loading), (Built, Stopped), (Built, Crashed),
+            (Loading, Running), (Loading, Stopped), (Loading, Crashed),
+            (Running, Completed), (Running, Stopping), (Running, Stopped), (Running, Crashed),
+            (Stopping, Stopped), (Stopping, Crashed),
+        ];
+
+        for from in State::iter() {
+            for to in State::iter() {
+                let expected = from == to || from == Unknown || legal.contains(&(from, to));
+
+                assert_eq!(
+                    from.can_transition_to(to),
+                    expected,
+                    "{from} -> {to} should be {}",
+                    if expected { "legal" } else { "illegal" }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for state in State::iter() {
+            assert_eq!(State::from_str(&state.to_string()), Ok(state));
+
+            // Spans recording a state with `?` instead of `%` should parse the same
+            assert_eq!(format!("{state:?}"), state.to_string());
+        }
+    }
+}
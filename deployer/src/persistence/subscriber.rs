@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{broadcast::Receiver, watch};
+use uuid::Uuid;
+
+use super::State;
+use crate::deployment::deploy_layer;
+
+/// Keeps track of how many log subscribers are alive for each deployment
+#[derive(Clone, Default)]
+pub struct SubscriberCounts(Arc<Mutex<HashMap<Uuid, usize>>>);
+
+impl SubscriberCounts {
+    pub fn get(&self, id: &Uuid) -> usize {
+        self.0
+            .lock()
+            .expect("subscriber counts lock should not be poisoned")
+            .get(id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn increment(&self, id: Uuid) {
+        *self
+            .0
+            .lock()
+            .expect("subscriber counts lock should not be poisoned")
+            .entry(id)
+            .or_default() += 1;
+    }
+
+    fn decrement(&self, id: &Uuid) {
+        let mut counts = self
+            .0
+            .lock()
+            .expect("subscriber counts lock should not be poisoned");
+
+        if let Some(count) = counts.get_mut(id) {
+            *count -= 1;
+
+            if *count == 0 {
+                counts.remove(id);
+            }
+        }
+    }
+}
+
+/// Senders for the state changes of the deployments which are being watched
+#[derive(Clone, Default)]
+pub struct StateWatchers(Arc<Mutex<HashMap<Uuid, watch::Sender<State>>>>);
+
+impl StateWatchers {
+    /// Start watching the state changes of deployment `id`. Only states sent after this call are seen.
+    pub fn subscribe(&self, id: Uuid) -> watch::Receiver<State> {
+        let mut watchers = self
+            .0
+            .lock()
+            .expect("state watchers lock should not be poisoned");
+
+        // Deployments which are no longer watched might never change state again to be cleaned up by `send`
+        watchers.retain(|_, sender| sender.receiver_count() > 0);
+
+        watchers
+            .entry(id)
+            .or_insert_with(|| watch::channel(State::default()).0)
+            .subscribe()
+    }
+
+    /// Let the watchers of deployment `id` know it moved to `state`. Watchers of a deployment which reached a terminal
+    /// state are closed since it will not change again.
+    pub fn send(&self, id: &Uuid, state: State) {
+        let mut watchers = self
+            .0
+            .lock()
+            .expect("state watchers lock should not be poisoned");
+
+        if let Some(sender) = watchers.get(id) {
+            let closed = sender.send(state).is_err();
+
+            if closed || state.is_terminal() {
+                watchers.remove(id);
+            }
+        }
+    }
+}
+
+/// Log subscriber for a deployment which is counted for as long as it is alive
+pub struct DeploymentLogSubscriber {
+    id: Uuid,
+    receiver: Receiver<deploy_layer::Log>,
+    counts: SubscriberCounts,
+}
+
+impl DeploymentLogSubscriber {
+    pub fn new(id: Uuid, receiver: Receiver<deploy_layer::Log>, counts: SubscriberCounts) -> Self {
+        counts.increment(id);
+
+        Self {
+            id,
+            receiver,
+            counts,
+        }
+    }
+}
+
+impl Deref for DeploymentLogSubscriber {
+    type Target = Receiver<deploy_layer::Log>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.receiver
+    }
+}
+
+impl DerefMut for DeploymentLogSubscriber {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.receiver
+    }
+}
+
+impl Drop for DeploymentLogSubscriber {
+    fn drop(&mut self) {
+        self.counts.decrement(&self.id);
+    }
+}